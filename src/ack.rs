@@ -0,0 +1,319 @@
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+use crate::protocol::{Ack, KeyEvent, PeerId, Receipt};
+
+/// How far behind the newest id from a sender an event may be and still be
+/// recognized as a duplicate or not.
+const DUPLICATE_WINDOW: u64 = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Delivery {
+    Delivered,
+    TimedOut,
+}
+
+/// The outcome of an acknowledged event, per recipient.
+#[derive(Debug)]
+pub struct DeliveryReport {
+    pub id: u64,
+    pub key: u16,
+    pub recipients: Vec<(PeerId, Delivery)>,
+}
+
+impl DeliveryReport {
+    pub fn missing(&self) -> Vec<PeerId> {
+        self.recipients
+            .iter()
+            .filter(|(_, delivery)| *delivery == Delivery::TimedOut)
+            .map(|(recipient, _)| *recipient)
+            .collect()
+    }
+}
+
+struct Pending {
+    event: KeyEvent,
+    /// Who the server sent the event to, once its receipt has arrived.
+    recipients: Option<Vec<PeerId>>,
+    acked: HashSet<PeerId>,
+    deadline: Instant,
+    retransmits: u32,
+}
+
+impl Pending {
+    fn is_complete(&self) -> bool {
+        self.recipients
+            .as_ref()
+            .is_some_and(|recipients| recipients.iter().all(|r| self.acked.contains(r)))
+    }
+
+    fn report(self) -> DeliveryReport {
+        let recipients = self
+            .recipients
+            .unwrap_or_default()
+            .into_iter()
+            .map(|recipient| {
+                let delivery = if self.acked.contains(&recipient) {
+                    Delivery::Delivered
+                } else {
+                    Delivery::TimedOut
+                };
+                (recipient, delivery)
+            })
+            .collect();
+        DeliveryReport {
+            id: self.event.id,
+            key: self.event.key,
+            recipients,
+        }
+    }
+}
+
+/// Tracks outgoing events that asked for acknowledgement, until every
+/// recipient has acked them or the retransmits run out.
+pub struct AckTracker {
+    timeout: Duration,
+    max_retransmits: u32,
+    pending: HashMap<u64, Pending>,
+}
+
+impl AckTracker {
+    pub fn new(timeout: Duration, max_retransmits: u32) -> Self {
+        Self {
+            timeout,
+            max_retransmits,
+            pending: HashMap::new(),
+        }
+    }
+
+    /// Starts tracking an event that was just sent.
+    pub fn sent(&mut self, event: &KeyEvent) {
+        self.pending.insert(
+            event.id,
+            Pending {
+                event: event.clone(),
+                recipients: None,
+                acked: HashSet::new(),
+                deadline: Instant::now() + self.timeout,
+                retransmits: 0,
+            },
+        );
+    }
+
+    pub fn receipt(&mut self, receipt: Receipt) -> Option<DeliveryReport> {
+        let pending = self.pending.get_mut(&receipt.id)?;
+        pending.recipients = Some(receipt.recipients);
+        self.complete(receipt.id)
+    }
+
+    pub fn ack(&mut self, ack: &Ack) -> Option<DeliveryReport> {
+        let pending = self.pending.get_mut(&ack.id)?;
+        pending.acked.insert(ack.receiver);
+        self.complete(ack.id)
+    }
+
+    fn complete(&mut self, id: u64) -> Option<DeliveryReport> {
+        if !self.pending.get(&id)?.is_complete() {
+            return None;
+        }
+        self.pending.remove(&id).map(Pending::report)
+    }
+
+    /// Returns the events whose acks are overdue and should be sent again,
+    /// and reports for those that have run out of retransmits.
+    pub fn poll(&mut self) -> (Vec<KeyEvent>, Vec<DeliveryReport>) {
+        self.poll_at(Instant::now())
+    }
+
+    fn poll_at(&mut self, now: Instant) -> (Vec<KeyEvent>, Vec<DeliveryReport>) {
+        let mut retransmit = Vec::new();
+        let mut expired = Vec::new();
+
+        for (id, pending) in self.pending.iter_mut() {
+            if pending.deadline > now {
+                continue;
+            }
+            if pending.retransmits < self.max_retransmits {
+                pending.retransmits += 1;
+                pending.deadline = now + self.timeout;
+                retransmit.push(pending.event.clone());
+            } else {
+                expired.push(*id);
+            }
+        }
+
+        let reports = expired
+            .into_iter()
+            .filter_map(|id| self.pending.remove(&id))
+            .map(Pending::report)
+            .collect();
+        (retransmit, reports)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Arrival {
+    New,
+    /// Not received before, but older than an event that was, e.g. the
+    /// retransmission of one that went missing.
+    OutOfOrder,
+    /// Already received, e.g. a retransmission or a replay.
+    Duplicate,
+    /// So far behind the newest event from the same sender that there is no
+    /// telling whether it was received.
+    TooOld,
+}
+
+#[derive(Default)]
+struct SenderWindow {
+    highest: u64,
+    /// The ids received within `DUPLICATE_WINDOW` of `highest`.
+    seen: BTreeSet<u64>,
+}
+
+/// Classifies incoming events by sender and id, so each is acted on at most
+/// once.
+#[derive(Default)]
+pub struct DuplicateFilter {
+    senders: HashMap<PeerId, SenderWindow>,
+}
+
+impl DuplicateFilter {
+    /// Starts over for `sender`, e.g. once its id belongs to another client.
+    pub fn forget(&mut self, sender: PeerId) {
+        self.senders.remove(&sender);
+    }
+
+    pub fn check(&mut self, event: &KeyEvent) -> Arrival {
+        let window = self.senders.entry(event.sender).or_default();
+        if window.seen.contains(&event.id) {
+            return Arrival::Duplicate;
+        }
+        if event.id.saturating_add(DUPLICATE_WINDOW) <= window.highest {
+            return Arrival::TooOld;
+        }
+
+        window.seen.insert(event.id);
+        if event.id < window.highest {
+            return Arrival::OutOfOrder;
+        }
+        window.highest = event.id;
+        let oldest = window.highest.saturating_sub(DUPLICATE_WINDOW - 1);
+        window.seen = window.seen.split_off(&oldest);
+        Arrival::New
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(sender: PeerId, id: u64) -> KeyEvent {
+        KeyEvent {
+            key: 30,
+            sender,
+            id,
+            ack: true,
+            ..Default::default()
+        }
+    }
+
+    fn ack(id: u64, receiver: PeerId) -> Ack {
+        Ack {
+            sender: 1,
+            id,
+            receiver,
+        }
+    }
+
+    #[test]
+    fn classifies_new_duplicate_and_out_of_order() {
+        let mut filter = DuplicateFilter::default();
+        assert_eq!(filter.check(&event(1, 5)), Arrival::New);
+        assert_eq!(filter.check(&event(1, 5)), Arrival::Duplicate);
+        assert_eq!(filter.check(&event(1, 3)), Arrival::OutOfOrder);
+        assert_eq!(filter.check(&event(1, 3)), Arrival::Duplicate);
+        assert_eq!(filter.check(&event(1, 6)), Arrival::New);
+        // Senders are tracked separately.
+        assert_eq!(filter.check(&event(2, 3)), Arrival::New);
+    }
+
+    #[test]
+    fn evicts_old_ids_from_the_window() {
+        let mut filter = DuplicateFilter::default();
+        for id in 1..=DUPLICATE_WINDOW + 1 {
+            assert_eq!(filter.check(&event(1, id)), Arrival::New);
+        }
+        // Id 1 fell out of the window, so it is no longer a known duplicate.
+        assert_eq!(filter.check(&event(1, 1)), Arrival::TooOld);
+        assert_eq!(filter.check(&event(1, 2)), Arrival::Duplicate);
+
+        // An id that went missing can still arrive while in the window.
+        let mut filter = DuplicateFilter::default();
+        assert_eq!(filter.check(&event(1, 1)), Arrival::New);
+        assert_eq!(filter.check(&event(1, DUPLICATE_WINDOW)), Arrival::New);
+        assert_eq!(filter.check(&event(1, 2)), Arrival::OutOfOrder);
+        assert_eq!(filter.check(&event(1, DUPLICATE_WINDOW + 2)), Arrival::New);
+        assert_eq!(filter.check(&event(1, 3)), Arrival::OutOfOrder);
+        assert_eq!(filter.check(&event(1, 2)), Arrival::TooOld);
+    }
+
+    #[test]
+    fn forget_starts_over() {
+        let mut filter = DuplicateFilter::default();
+        assert_eq!(filter.check(&event(1, 5)), Arrival::New);
+        filter.forget(1);
+        assert_eq!(filter.check(&event(1, 5)), Arrival::New);
+        assert_eq!(filter.check(&event(1, 1)), Arrival::OutOfOrder);
+        assert_eq!(filter.check(&event(1, 1)), Arrival::Duplicate);
+    }
+
+    #[test]
+    fn completes_once_every_recipient_acks() {
+        let mut tracker = AckTracker::new(Duration::from_secs(1), 2);
+        tracker.sent(&event(1, 7));
+        // Acks may arrive before the receipt.
+        assert!(tracker.ack(&ack(7, 2)).is_none());
+        let receipt = Receipt {
+            id: 7,
+            recipients: vec![2, 3],
+        };
+        assert!(tracker.receipt(receipt).is_none());
+        let report = tracker.ack(&ack(7, 3)).unwrap();
+        assert_eq!(report.id, 7);
+        assert!(report.missing().is_empty());
+        assert!(tracker.ack(&ack(7, 3)).is_none());
+    }
+
+    #[test]
+    fn retransmits_then_reports_missing_acks() {
+        let timeout = Duration::from_secs(1);
+        let mut tracker = AckTracker::new(timeout, 2);
+        let start = Instant::now();
+        tracker.sent(&event(1, 7));
+        tracker.receipt(Receipt {
+            id: 7,
+            recipients: vec![2, 3],
+        });
+        tracker.ack(&ack(7, 2));
+
+        let (retransmit, reports) = tracker.poll_at(start);
+        assert!(retransmit.is_empty() && reports.is_empty());
+
+        let mut now = start;
+        for _ in 0..2 {
+            now += timeout * 2;
+            let (retransmit, reports) = tracker.poll_at(now);
+            assert_eq!(retransmit.len(), 1);
+            assert_eq!(retransmit[0].id, 7);
+            assert!(reports.is_empty());
+        }
+
+        now += timeout * 2;
+        let (retransmit, reports) = tracker.poll_at(now);
+        assert!(retransmit.is_empty());
+        assert_eq!(reports.len(), 1);
+        assert_eq!(reports[0].missing(), vec![3]);
+        assert!(tracker.poll_at(now + timeout * 2).1.is_empty());
+    }
+}
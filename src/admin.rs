@@ -0,0 +1,170 @@
+use anyhow::Result;
+use evdev::KeyCode;
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::thread;
+use tiny_http::{Header, Method, Request, Response};
+
+use crate::protocol::{KEY_MAX, PeerId};
+use crate::server::ServerState;
+
+/// Largest request body accepted, in bytes.
+const MAX_BODY_SIZE: u64 = 4 * 1024;
+
+/// A key given by name (`KEY_A`) or by code (`30`).
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum KeyArg {
+    Name(String),
+    Code(u16),
+}
+
+#[derive(Deserialize)]
+struct BroadcastRequest {
+    key: KeyArg,
+}
+
+#[derive(Serialize)]
+struct ErrorBody {
+    error: String,
+}
+
+type Reply = (u16, String);
+
+/// Serves the admin API on `addr` from a background thread. Requests must
+/// carry `Authorization: Bearer <admin_token>` if a token is configured:
+///
+/// - `GET /health`: uptime, client and session counts, rejected frames
+/// - `GET /clients`: connected clients and their traffic
+/// - `POST /clients/{peer_id}/kick`: disconnects a client
+/// - `POST /broadcast` with `{"key": "KEY_A"}`: presses a key on every client
+pub fn spawn(addr: &str, state: Arc<ServerState>) -> Result<()> {
+    let server = tiny_http::Server::http(addr)
+        .map_err(|e| anyhow::anyhow!("Failed to bind admin API to {}: {}", addr, e))?;
+    tracing::info!("Admin API listening on {}", addr);
+
+    thread::spawn(move || {
+        for mut request in server.incoming_requests() {
+            let (status, body) = handle(&state, &mut request);
+            tracing::debug!(method = %request.method(), url = request.url(), status, "Admin request");
+            let response = Response::from_string(body)
+                .with_status_code(status)
+                .with_header(
+                    Header::from_bytes("Content-Type", "application/json")
+                        .expect("static header is valid"),
+                );
+            if let Err(e) = request.respond(response) {
+                tracing::warn!(error = %e, "Failed to answer admin request");
+            }
+        }
+    });
+    Ok(())
+}
+
+fn handle(state: &ServerState, request: &mut Request) -> Reply {
+    let authorization = request
+        .headers()
+        .iter()
+        .find(|header| header.field.equiv("Authorization"))
+        .map(|header| header.value.as_str());
+    if !authorized(state.config().admin_token.as_deref(), authorization) {
+        return error(401, "missing or wrong bearer token".to_string());
+    }
+
+    let url = request.url().to_string();
+    let path: Vec<&str> = url
+        .split('?')
+        .next()
+        .unwrap_or_default()
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .collect();
+
+    match (request.method(), path.as_slice()) {
+        (Method::Get, ["health"]) => json(200, &state.health()),
+        (Method::Get, ["clients"]) => json(200, &state.clients()),
+        (Method::Post, ["clients", peer_id, "kick"]) => match peer_id.parse::<PeerId>() {
+            Ok(peer_id) if state.kick(peer_id) => {
+                json(200, &serde_json::json!({"kicked": peer_id}))
+            }
+            Ok(peer_id) => error(404, format!("no connected client with peer id {}", peer_id)),
+            Err(_) => error(400, format!("invalid peer id: {}", peer_id)),
+        },
+        (Method::Post, ["broadcast"]) => broadcast(state, request),
+        (_, ["health"] | ["clients"] | ["clients", _, "kick"] | ["broadcast"]) => {
+            error(405, "method not allowed".to_string())
+        }
+        _ => error(404, format!("not found: {}", url)),
+    }
+}
+
+fn broadcast(state: &ServerState, request: &mut Request) -> Reply {
+    let body: BroadcastRequest =
+        match serde_json::from_reader(std::io::Read::take(request.as_reader(), MAX_BODY_SIZE)) {
+            Ok(body) => body,
+            Err(e) => return error(400, format!("invalid request body: {}", e)),
+        };
+    let key = match body.key {
+        KeyArg::Code(code) => code,
+        KeyArg::Name(name) => match KeyCode::from_str(&name) {
+            Ok(key) => key.code(),
+            Err(_) => return error(400, format!("unknown key: {}", name)),
+        },
+    };
+    if key > KEY_MAX {
+        return error(400, format!("key code {} is out of range", key));
+    }
+    if !state.allows_key(key) {
+        return error(
+            403,
+            format!("{:?} is denied by server policy", KeyCode::new(key)),
+        );
+    }
+    match state.broadcast_operator_key(key) {
+        Ok(()) => json(200, &serde_json::json!({"broadcast": key})),
+        Err(e) => error(500, format!("{:#}", e)),
+    }
+}
+
+/// Whether the `Authorization` header carries `token`, if one is required.
+fn authorized(token: Option<&str>, authorization: Option<&str>) -> bool {
+    let Some(token) = token else { return true };
+    authorization
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|given| crate::utils::secrets_match(token, given))
+}
+
+fn json<T: Serialize>(status: u16, body: &T) -> Reply {
+    match serde_json::to_string(body) {
+        Ok(body) => (status, body),
+        Err(e) => error(500, e.to_string()),
+    }
+}
+
+fn error(status: u16, error: String) -> Reply {
+    let body = serde_json::to_string(&ErrorBody { error }).unwrap_or_default();
+    (status, body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn open_without_a_token() {
+        assert!(authorized(None, None));
+        assert!(authorized(None, Some("Bearer anything")));
+    }
+
+    #[test]
+    fn requires_the_configured_token() {
+        let token = Some("s3cret");
+        assert!(authorized(token, Some("Bearer s3cret")));
+        assert!(!authorized(token, None));
+        assert!(!authorized(token, Some("Bearer s3cre")));
+        assert!(!authorized(token, Some("Bearer s3cret2")));
+        assert!(!authorized(token, Some("Basic s3cret")));
+        assert!(!authorized(token, Some("s3cret")));
+    }
+}
@@ -1,14 +1,40 @@
 use anyhow::{Context, Result};
 use evdev::{KeyCode, uinput::VirtualDevice};
 use rand::Rng;
-use std::io::{self, Read, Write};
-use std::sync::mpsc;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, mpsc};
 use std::thread;
+use std::time::{Duration, Instant};
 
-use crate::config::{KeyCodeMap, KeySyncConfig};
+use crate::ack::{AckTracker, Arrival, DeliveryReport, DuplicateFilter};
+use crate::clock::{ClockSync, now_micros};
+use crate::config::{KeyCodeMap, KeySyncConfig, LiveConfig};
+use crate::control::ProfileRequest;
 use crate::keyboard::KeyboardMonitor;
-use crate::protocol::KeyEvent;
-use crate::reconnectable_stream::ReconnectableTcpStream;
+use crate::metrics::{Counter, CounterVec, Encoder, Histogram};
+use crate::offline_queue::{OfflineQueue, OfflineQueueConfig};
+use crate::protocol::{
+    Ack, Codec, Compression, Goodbye, KeyEvent, MAX_BATCH_LEN, Message, PeerId, Ping,
+};
+use crate::reconnectable_stream::{
+    ClientIdentity, ConnectionState, ReconnectPolicy, ReconnectableTcpStream, StreamMonitor,
+};
+use crate::scheduler::{Humanizer, Scheduler, instant_at};
+
+/// How often the sender wakes up without key events, to notice that the
+/// connection came back and flush the offline queue.
+const SEND_POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// How often the clock offset to the server is re-sampled.
+const PING_INTERVAL: Duration = Duration::from_secs(10);
+/// How long shutting down may take before the client exits regardless.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(3);
+/// How many stale events are remembered, so their retransmissions aren't
+/// acked either.
+const STALE_MEMORY: usize = 64;
 
 fn make_client_id() -> String {
     let username = ["SUDO_USER", "USER", "LOGNAME", "USERNAME"]
@@ -44,136 +70,961 @@ fn setup_virtual_device_from_map(incoming_map: &KeyCodeMap) -> Result<VirtualDev
         .context("Failed to build virtual keyboard")
 }
 
+/// The keys the virtual keyboard needs for `incoming_map`.
+fn virtual_keys(incoming_map: &KeyCodeMap) -> HashSet<KeyCode> {
+    incoming_map.values().copied().collect()
+}
+
+/// Loads the config file into the live config, for the active profile, and
+/// switches between profiles while the client runs.
+struct Profiles {
+    path: PathBuf,
+    /// Held while loading, so that reloads and switches apply one at a time.
+    active: Mutex<Option<String>>,
+    config: LiveConfig,
+    scheduler: Scheduler,
+}
+
+impl Profiles {
+    /// Reads the config file again, for the active profile.
+    fn reload(&self) -> Result<()> {
+        let active = self.active.lock().unwrap();
+        self.apply(active.as_deref())?;
+        tracing::info!(profile = ?*active, "Reloaded config");
+        Ok(())
+    }
+
+    /// Switches to `profile`, or to just the base settings.
+    fn select(&self, profile: Option<String>) -> Result<String> {
+        let mut active = self.active.lock().unwrap();
+        self.apply(profile.as_deref())?;
+        *active = profile;
+        let name = profile_name(active.as_deref());
+        tracing::info!(profile = name, "Switched profile");
+        Ok(name.to_string())
+    }
+
+    /// Switches to the profile after the active one, in name order, going
+    /// back to the base settings after the last.
+    fn next(&self) -> Result<String> {
+        let names = self.config.get().profiles.clone();
+        if names.is_empty() {
+            return Err(anyhow::anyhow!("No profiles in {}", self.path.display()));
+        }
+        let next = match self.active() {
+            None => names.first().cloned(),
+            Some(active) => names
+                .iter()
+                .position(|name| *name == active)
+                .and_then(|i| names.get(i + 1).cloned()),
+        };
+        self.select(next)
+    }
+
+    fn active(&self) -> Option<String> {
+        self.active.lock().unwrap().clone()
+    }
+
+    /// Loads `profile` from the file and swaps it in, rebuilding the virtual
+    /// keyboard only if it needs a different set of keys.
+    fn apply(&self, profile: Option<&str>) -> Result<()> {
+        let path = &self.path;
+        let file =
+            File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+        let new = KeySyncConfig::from_reader(file, profile)
+            .with_context(|| format!("Invalid {}", path.display()))?;
+        if new.incoming.is_empty() && new.outgoing.is_empty() {
+            return Err(anyhow::anyhow!(
+                "No key mappings found in {}",
+                path.display()
+            ));
+        }
+
+        if virtual_keys(&new.incoming) != virtual_keys(&self.config.get().incoming) {
+            self.scheduler
+                .set_device(setup_virtual_device_from_map(&new.incoming)?)?;
+            tracing::info!("Rebuilt the virtual keyboard for the new set of keys");
+        }
+        tracing::debug!(
+            incoming = new.incoming.len(),
+            outgoing = new.outgoing.len(),
+            devices = ?new.devices,
+            "Applied config"
+        );
+        self.config.set(new);
+        Ok(())
+    }
+
+    /// Carries out a request from the control socket.
+    fn handle(&self, request: ProfileRequest) -> Result<String> {
+        match request {
+            ProfileRequest::Active => Ok(profile_name(self.active().as_deref()).to_string()),
+            ProfileRequest::Next => self.next(),
+            ProfileRequest::Select(profile) => self.select(profile),
+        }
+    }
+}
+
+fn profile_name(profile: Option<&str>) -> &str {
+    profile.unwrap_or("base")
+}
+
 fn handle_incoming_key(
     event: &KeyEvent,
-    incoming_map: &KeyCodeMap,
-    virtual_keyboard: &mut VirtualDevice,
+    config: &KeySyncConfig,
+    scheduler: &Scheduler,
+    humanizer: &mut Humanizer,
+    sender: &str,
+    latency: &Latency,
+    press_at: Instant,
 ) -> Result<()> {
-    let mapped_key = match incoming_map.get(&KeyCode::new(event.key)) {
+    let key = KeyCode::new(event.key);
+    let mapped_key = match config.incoming.get(&key) {
         Some(key) => key,
         None => return Ok(()),
     };
+    if !config.key_filter.allows(mapped_key.code()) {
+        tracing::warn!(
+            key = %event.key,
+            target_key = ?mapped_key,
+            client_id = %sender,
+            "Refusing denied key"
+        );
+        return Ok(());
+    }
 
     tracing::info!(
         key = %event.key,
         target_key = ?mapped_key,
-        client_id = %event.client_id,
+        client_id = %sender,
+        latency_ms = latency.one_way_ms(),
+        age_ms = latency.age_ms(),
         "Received key event"
     );
 
-    press_key(virtual_keyboard, *mapped_key).context("Failed to simulate key press")?;
+    let (delay, hold) = humanizer.timing(config.humanize.get(&key));
+    if !delay.is_zero() || !hold.is_zero() {
+        tracing::debug!(
+            delay_ms = delay.as_secs_f64() * 1000.0,
+            hold_ms = hold.as_secs_f64() * 1000.0,
+            "Humanized key press"
+        );
+    }
 
-    Ok(())
+    scheduler.press_at(press_at + delay, *mapped_key, hold)
+}
+
+/// Timing of a received key event, measured on the server's clock.
+struct Latency {
+    /// From the sender writing the event to now.
+    one_way_us: i64,
+    /// From the original key press to now.
+    age_us: i64,
+}
+
+impl Latency {
+    fn measure(event: &KeyEvent, clock: &ClockSync) -> Self {
+        let now_us = clock.server_now() as i64;
+        Self {
+            one_way_us: now_us - event.sent_at_us as i64,
+            age_us: now_us - event.timestamp_us as i64,
+        }
+    }
+
+    fn one_way_ms(&self) -> f64 {
+        self.one_way_us as f64 / 1000.0
+    }
+
+    fn age_ms(&self) -> f64 {
+        self.age_us as f64 / 1000.0
+    }
+}
+
+fn log_delivery(report: &DeliveryReport, shared: &Shared) {
+    let missing: Vec<String> = report
+        .missing()
+        .into_iter()
+        .map(|id| shared.peer_name(id))
+        .collect();
+    shared.metrics.acks_missing.add(missing.len() as u64);
+    if missing.is_empty() {
+        tracing::debug!(
+            id = report.id,
+            key = report.key,
+            recipients = report.recipients.len(),
+            "Key event acknowledged by all recipients"
+        );
+    } else {
+        tracing::warn!(
+            id = report.id,
+            key = report.key,
+            missing = ?missing,
+            "Key event was not acknowledged"
+        );
+    }
+}
+
+/// Acknowledges `event` back to its sender, if it asked for that.
+fn send_ack(stream: &mut ReconnectableTcpStream, event: &KeyEvent) {
+    if !event.ack {
+        return;
+    }
+    let ack = Ack {
+        sender: event.sender,
+        id: event.id,
+        receiver: 0,
+    };
+    if let Err(e) = stream.write_message(&Message::Ack(ack)) {
+        tracing::warn!(error = %e, id = event.id, "Failed to acknowledge key event");
+    }
+}
+
+/// What the receiving thread needs to act on incoming key events.
+struct Receiver {
+    config: LiveConfig,
+    shared: Arc<Shared>,
+    scheduler: Scheduler,
+    humanizer: Humanizer,
+    duplicates: DuplicateFilter,
+    max_event_age: Option<Duration>,
+    /// Recently dropped stale events, by sender and id.
+    stale: VecDeque<(PeerId, u64)>,
+}
+
+impl Receiver {
+    fn key_event(&mut self, stream: &mut ReconnectableTcpStream, event: KeyEvent) {
+        tracing::trace!(event = ?event, "Received message from server");
+        stream.record_received(event.seq);
+        let sender = self.shared.peer_name(event.sender);
+        match self.duplicates.check(&event) {
+            arrival @ (Arrival::New | Arrival::OutOfOrder) => {
+                if arrival == Arrival::OutOfOrder {
+                    tracing::debug!(id = event.id, client_id = %sender, "Received key event out of order");
+                }
+                let latency = Latency::measure(&event, &self.shared.clock.lock().unwrap());
+                let metrics = &self.shared.metrics;
+                metrics.events_received.inc(&sender);
+                metrics.latency.observe_us(latency.one_way_us);
+                metrics.age.observe_us(latency.age_us);
+                if self
+                    .max_event_age
+                    .is_some_and(|max| latency.age_us > max.as_micros() as i64)
+                {
+                    metrics.events_dropped.inc("stale");
+                    // Not acked, so the sender reports it as missing.
+                    tracing::warn!(
+                        key = event.key,
+                        client_id = %sender,
+                        age_ms = latency.age_ms(),
+                        "Dropping stale key event"
+                    );
+                    if self.stale.len() >= STALE_MEMORY {
+                        self.stale.pop_front();
+                    }
+                    self.stale.push_back((event.sender, event.id));
+                    return;
+                }
+                send_ack(stream, &event);
+                if let Err(e) = handle_incoming_key(
+                    &event,
+                    &self.config.get(),
+                    &self.scheduler,
+                    &mut self.humanizer,
+                    &sender,
+                    &latency,
+                    press_time(&event, &sender, &self.shared.clock),
+                ) {
+                    tracing::warn!(error = %e, "Error handling incoming key");
+                }
+            }
+            Arrival::Duplicate => {
+                // The sender may have missed our first ack.
+                self.shared.metrics.events_dropped.inc("duplicate");
+                tracing::debug!(id = event.id, client_id = %sender, "Dropping duplicate key event");
+                self.ack_unless_stale(stream, &event);
+            }
+            Arrival::TooOld => {
+                // Possibly pressed already, so it isn't pressed again, nor
+                // acked: the sender reports it as missing.
+                self.shared.metrics.events_dropped.inc("too_old");
+                tracing::warn!(id = event.id, client_id = %sender, "Dropping key event too far out of order");
+            }
+        }
+    }
+
+    /// Acks a retransmitted event, unless it was first dropped as stale.
+    fn ack_unless_stale(&self, stream: &mut ReconnectableTcpStream, event: &KeyEvent) {
+        if !self.stale.contains(&(event.sender, event.id)) {
+            send_ack(stream, event);
+        }
+    }
 }
 
 fn receive_server_messages(
     mut stream: ReconnectableTcpStream,
-    incoming_map: KeyCodeMap,
+    config: LiveConfig,
+    shared: Arc<Shared>,
+    scheduler: Scheduler,
+    max_event_age: Option<Duration>,
 ) -> Result<()> {
-    let mut buffer = [0; 1024];
-    let mut virtual_keyboard = setup_virtual_device_from_map(&incoming_map)?;
+    let mut receiver = Receiver {
+        scheduler,
+        humanizer: Humanizer::new(config.get().humanize_seed),
+        duplicates: DuplicateFilter::default(),
+        stale: VecDeque::new(),
+        config,
+        shared: Arc::clone(&shared),
+        max_event_age,
+    };
 
     loop {
-        match stream.read(&mut buffer) {
-            Ok(0) => {
-                tracing::warn!("Server closed the connection");
-                break;
-            }
-            Ok(bytes_read) => {
-                tracing::trace!(message = %String::from_utf8_lossy(&buffer[..bytes_read]), "Received message from server");
-
-                match KeyEvent::from_slice(&buffer[..bytes_read]) {
-                    Ok(event) => {
-                        if let Err(e) =
-                            handle_incoming_key(&event, &incoming_map, &mut virtual_keyboard)
-                        {
-                            tracing::warn!(error = %e, "Error handling incoming key");
-                        }
+        match stream.read_frame() {
+            Ok(frame) => match stream.wire().decode(&frame) {
+                Ok(Message::Key(event)) => receiver.key_event(&mut stream, event),
+                Ok(Message::Batch(events)) => {
+                    tracing::trace!(count = events.len(), "Received batch of key events");
+                    for event in events {
+                        receiver.key_event(&mut stream, event);
+                    }
+                }
+                Ok(Message::Peer(peer)) => {
+                    tracing::debug!(peer_id = peer.id, client_id = %peer.client_id, "Learned peer");
+                    let previous = shared
+                        .peers
+                        .lock()
+                        .unwrap()
+                        .insert(peer.id, peer.client_id.clone());
+                    if previous.is_some_and(|previous| previous != peer.client_id) {
+                        // The server restarted and handed the id to someone else.
+                        receiver.duplicates.forget(peer.id);
                     }
-                    Err(e) => {
-                        tracing::warn!(error = %e, "Failed to parse key event");
+                }
+                Ok(Message::Receipt(receipt)) => {
+                    let report = shared.ack_tracker.lock().unwrap().receipt(receipt);
+                    if let Some(report) = report {
+                        log_delivery(&report, &shared);
                     }
                 }
-            }
+                Ok(Message::Ack(ack)) => {
+                    let report = shared.ack_tracker.lock().unwrap().ack(&ack);
+                    if let Some(report) = report {
+                        log_delivery(&report, &shared);
+                    }
+                }
+                Ok(Message::Goodbye(goodbye)) => {
+                    tracing::warn!(
+                        reason = %goodbye.reason,
+                        retry_after_ms = goodbye.retry_after_ms,
+                        "Server is closing the connection"
+                    );
+                    if let Some(retry_after_ms) = goodbye.retry_after_ms {
+                        stream.defer_reconnect(Duration::from_millis(retry_after_ms));
+                    }
+                }
+                Ok(Message::Pong(pong)) => {
+                    shared
+                        .clock
+                        .lock()
+                        .unwrap()
+                        .record(pong.sent_us, pong.server_us, now_micros());
+                }
+                Ok(other) => {
+                    tracing::warn!(message = ?other, "Unexpected message from server");
+                }
+                Err(e) => {
+                    tracing::warn!(error = %e, "Failed to parse message");
+                }
+            },
+            Err(_) if stream.state() == ConnectionState::Closed => return Ok(()),
             Err(e) => {
-                return Err(anyhow::anyhow!("Error reading from server: {}", e));
+                tracing::warn!(error = %e, "Error reading from server");
+                stream
+                    .reconnect()
+                    .context("Failed to reconnect to server")?;
             }
         }
     }
+}
+
+/// When to press the key for `event`: its scheduled time translated to our
+/// clock, or right away.
+fn press_time(event: &KeyEvent, sender: &str, clock: &Mutex<ClockSync>) -> Instant {
+    let Some(execute_at_us) = event.execute_at_us else {
+        return Instant::now();
+    };
+    let local_us = clock.lock().unwrap().to_local_time(execute_at_us);
+    let now_us = now_micros();
+    if local_us < now_us {
+        tracing::warn!(
+            key = event.key,
+            client_id = %sender,
+            late_ms = (now_us - local_us) as f64 / 1000.0,
+            "Scheduled key event arrived too late"
+        );
+    }
+    instant_at(local_us)
+}
 
+/// Stamps `events` with the current server time and writes them to the
+/// server, as a single `Key` or in batches.
+fn write_key_events(
+    stream: &mut ReconnectableTcpStream,
+    clock: &Mutex<ClockSync>,
+    events: &[KeyEvent],
+) -> io::Result<()> {
+    for chunk in events.chunks(MAX_BATCH_LEN) {
+        let sent_at_us = clock.lock().unwrap().server_now();
+        let mut chunk = chunk.to_vec();
+        for event in &mut chunk {
+            event.sent_at_us = sent_at_us;
+        }
+        let message = match <[KeyEvent; 1]>::try_from(chunk) {
+            Ok([event]) => Message::Key(event),
+            Err(chunk) => Message::Batch(chunk),
+        };
+        stream.write_message(&message)?;
+    }
     Ok(())
 }
 
-fn send_key_events(mut stream: ReconnectableTcpStream, rx: mpsc::Receiver<KeyEvent>) -> Result<()> {
-    for event in rx {
-        let payload = event.to_payload()?;
+fn send_key_events(
+    stream: &mut ReconnectableTcpStream,
+    shared: &Shared,
+    events: &[KeyEvent],
+) -> Result<()> {
+    write_key_events(stream, &shared.clock, events)
+        .context("Failed to send key events to server")?;
+    shared.metrics.events_sent.add(events.len() as u64);
+    let mut tracker = shared.ack_tracker.lock().unwrap();
+    for event in events.iter().filter(|event| event.ack) {
+        tracker.sent(event);
+    }
+    Ok(())
+}
 
-        stream
-            .write_all(&payload)
-            .context("Failed to send key event to server")?;
+/// Waits up to `SEND_POLL_INTERVAL` for a key event, then collects whatever
+/// else arrives within `batch_window` of it. Returns `None` once the keyboard
+/// monitor has gone away.
+fn next_key_events(rx: &mpsc::Receiver<KeyEvent>, batch_window: Duration) -> Option<Vec<KeyEvent>> {
+    let first = match rx.recv_timeout(SEND_POLL_INTERVAL) {
+        Ok(event) => event,
+        Err(mpsc::RecvTimeoutError::Timeout) => return Some(Vec::new()),
+        Err(mpsc::RecvTimeoutError::Disconnected) => return None,
+    };
+    let mut events = vec![first];
+    let deadline = Instant::now() + batch_window;
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            break;
+        }
+        match rx.recv_timeout(remaining) {
+            Ok(event) => events.push(event),
+            Err(_) => break,
+        }
     }
+    Some(events)
+}
 
-    Ok(())
+/// Converts the local times the keyboard stamped on `event` to the server's
+/// clock, as receivers expect.
+fn to_server_clock(event: &mut KeyEvent, clock: &ClockSync) {
+    event.timestamp_us = clock.to_server_time(event.timestamp_us);
+    event.execute_at_us = event.execute_at_us.map(|at| clock.to_server_time(at));
 }
 
-pub fn run(server_addr: &str) -> Result<()> {
-    let client_id = make_client_id();
-    let config_path = KeySyncConfig::file_name();
+/// Forwards key events to the server. While the connection is down (the
+/// receiving side drives reconnection), events go to the offline queue
+/// instead of blocking, and are flushed per its policy once it is back.
+///
+/// Events read within `batch_window` of each other go out as one frame.
+fn forward_key_events(
+    mut stream: ReconnectableTcpStream,
+    rx: mpsc::Receiver<KeyEvent>,
+    offline_config: OfflineQueueConfig,
+    batch_window: Duration,
+    shared: Arc<Shared>,
+) -> Result<()> {
+    let states = stream.subscribe();
+    let mut offline_queue = OfflineQueue::new(offline_config);
+    let mut next_id = 1;
+    let mut last_ping: Option<Instant> = None;
 
-    let config_file =
-        match crate::utils::open_or_create(config_path).context("Failed to open config file") {
-            Ok((mut file, created)) if created => {
-                file.write_all(KeySyncConfig::default_config_string().as_bytes())?;
-                file
+    while let Some(mut events) = next_key_events(&rx, batch_window) {
+        for event in &mut events {
+            event.id = next_id;
+            next_id += 1;
+            to_server_clock(event, &shared.clock.lock().unwrap());
+        }
+
+        for state in states.try_iter() {
+            match state {
+                ConnectionState::GaveUp => {
+                    return Err(anyhow::anyhow!("Gave up reconnecting to server"));
+                }
+                ConnectionState::Disconnected => {
+                    tracing::info!("Server unreachable; queueing outgoing key events");
+                }
+                // Take a fresh clock sample on the new connection right away.
+                ConnectionState::Connected => last_ping = None,
+                _ => {}
             }
-            Ok((file, _)) => {
-                tracing::info!("Config file found, using existing file");
-                file
+        }
+
+        if stream.state() == ConnectionState::Connected {
+            if last_ping.is_none_or(|at| at.elapsed() >= PING_INTERVAL) {
+                last_ping = Some(Instant::now());
+                let ping = Ping {
+                    sent_us: now_micros(),
+                };
+                if let Err(e) = stream.write_message(&Message::Ping(ping)) {
+                    tracing::warn!(error = %e, "Failed to ping server");
+                }
             }
-            Err(e) => {
-                tracing::error!("Failed to open config file: {}", e);
-                return Err(e);
+
+            if let Err(e) =
+                offline_queue.flush(|events| send_key_events(&mut stream, &shared, events))
+            {
+                tracing::warn!(error = %e, "Failed to flush queued key events");
             }
-        };
 
-    let config = KeySyncConfig::from_reader(config_file).context("failed to parse config file")?;
+            let (retransmit, reports) = shared.ack_tracker.lock().unwrap().poll();
+            if !retransmit.is_empty() {
+                tracing::debug!(
+                    count = retransmit.len(),
+                    "Retransmitting unacknowledged key events"
+                );
+                if let Err(e) = write_key_events(&mut stream, &shared.clock, &retransmit) {
+                    tracing::warn!(error = %e, "Failed to retransmit key events");
+                }
+            }
+            for report in &reports {
+                log_delivery(report, &shared);
+            }
+        } else {
+            offline_queue.expire();
+        }
+
+        if events.is_empty() {
+            continue;
+        }
+        if !offline_queue.is_empty() || stream.state() != ConnectionState::Connected {
+            events
+                .into_iter()
+                .for_each(|event| offline_queue.push(event));
+        } else if let Err(e) = send_key_events(&mut stream, &shared, &events) {
+            tracing::warn!(error = %e, "Queueing key events until reconnected");
+            events
+                .into_iter()
+                .for_each(|event| offline_queue.push(event));
+        }
+    }
+
+    Ok(())
+}
+
+#[derive(Default)]
+struct ClientMetrics {
+    events_sent: Counter,
+    /// By the sender's `client_id`.
+    events_received: CounterVec,
+    /// By reason.
+    events_dropped: CounterVec,
+    acks_missing: Counter,
+    latency: Histogram,
+    age: Histogram,
+}
+
+/// State shared by the sending and receiving threads.
+struct Shared {
+    ack_tracker: Mutex<AckTracker>,
+    clock: Mutex<ClockSync>,
+    /// The `client_id` of every peer the server has announced.
+    peers: Mutex<HashMap<PeerId, String>>,
+    metrics: ClientMetrics,
+}
+
+impl Shared {
+    fn peer_name(&self, id: PeerId) -> String {
+        match self.peers.lock().unwrap().get(&id) {
+            Some(client_id) => client_id.clone(),
+            None => format!("#{}", id),
+        }
+    }
+
+    fn render_metrics(&self, stream: &StreamMonitor) -> String {
+        let metrics = &self.metrics;
+        let mut encoder = Encoder::default();
+        encoder.gauge(
+            "keysync_connected",
+            "Whether the client is connected to the server.",
+            if stream.state() == ConnectionState::Connected {
+                1.0
+            } else {
+                0.0
+            },
+        );
+        encoder.counter(
+            "keysync_reconnect_attempts_total",
+            "Attempts to reconnect to the server.",
+            stream.reconnect_attempts(),
+        );
+        encoder.gauge(
+            "keysync_clock_offset_seconds",
+            "Estimated server clock minus local clock.",
+            self.clock.lock().unwrap().offset_us() as f64 / 1_000_000.0,
+        );
+        encoder.counter(
+            "keysync_events_sent_total",
+            "Key events sent to the server.",
+            metrics.events_sent.get(),
+        );
+        encoder.counter_vec(
+            "keysync_events_received_total",
+            "New key events received, by sender.",
+            "client_id",
+            &metrics.events_received,
+        );
+        encoder.counter_vec(
+            "keysync_events_dropped_total",
+            "Received key events that were not pressed, by reason.",
+            "reason",
+            &metrics.events_dropped,
+        );
+        encoder.counter(
+            "keysync_acks_missing_total",
+            "Recipients that never acknowledged an event we sent.",
+            metrics.acks_missing.get(),
+        );
+        encoder.histogram(
+            "keysync_event_latency_seconds",
+            "From the sender writing a key event to receiving it.",
+            &metrics.latency,
+        );
+        encoder.histogram(
+            "keysync_event_age_seconds",
+            "From the original key press to receiving the event.",
+            &metrics.age,
+        );
+        encoder.finish()
+    }
+}
+
+/// Tunables for the client's connection to the server.
+#[derive(Debug, Clone)]
+pub struct ClientOptions {
+    /// Tried in order, on every connection attempt.
+    pub server_addresses: Vec<String>,
+    /// How the client introduces itself; made up from the user name if unset.
+    pub client_id: Option<String>,
+    pub reconnect: ReconnectPolicy,
+    pub offline_queue: OfflineQueueConfig,
+    /// How long to wait for acknowledgements before retransmitting.
+    pub ack_timeout: Duration,
+    pub ack_retransmits: u32,
+    /// Incoming key events pressed longer ago than this are dropped.
+    pub max_event_age: Option<Duration>,
+    /// Outgoing key events within this window of each other share a frame.
+    pub batch_window: Duration,
+    pub codec: Codec,
+    /// Labels shown to server operators.
+    pub tags: Vec<String>,
+    /// The channel to join on the server.
+    pub channel: Option<String>,
+    /// The auth secret to present to the server.
+    pub secret: Option<String>,
+    /// Compression to offer the server.
+    pub compression: Compression,
+    /// Serve Prometheus metrics on this address.
+    pub metrics_address: Option<String>,
+    /// The profile in the config file to use, if not just its base settings.
+    pub profile: Option<String>,
+    /// Take commands, such as switching profiles, on this Unix socket.
+    pub control_socket: Option<PathBuf>,
+}
+
+/// Writes the commented example config to the user's config file, for a
+/// first run without any config.
+fn create_config() -> Result<PathBuf> {
+    let path = KeySyncConfig::user_path()
+        .context("No config file found and no home directory to create one in; pass --config")?;
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+    }
+    let (mut file, _) = crate::utils::open_or_create(&path)
+        .with_context(|| format!("Failed to create {}", path.display()))?;
+    file.write_all(KeySyncConfig::default_config_string().as_bytes())?;
+    tracing::warn!(path = %path.display(), "No config file found; created one with examples");
+    Ok(path)
+}
+
+/// The config file to use, per `KeySyncConfig::locate`, creating one with
+/// examples if there is none.
+pub fn find_config(explicit: Option<&Path>) -> Result<PathBuf> {
+    match KeySyncConfig::locate(explicit) {
+        Some(path) => Ok(path),
+        None => create_config(),
+    }
+}
+
+pub fn run(config_path: &Path, options: ClientOptions) -> Result<Shutdown> {
+    if options.server_addresses.is_empty() {
+        return Err(anyhow::anyhow!("No server address given"));
+    }
+    let client_id = options.client_id.clone().unwrap_or_else(make_client_id);
+    let config_path = config_path.to_path_buf();
+
+    let config_file = File::open(&config_path)
+        .with_context(|| format!("Failed to open config file {}", config_path.display()))?;
+    let config = KeySyncConfig::from_reader(config_file, options.profile.as_deref())
+        .with_context(|| format!("Failed to parse config file {}", config_path.display()))?;
+    tracing::info!(path = %config_path.display(), profile = options.profile, "Loaded config");
 
     if config.incoming.is_empty() && config.outgoing.is_empty() {
         return Err(anyhow::anyhow!(
-            "No key mappings found in config file, please configure"
+            "No key mappings found in {}, please configure",
+            config_path.display()
         ));
     }
 
+    let scheduler = Scheduler::spawn(setup_virtual_device_from_map(&config.incoming)?);
+    let config = LiveConfig::new(config);
+    let profiles = Arc::new(Profiles {
+        path: config_path.clone(),
+        active: Mutex::new(options.profile.clone()),
+        config: config.clone(),
+        scheduler: scheduler.clone(),
+    });
+    {
+        let profiles = Arc::clone(&profiles);
+        crate::watch::watch_file(&config_path, move || {
+            if let Err(e) = profiles.reload() {
+                tracing::error!(
+                    "Ignoring config change, keeping the current config: {:#}",
+                    e
+                );
+            }
+        })?;
+        tracing::info!(path = %config_path.display(), "Watching config for changes");
+    }
+    // Held until the client returns, which removes the socket.
+    let _control_socket = match &options.control_socket {
+        Some(path) => {
+            let profiles = Arc::clone(&profiles);
+            Some(crate::control::serve(path, move |request| {
+                profiles.handle(request)
+            })?)
+        }
+        None => None,
+    };
+
+    let stop = Arc::new(AtomicBool::new(false));
     let (tx, rx) = mpsc::channel();
+    let on_hotkey = {
+        let profiles = Arc::clone(&profiles);
+        move || {
+            if let Err(e) = profiles.next() {
+                tracing::warn!("Failed to switch profile: {:#}", e);
+            }
+        }
+    };
+    let monitor = KeyboardMonitor::new(tx, config.clone(), Arc::clone(&stop), on_hotkey);
+
+    let mut stream = ReconnectableTcpStream::new(
+        &options.server_addresses,
+        options.reconnect,
+        ClientIdentity {
+            client_id,
+            tags: options.tags,
+            channel: options.channel,
+            secret: options.secret,
+        },
+        options.codec,
+        options.compression,
+    )?;
+
+    let shared = Arc::new(Shared {
+        ack_tracker: Mutex::new(AckTracker::new(
+            options.ack_timeout,
+            options.ack_retransmits,
+        )),
+        clock: Mutex::new(ClockSync::default()),
+        peers: Mutex::new(HashMap::new()),
+        metrics: ClientMetrics::default(),
+    });
+
+    if let Some(metrics_address) = &options.metrics_address {
+        let shared = Arc::clone(&shared);
+        let monitor = stream.monitor();
+        crate::metrics::serve(metrics_address, move || shared.render_metrics(&monitor))?;
+    }
 
-    let monitor = KeyboardMonitor::new(tx, config.clone(), client_id);
+    let (done_tx, done_rx) = mpsc::channel();
+    let signal_tx = done_tx.clone();
+    crate::utils::on_shutdown_signal(move |_| {
+        let _ = signal_tx.send(Stop::Signal);
+    })?;
 
-    let monitor_handle = thread::spawn(move || monitor.start());
+    let monitor_handle = spawn_worker("keyboard monitor", &done_tx, move || monitor.start());
+
+    let receiver_handle = {
+        let stream = stream.try_clone().context("Failed to clone stream")?;
+        let config = config.clone();
+        let shared = Arc::clone(&shared);
+        let scheduler = scheduler.clone();
+        let max_event_age = options.max_event_age;
+        spawn_worker("receiver", &done_tx, move || {
+            receive_server_messages(stream, config, shared, scheduler, max_event_age)
+        })
+    };
+
+    let sender_handle = {
+        let stream = stream.try_clone().context("Failed to clone stream")?;
+        let shared = Arc::clone(&shared);
+        let offline_queue = options.offline_queue;
+        let batch_window = options.batch_window;
+        spawn_worker("sender", &done_tx, move || {
+            forward_key_events(stream, rx, offline_queue, batch_window, shared)
+        })
+    };
 
-    let stream = ReconnectableTcpStream::new(server_addr)
-        .context(format!("Failed to connect to server at {}", server_addr))?;
+    let reason = done_rx
+        .recv()
+        .map_err(|_| anyhow::anyhow!("Every client thread went away"))?;
+    match &reason {
+        Stop::Signal => {}
+        Stop::Finished(name, Ok(())) => tracing::info!(thread = name, "Client thread finished"),
+        Stop::Finished(name, Err(e)) => {
+            tracing::error!(thread = name, error = %format!("{:#}", e), "Client thread failed")
+        }
+    }
+    tracing::info!("Shutting down");
 
-    let receive_stream = stream.try_clone().context("Failed to clone stream")?;
+    let deadline = Instant::now() + SHUTDOWN_TIMEOUT;
+    stop.store(true, Ordering::Relaxed);
+    // The sender finishes once the keyboard monitor has stopped feeding it.
+    let mut clean = join_within(monitor_handle, deadline);
+    clean &= join_within(sender_handle, deadline);
 
-    let incoming_map = config.incoming.clone();
-    let receiver_handle =
-        thread::spawn(move || receive_server_messages(receive_stream, incoming_map.clone()));
+    if stream.state() == ConnectionState::Connected {
+        let goodbye = Goodbye {
+            reason: "client shutting down".to_string(),
+            retry_after_ms: None,
+        };
+        if let Err(e) = stream.write_message(&Message::Goodbye(goodbye)) {
+            tracing::warn!(error = %e, "Failed to tell the server we are leaving");
+        }
+    }
+    stream.close();
+    clean &= join_within(receiver_handle, deadline);
 
-    let sender_result = send_key_events(stream, rx);
+    // Nothing presses keys any more, so whatever is down now stays released.
+    if let Err(e) = scheduler.release_all(deadline.saturating_duration_since(Instant::now())) {
+        tracing::warn!(error = %e, "Failed to release held keys");
+        clean = false;
+    }
 
-    monitor_handle
-        .join()
-        .map_err(|e| anyhow::anyhow!("Error joining keyboard monitor thread: {:?}", e))??;
+    for stop in done_rx.try_iter() {
+        if let Stop::Finished(name, Err(e)) = stop {
+            tracing::warn!(thread = name, error = %format!("{:#}", e), "Client thread failed while shutting down");
+        }
+    }
 
-    receiver_handle
-        .join()
-        .map_err(|e| anyhow::anyhow!("Error joining receiver thread: {:?}", e))??;
+    match reason {
+        Stop::Finished(_, Err(e)) => Err(e),
+        _ if clean => {
+            tracing::info!("Client stopped");
+            Ok(Shutdown::Clean)
+        }
+        _ => Ok(Shutdown::Incomplete),
+    }
+}
 
-    sender_result
+/// Why the client started shutting down.
+enum Stop {
+    Signal,
+    Finished(&'static str, Result<()>),
 }
 
-fn press_key(device: &mut VirtualDevice, key: KeyCode) -> io::Result<()> {
-    device.emit(&[*evdev::KeyEvent::new(key, 1)])?;
-    device.emit(&[*evdev::KeyEvent::new(key, 0)])?;
-    Ok(())
+/// How the client stopped.
+pub enum Shutdown {
+    /// Every thread stopped and every key was released in time.
+    Clean,
+    /// Gave up waiting for a thread to stop or for keys to be released.
+    Incomplete,
+}
+
+impl Shutdown {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Shutdown::Clean => 0,
+            Shutdown::Incomplete => 2,
+        }
+    }
+}
+
+/// Runs `work` on a named thread that reports its result on `done`.
+fn spawn_worker(
+    name: &'static str,
+    done: &mpsc::Sender<Stop>,
+    work: impl FnOnce() -> Result<()> + Send + 'static,
+) -> (&'static str, thread::JoinHandle<()>) {
+    let done = done.clone();
+    let handle = thread::spawn(move || {
+        let _ = done.send(Stop::Finished(name, work()));
+    });
+    (name, handle)
+}
+
+/// Waits for a worker until `deadline`, returning whether it stopped.
+fn join_within((name, handle): (&'static str, thread::JoinHandle<()>), deadline: Instant) -> bool {
+    while !handle.is_finished() {
+        if Instant::now() >= deadline {
+            tracing::warn!(thread = name, "Client thread did not stop in time");
+            return false;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    match handle.join() {
+        Ok(()) => true,
+        Err(e) => {
+            tracing::error!(thread = name, "Client thread panicked: {:?}", e);
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn converts_every_timestamp_to_server_clock() {
+        let mut clock = ClockSync::default();
+        // The server's clock reads 5ms ahead of ours.
+        clock.record(1_000, 6_000, 1_000);
+        let mut event = KeyEvent {
+            timestamp_us: 10_000,
+            execute_at_us: Some(60_000),
+            ..KeyEvent::default()
+        };
+
+        to_server_clock(&mut event, &clock);
+
+        assert_eq!(event.timestamp_us, 15_000);
+        assert_eq!(event.execute_at_us, Some(65_000));
+        // Receivers schedule the press for the same moment the sender meant.
+        assert_eq!(clock.to_local_time(event.execute_at_us.unwrap()), 60_000);
+    }
+
+    #[test]
+    fn keeps_unscheduled_events_unscheduled() {
+        let mut clock = ClockSync::default();
+        clock.record(1_000, 6_000, 1_000);
+        let mut event = KeyEvent::default();
+
+        to_server_clock(&mut event, &clock);
+
+        assert_eq!(event.execute_at_us, None);
+    }
 }
@@ -0,0 +1,75 @@
+use std::collections::VecDeque;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Number of recent ping exchanges considered when estimating the offset.
+const MAX_SAMPLES: usize = 8;
+
+/// Microseconds since the Unix epoch on the local clock.
+pub fn now_micros() -> u64 {
+    system_time_micros(SystemTime::now())
+}
+
+pub fn system_time_micros(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_micros() as u64)
+        .unwrap_or(0)
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Sample {
+    round_trip_us: u64,
+    offset_us: i64,
+}
+
+/// Estimates how far the server's clock is ahead of ours from ping exchanges,
+/// NTP style: the offset is taken from the sample with the shortest round
+/// trip, as that one had the least room for asymmetric delay.
+#[derive(Debug, Default)]
+pub struct ClockSync {
+    samples: VecDeque<Sample>,
+}
+
+impl ClockSync {
+    /// Records one exchange: we sent at `sent_us`, the server read its clock
+    /// at `server_us`, and the reply arrived at `received_us` (local clock).
+    pub fn record(&mut self, sent_us: u64, server_us: u64, received_us: u64) {
+        let round_trip_us = received_us.saturating_sub(sent_us);
+        let midpoint_us = sent_us + round_trip_us / 2;
+        let sample = Sample {
+            round_trip_us,
+            offset_us: server_us as i64 - midpoint_us as i64,
+        };
+        if self.samples.len() >= MAX_SAMPLES {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(sample);
+        tracing::debug!(
+            round_trip_us,
+            offset_us = sample.offset_us,
+            estimate_us = self.offset_us(),
+            "Clock sample"
+        );
+    }
+
+    /// The current estimate of server time minus local time, 0 until the
+    /// first exchange completes.
+    pub fn offset_us(&self) -> i64 {
+        self.samples
+            .iter()
+            .min_by_key(|sample| sample.round_trip_us)
+            .map_or(0, |sample| sample.offset_us)
+    }
+
+    pub fn to_server_time(&self, local_us: u64) -> u64 {
+        local_us.saturating_add_signed(self.offset_us())
+    }
+
+    pub fn to_local_time(&self, server_us: u64) -> u64 {
+        server_us.saturating_add_signed(-self.offset_us())
+    }
+
+    /// The current time on the server's clock, as best we know it.
+    pub fn server_now(&self) -> u64 {
+        self.to_server_time(now_micros())
+    }
+}
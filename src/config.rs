@@ -1,71 +1,794 @@
+use anyhow::Context;
+use clap::ValueEnum;
 use evdev::KeyCode;
 use serde::{Deserialize, Deserializer};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::fmt;
+use std::fs;
 use std::io::Read;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use crate::key_filter::{self, DANGEROUS_KEYS, KeyFilter, KeyRange};
+use crate::offline_queue::OfflinePolicy;
+use crate::protocol::{Codec, Compression};
 
 pub type KeyCodeMap = HashMap<KeyCode, KeyCode>;
 
+/// Names the config file to use, overriding the usual locations.
+const CONFIG_ENV: &str = "KEYSYNC_CONFIG";
+/// Machine-wide config, used when the user has none.
+const SYSTEM_CONFIG: &str = "/etc/keysync/config.yaml";
+/// Where the client used to look for its config.
+const LEGACY_CONFIG: &str = "config.yaml";
+
 #[derive(Debug, Clone)]
 pub struct KeySyncConfig {
     pub incoming: KeyCodeMap,
     pub outgoing: KeyCodeMap,
     pub devices: Option<Vec<String>>,
+    /// Outgoing keys (after mapping) that receivers must acknowledge.
+    pub acknowledge: HashSet<KeyCode>,
+    /// Outgoing keys (after mapping) that receivers press in unison, this
+    /// long after the key was pressed here.
+    pub schedule: HashMap<KeyCode, Duration>,
+    /// Randomized timing for incoming mappings, keyed by the remote key.
+    pub humanize: HashMap<KeyCode, Humanize>,
+    /// Seeds the randomness behind `humanize`, for reproducible timing.
+    pub humanize_seed: Option<u64>,
+    /// Incoming keys (after mapping) that are never pressed: the built-in
+    /// dangerous keys plus any listed under `deny`.
+    pub key_filter: KeyFilter,
+    /// Local keys that, held together, switch to the next profile instead of
+    /// being sent.
+    pub profile_hotkey: Vec<KeyCode>,
+    /// The names of the profiles in the file, sorted.
+    pub profiles: Vec<String>,
 }
 
-// Helper struct for raw deserialization (string keys/values)
+/// The config in use, shared between threads and replaced as a whole when
+/// the file changes, so no reader ever sees half of an edit.
+#[derive(Debug, Clone)]
+pub struct LiveConfig(Arc<RwLock<Arc<KeySyncConfig>>>);
+
+impl LiveConfig {
+    pub fn new(config: KeySyncConfig) -> Self {
+        Self(Arc::new(RwLock::new(Arc::new(config))))
+    }
+
+    pub fn get(&self) -> Arc<KeySyncConfig> {
+        Arc::clone(&self.0.read().unwrap())
+    }
+
+    pub fn set(&self, config: KeySyncConfig) {
+        *self.0.write().unwrap() = Arc::new(config);
+    }
+}
+
+/// Client command line options, given in the config file under their long
+/// names (with underscores). The command line and the environment win over
+/// the file. These are read once, at startup.
+#[derive(Debug, Default, Deserialize)]
+pub struct ClientSettings {
+    #[serde(default, deserialize_with = "one_or_many")]
+    pub server_address: Option<Vec<String>>,
+    pub client_id: Option<String>,
+    pub tags: Option<Vec<String>>,
+    pub channel: Option<String>,
+    pub secret: Option<String>,
+    pub connect_timeout_ms: Option<u64>,
+    pub max_reconnect_attempts: Option<u32>,
+    pub max_reconnect_backoff_ms: Option<u64>,
+    pub offline_queue_size: Option<usize>,
+    pub offline_ttl_ms: Option<u64>,
+    #[serde(default, deserialize_with = "value_enum")]
+    pub offline_policy: Option<OfflinePolicy>,
+    pub ack_timeout_ms: Option<u64>,
+    pub ack_retransmits: Option<u32>,
+    pub max_event_age_ms: Option<u64>,
+    pub batch_window_ms: Option<u64>,
+    #[serde(default, deserialize_with = "value_enum")]
+    pub compression: Option<Compression>,
+    #[serde(default, deserialize_with = "value_enum")]
+    pub codec: Option<Codec>,
+    pub metrics_address: Option<String>,
+    pub profile: Option<String>,
+    pub control_socket: Option<PathBuf>,
+}
+
+impl ClientSettings {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config file {}", path.display()))?;
+        // A file with nothing but comments sets nothing.
+        if text.lines().all(|line| {
+            let line = line.trim();
+            line.is_empty() || line.starts_with('#')
+        }) {
+            return Ok(Self::default());
+        }
+        let settings = serde_norway::from_str(&text)
+            .with_context(|| format!("Failed to parse config file {}", path.display()))?;
+        let value: serde_norway::Value = serde_norway::from_str(&text)?;
+        for finding in unknown_settings(&value) {
+            let setting = match finding.section.as_str() {
+                "" => finding.token,
+                section => format!("{}.{}", section, finding.token),
+            };
+            tracing::warn!(
+                path = %path.display(),
+                %setting,
+                "Ignoring {}; run `keysync config check` for details",
+                finding.message
+            );
+        }
+        Ok(settings)
+    }
+}
+
+// Repeatable options take a single value or a list.
+fn one_or_many<'de, D>(deserializer: D) -> Result<Option<Vec<String>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+    Ok(match Option::<OneOrMany>::deserialize(deserializer)? {
+        Some(OneOrMany::One(value)) => Some(vec![value]),
+        Some(OneOrMany::Many(values)) => Some(values),
+        None => None,
+    })
+}
+
+// Options with a fixed set of values are spelled as on the command line.
+fn value_enum<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: ValueEnum,
+{
+    let Some(value) = Option::<String>::deserialize(deserializer)? else {
+        return Ok(None);
+    };
+    T::from_str(&value, true).map(Some).map_err(|_| {
+        let choices: Vec<String> = T::value_variants()
+            .iter()
+            .filter_map(|v| v.to_possible_value())
+            .map(|v| v.get_name().to_string())
+            .collect();
+        serde::de::Error::custom(format!(
+            "invalid value '{}', expected one of: {}",
+            value,
+            choices.join(", ")
+        ))
+    })
+}
+
+/// An inclusive range of durations to pick from at random.
+#[derive(Debug, Clone, Copy)]
+pub struct DurationRange {
+    pub min: Duration,
+    pub max: Duration,
+}
+
+/// Per-mapping timing variation applied before a received key is pressed.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Humanize {
+    /// Extra delay before pressing.
+    pub delay: Option<DurationRange>,
+    /// How long the key is held down.
+    pub hold: Option<DurationRange>,
+}
+
+// Milliseconds, either fixed or a `[min, max]` range.
 #[derive(Deserialize)]
-struct RawKeySyncConfig {
+#[serde(untagged)]
+enum RawDurationRange {
+    Fixed(u64),
+    Range([u64; 2]),
+}
+
+impl RawDurationRange {
+    fn parse(&self) -> Result<DurationRange, String> {
+        let (min, max) = match *self {
+            RawDurationRange::Fixed(ms) => (ms, ms),
+            RawDurationRange::Range([min, max]) => (min, max),
+        };
+        if min > max {
+            return Err(format!("range [{}, {}] has min > max", min, max));
+        }
+        Ok(DurationRange {
+            min: Duration::from_millis(min),
+            max: Duration::from_millis(max),
+        })
+    }
+}
+
+// A key or key range as written in the file. YAML reads bare codes like `30`
+// or `0x1e` as numbers, so those are taken too.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct RawKey(String);
+
+impl<'de> Deserialize<'de> for RawKey {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct Visitor;
+
+        impl serde::de::Visitor<'_> for Visitor {
+            type Value = RawKey;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a key name or code")
+            }
+
+            fn visit_str<E>(self, v: &str) -> Result<RawKey, E> {
+                Ok(RawKey(v.to_string()))
+            }
+
+            fn visit_u64<E>(self, v: u64) -> Result<RawKey, E> {
+                Ok(RawKey(v.to_string()))
+            }
+
+            fn visit_i64<E>(self, v: i64) -> Result<RawKey, E> {
+                Ok(RawKey(v.to_string()))
+            }
+        }
+
+        deserializer.deserialize_any(Visitor)
+    }
+}
+
+impl std::ops::Deref for RawKey {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for RawKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// Pairs up the keys of a mapping from `from` to `to`. A range maps key by
+/// key onto a range of the same length, or all onto a single key.
+fn parse_mapping(from: &str, to: &str) -> Result<Vec<(KeyCode, KeyCode)>, String> {
+    let from_keys: Vec<KeyCode> = KeyRange::from_str(from)?.keys().collect();
+    let to_keys: Vec<KeyCode> = KeyRange::from_str(to)?.keys().collect();
+    match to_keys.as_slice() {
+        [to] => Ok(from_keys.into_iter().map(|from| (from, *to)).collect()),
+        _ if from_keys.len() == to_keys.len() => Ok(from_keys.into_iter().zip(to_keys).collect()),
+        _ => Err(format!(
+            "{} covers {} key(s) but {} covers {}",
+            from.trim(),
+            from_keys.len(),
+            to.trim(),
+            to_keys.len()
+        )),
+    }
+}
+
+// An incoming mapping: just the local key, or the local key with timing.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawIncomingMapping {
+    Key(RawKey),
+    Detailed {
+        key: RawKey,
+        #[serde(default)]
+        delay_ms: Option<RawDurationRange>,
+        #[serde(default)]
+        hold_ms: Option<RawDurationRange>,
+    },
+}
+
+// A set of mappings and the settings that go with them: the top level of the
+// file, or one of its named profiles.
+#[derive(Deserialize, Default)]
+struct RawProfile {
     #[serde(default)]
-    incoming: HashMap<String, String>,
+    incoming: HashMap<RawKey, RawIncomingMapping>,
     #[serde(default)]
-    outgoing: HashMap<String, String>,
+    outgoing: HashMap<RawKey, RawKey>,
     #[serde(default)]
     devices: Option<Vec<String>>,
+    #[serde(default)]
+    acknowledge: Option<Vec<RawKey>>,
+    #[serde(default)]
+    schedule: HashMap<RawKey, u64>,
+    #[serde(default)]
+    humanize_seed: Option<u64>,
+    #[serde(default)]
+    deny: Option<Vec<RawKey>>,
+    #[serde(default)]
+    profile_hotkey: Option<Vec<RawKey>>,
 }
 
-impl<'de> Deserialize<'de> for KeySyncConfig {
-    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
-    where
-        D: Deserializer<'de>,
-    {
-        let raw = RawKeySyncConfig::deserialize(deserializer)?;
-
-        let parse_key_code_map =
-            |map: HashMap<String, String>, which: &str| -> Result<KeyCodeMap, D::Error> {
-                let mut result = HashMap::new();
-                for (k, v) in map {
-                    let key_code = KeyCode::from_str(&k);
-                    let val_code = KeyCode::from_str(&v);
-                    match (key_code, val_code) {
-                        (Ok(kc), Ok(vc)) => {
-                            result.insert(kc, vc);
-                        }
-                        (r1, r2) => {
-                            return Err(serde::de::Error::custom(format!(
-                                "invalid {} key mapping: {} -> {} ({:?} -> {:?})",
-                                which, k, v, r1, r2
-                            )));
-                        }
+// Helper struct for raw deserialization (string keys/values)
+#[derive(Deserialize)]
+struct RawKeySyncConfig {
+    #[serde(flatten)]
+    base: RawProfile,
+    #[serde(default)]
+    profiles: HashMap<String, RawProfile>,
+}
+
+impl RawKeySyncConfig {
+    /// Takes out the named profile, to apply on top of the base settings.
+    fn select(&mut self, profile: Option<&str>) -> anyhow::Result<Option<RawProfile>> {
+        let Some(name) = profile else {
+            return Ok(None);
+        };
+        match self.profiles.remove(name) {
+            Some(profile) => Ok(Some(profile)),
+            None => {
+                let mut names: Vec<&str> = self.profiles.keys().map(String::as_str).collect();
+                names.sort();
+                let available = if names.is_empty() {
+                    "none".to_string()
+                } else {
+                    names.join(", ")
+                };
+                Err(anyhow::anyhow!(
+                    "No profile named '{}' in the config (profiles: {})",
+                    name,
+                    available
+                ))
+            }
+        }
+    }
+}
+
+impl KeySyncConfig {
+    /// A config with no mappings, denying just the built-in dangerous keys.
+    fn empty() -> Self {
+        KeySyncConfig {
+            incoming: HashMap::new(),
+            outgoing: HashMap::new(),
+            devices: None,
+            acknowledge: HashSet::new(),
+            schedule: HashMap::new(),
+            humanize: HashMap::new(),
+            humanize_seed: None,
+            key_filter: KeyFilter {
+                allow: Vec::new(),
+                deny: DANGEROUS_KEYS
+                    .iter()
+                    .map(|k| KeyRange::from_str(k).unwrap())
+                    .collect(),
+            },
+            profile_hotkey: Vec::new(),
+            profiles: Vec::new(),
+        }
+    }
+
+    /// Applies `raw` on top of this config: its mappings are added key by
+    /// key (replacing those for the same key, however they were written) and
+    /// anything else it sets replaces the current setting.
+    fn apply(&mut self, raw: RawProfile) -> anyhow::Result<()> {
+        let parse_key_code_map = |map: Vec<(&RawKey, &RawKey)>, which: &str| {
+            let mut result = KeyCodeMap::new();
+            let mut written: HashMap<KeyCode, &RawKey> = HashMap::new();
+            for (k, v) in map {
+                let pairs = parse_mapping(k, v).map_err(|e| {
+                    anyhow::anyhow!("invalid {} key mapping: {} -> {} ({})", which, k, v, e)
+                })?;
+                for (kc, vc) in pairs {
+                    // Two entries for one key, like `a` and `KEY_A`: which
+                    // one wins would be down to chance.
+                    if let Some(other) = written.insert(kc, k) {
+                        let mut names = [&**other, &**k];
+                        names.sort();
+                        return Err(anyhow::anyhow!(
+                            "{} {:?} is mapped by both {} and {}",
+                            which,
+                            kc,
+                            names[0],
+                            names[1]
+                        ));
                     }
+                    result.insert(kc, vc);
                 }
-                Ok(result)
+            }
+            Ok::<_, anyhow::Error>(result)
+        };
+
+        let parse_keys = |k: &RawKey, which: &str| -> anyhow::Result<Vec<KeyCode>> {
+            let range = KeyRange::from_str(k)
+                .map_err(|e| anyhow::anyhow!("invalid {} key: {}", which, e))?;
+            Ok(range.keys().collect())
+        };
+
+        if let Some(acknowledge) = raw.acknowledge {
+            let mut keys = HashSet::new();
+            for k in &acknowledge {
+                keys.extend(parse_keys(k, "acknowledge")?);
+            }
+            self.acknowledge = keys;
+        }
+
+        for (k, delay_ms) in &raw.schedule {
+            for key in parse_keys(k, "schedule")? {
+                self.schedule.insert(key, Duration::from_millis(*delay_ms));
+            }
+        }
+
+        if let Some(deny) = raw.deny {
+            let mut ranges = Self::empty().key_filter.deny;
+            for k in &deny {
+                ranges.push(
+                    KeyRange::from_str(k)
+                        .map_err(|e| anyhow::anyhow!("invalid deny key: {}", e))?,
+                );
+            }
+            self.key_filter.deny = ranges;
+        }
+
+        if let Some(hotkey) = raw.profile_hotkey {
+            self.profile_hotkey = hotkey
+                .iter()
+                .map(|k| {
+                    key_filter::parse_key(k)
+                        .map_err(|e| anyhow::anyhow!("invalid profile_hotkey key: {}", e))
+                })
+                .collect::<anyhow::Result<_>>()?;
+        }
+
+        let mut incoming = Vec::new();
+        let mut humanize = HashMap::new();
+        for (k, mapping) in &raw.incoming {
+            let (v, delay_ms, hold_ms) = match mapping {
+                RawIncomingMapping::Key(v) => (v, &None, &None),
+                RawIncomingMapping::Detailed {
+                    key,
+                    delay_ms,
+                    hold_ms,
+                } => (key, delay_ms, hold_ms),
+            };
+            let parse_range = |range: &Option<RawDurationRange>, which: &str| {
+                range
+                    .as_ref()
+                    .map(|r| r.parse())
+                    .transpose()
+                    .map_err(|e| anyhow::anyhow!("invalid {} for incoming {}: {}", which, k, e))
             };
+            let settings = Humanize {
+                delay: parse_range(delay_ms, "delay_ms")?,
+                hold: parse_range(hold_ms, "hold_ms")?,
+            };
+            if settings.delay.is_some() || settings.hold.is_some() {
+                for key in parse_keys(k, "incoming")? {
+                    humanize.insert(key, settings);
+                }
+            }
+            incoming.push((k, v));
+        }
+        let incoming = parse_key_code_map(incoming, "incoming")?;
+        // Timing belongs to the mapping it was written with.
+        self.humanize.retain(|key, _| !incoming.contains_key(key));
+        self.humanize.extend(humanize);
+        self.incoming.extend(incoming);
 
-        Ok(KeySyncConfig {
-            incoming: parse_key_code_map(raw.incoming, "incoming")?,
-            outgoing: parse_key_code_map(raw.outgoing, "outgoing")?,
-            devices: raw.devices,
-        })
+        let outgoing = parse_key_code_map(raw.outgoing.iter().collect(), "outgoing")?;
+        self.outgoing.extend(outgoing);
+
+        if raw.devices.is_some() {
+            self.devices = raw.devices;
+        }
+        if raw.humanize_seed.is_some() {
+            self.humanize_seed = raw.humanize_seed;
+        }
+        Ok(())
+    }
+}
+
+/// Settings a profile, or the top level, can have.
+const PROFILE_SETTINGS: [&str; 8] = [
+    "incoming",
+    "outgoing",
+    "devices",
+    "acknowledge",
+    "schedule",
+    "humanize_seed",
+    "deny",
+    "profile_hotkey",
+];
+
+/// Settings only the top level can have: `profiles` and the client options.
+const TOP_LEVEL_SETTINGS: [&str; 21] = [
+    "profiles",
+    "server_address",
+    "client_id",
+    "tags",
+    "channel",
+    "secret",
+    "connect_timeout_ms",
+    "max_reconnect_attempts",
+    "max_reconnect_backoff_ms",
+    "offline_queue_size",
+    "offline_ttl_ms",
+    "offline_policy",
+    "ack_timeout_ms",
+    "ack_retransmits",
+    "max_event_age_ms",
+    "batch_window_ms",
+    "compression",
+    "codec",
+    "metrics_address",
+    "profile",
+    "control_socket",
+];
+
+/// A problem with one setting in a config file.
+#[derive(Debug)]
+pub struct Finding {
+    /// Whether the file can't be used as it is, rather than just looking
+    /// like a mistake.
+    pub error: bool,
+    /// Where the setting is, like `profiles.game.incoming`.
+    pub section: String,
+    /// The text at fault, to point at in the file.
+    pub token: String,
+    pub message: String,
+}
+
+fn suggest(word: &str, candidates: &[String]) -> String {
+    match crate::utils::closest(word, candidates.iter().map(String::as_str)) {
+        Some(candidate) => format!(" (did you mean {}?)", candidate),
+        None => String::new(),
     }
 }
 
+/// Collects the findings for one profile.
+struct Lint<'a> {
+    /// Prefix for the fields of this profile, like `profiles.game.`.
+    section: String,
+    key_names: &'a [String],
+    findings: &'a mut Vec<Finding>,
+}
+
+impl Lint<'_> {
+    fn error(&mut self, field: &str, token: &str, message: String) {
+        self.findings.push(Finding {
+            error: true,
+            section: format!("{}{}", self.section, field),
+            token: token.to_string(),
+            message,
+        });
+    }
+
+    /// The closest key name to a misspelled one, which may have been
+    /// written without `KEY_`.
+    fn suggest_key(&self, name: &str) -> String {
+        match suggest(name, self.key_names) {
+            hint if hint.is_empty() => suggest(&format!("KEY_{}", name), self.key_names),
+            hint => hint,
+        }
+    }
+
+    fn key(&mut self, field: &str, name: &str) {
+        if let Err(e) = key_filter::parse_key(name) {
+            let message = format!("{}{}", e, self.suggest_key(name));
+            self.error(field, name, message);
+        }
+    }
+
+    /// Checks a key or key range, returning the keys it covers.
+    fn keys(&mut self, field: &str, entry: &str) -> Option<Vec<KeyCode>> {
+        match KeyRange::from_str(entry) {
+            Ok(range) => Some(range.keys().collect()),
+            Err(e) => {
+                let hint = entry
+                    .split("..")
+                    .map(str::trim)
+                    .find(|name| key_filter::parse_key(name).is_err())
+                    .map_or(String::new(), |name| self.suggest_key(name));
+                self.error(field, entry, format!("{}{}", e, hint));
+                None
+            }
+        }
+    }
+
+    /// Checks the mappings of one section, including that no key is mapped
+    /// by two entries.
+    fn mappings<'k>(
+        &mut self,
+        field: &str,
+        mappings: impl IntoIterator<Item = (&'k str, &'k str)>,
+    ) {
+        let mut mappings: Vec<_> = mappings.into_iter().collect();
+        mappings.sort();
+        let mut written: HashMap<KeyCode, &str> = HashMap::new();
+        for (from, to) in mappings {
+            let (Some(from_keys), Some(_)) = (self.keys(field, from), self.keys(field, to)) else {
+                continue;
+            };
+            if let Err(e) = parse_mapping(from, to) {
+                self.error(field, from, e);
+                continue;
+            }
+            for key in from_keys {
+                if let Some(other) = written.insert(key, from) {
+                    let message = format!("{:?} is mapped by both {} and {}", key, other, from);
+                    self.error(field, from, message);
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Reports every key name and value in `profile` that can't be used.
+    fn profile(&mut self, profile: &RawProfile) {
+        let mut incoming = Vec::new();
+        for (remote, mapping) in &profile.incoming {
+            match mapping {
+                RawIncomingMapping::Key(local) => incoming.push((&**remote, &**local)),
+                RawIncomingMapping::Detailed {
+                    key: local,
+                    delay_ms,
+                    hold_ms,
+                } => {
+                    incoming.push((remote, local));
+                    for (which, range) in [("delay_ms", delay_ms), ("hold_ms", hold_ms)] {
+                        if let Some(Err(e)) = range.as_ref().map(RawDurationRange::parse) {
+                            let message = format!("invalid {} for {}: {}", which, remote, e);
+                            self.error("incoming", remote, message);
+                        }
+                    }
+                }
+            }
+        }
+        self.mappings("incoming", incoming);
+        let outgoing = profile.outgoing.iter().map(|(k, v)| (&**k, &**v));
+        self.mappings("outgoing", outgoing);
+        for entry in profile.acknowledge.iter().flatten() {
+            self.keys("acknowledge", entry);
+        }
+        for entry in profile.schedule.keys() {
+            self.keys("schedule", entry);
+        }
+        for name in profile.profile_hotkey.iter().flatten() {
+            self.key("profile_hotkey", name);
+        }
+        for entry in profile.deny.iter().flatten() {
+            self.keys("deny", entry);
+        }
+    }
+}
+
+/// Reports the problems in the config file `text` that can be pinned on a
+/// single setting. A file that isn't valid YAML, or has a setting of the
+/// wrong type, is an error.
+pub fn lint(text: &str) -> Result<Vec<Finding>, serde_norway::Error> {
+    let value: serde_norway::Value = serde_norway::from_str(text)?;
+    let key_names = crate::key_filter::key_names();
+    let mut findings = unknown_settings(&value);
+
+    if value.is_null() {
+        return Ok(findings);
+    }
+    ClientSettings::deserialize(value.clone())?;
+    let raw = RawKeySyncConfig::deserialize(value)?;
+    let mut profiles: Vec<(String, &RawProfile)> = raw
+        .profiles
+        .iter()
+        .map(|(name, profile)| (format!("profiles.{}.", name), profile))
+        .collect();
+    profiles.sort_by(|a, b| a.0.cmp(&b.0));
+    for (section, profile) in [(String::new(), &raw.base)].into_iter().chain(profiles) {
+        Lint {
+            section,
+            key_names: &key_names,
+            findings: &mut findings,
+        }
+        .profile(profile);
+    }
+    Ok(findings)
+}
+
+/// Settings at the top level or in a profile that the client doesn't know,
+/// and would otherwise ignore without a word.
+fn unknown_settings(value: &serde_norway::Value) -> Vec<Finding> {
+    let mut findings = Vec::new();
+    let mut unknown = |section: &str, name: &str, known: &[&str]| {
+        let known: Vec<String> = known.iter().map(|s| s.to_string()).collect();
+        findings.push(Finding {
+            error: false,
+            section: section.to_string(),
+            token: name.to_string(),
+            message: format!("unknown setting {}{}", name, suggest(name, &known)),
+        });
+    };
+    if let Some(top) = value.as_mapping() {
+        let known: Vec<&str> = PROFILE_SETTINGS
+            .iter()
+            .chain(TOP_LEVEL_SETTINGS.iter())
+            .copied()
+            .collect();
+        for name in top.keys().filter_map(|key| key.as_str()) {
+            if !known.contains(&name) {
+                unknown("", name, &known);
+            }
+        }
+        if let Some(profiles) = top.get("profiles").and_then(|p| p.as_mapping()) {
+            for (profile, body) in profiles {
+                let (Some(profile), Some(body)) = (profile.as_str(), body.as_mapping()) else {
+                    continue;
+                };
+                for name in body.keys().filter_map(|key| key.as_str()) {
+                    if !PROFILE_SETTINGS.contains(&name) {
+                        unknown(&format!("profiles.{}", profile), name, &PROFILE_SETTINGS);
+                    }
+                }
+            }
+        }
+    }
+    findings
+}
+
 impl KeySyncConfig {
-    pub fn file_name() -> &'static str {
-        "config.yaml"
+    /// The per-user config file, `$XDG_CONFIG_HOME/keysync/config.yaml`,
+    /// where `XDG_CONFIG_HOME` defaults to `~/.config`.
+    pub fn user_path() -> Option<PathBuf> {
+        let non_empty = |var| env::var_os(var).filter(|value| !value.is_empty());
+        let config_home = non_empty("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| non_empty("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+        Some(config_home.join("keysync").join("config.yaml"))
     }
-    pub fn from_reader<R: Read>(reader: R) -> anyhow::Result<Self> {
-        let config: KeySyncConfig = serde_norway::from_reader(reader)?;
+
+    /// Picks the config file: `explicit` (from `--config`), then
+    /// `$KEYSYNC_CONFIG`, then the first of the user and system config files
+    /// that exists. Returns `None` if there is none.
+    pub fn locate(explicit: Option<&Path>) -> Option<PathBuf> {
+        if let Some(path) = explicit {
+            return Some(path.to_path_buf());
+        }
+        if let Some(path) = env::var_os(CONFIG_ENV).filter(|value| !value.is_empty()) {
+            return Some(PathBuf::from(path));
+        }
+        let found = Self::user_path()
+            .into_iter()
+            .chain([PathBuf::from(SYSTEM_CONFIG)])
+            .find(|path| path.is_file());
+        if found.is_some() {
+            return found;
+        }
+        let legacy = Path::new(LEGACY_CONFIG);
+        if legacy.is_file() {
+            tracing::warn!(
+                "Using config.yaml from the current directory; move it to {} or pass --config",
+                Self::user_path().map_or("~/.config/keysync/config.yaml".into(), |path| path
+                    .display()
+                    .to_string())
+            );
+            return Some(legacy.to_path_buf());
+        }
+        None
+    }
+
+    /// Reads a config file, applying the named profile on top of its base
+    /// settings if one is given.
+    pub fn from_reader<R: Read>(reader: R, profile: Option<&str>) -> anyhow::Result<Self> {
+        let mut raw: RawKeySyncConfig = serde_norway::from_reader(reader)?;
+        let mut profiles: Vec<String> = raw.profiles.keys().cloned().collect();
+        profiles.sort();
+        let selected = raw.select(profile)?;
+        let mut config = Self::empty();
+        config.apply(raw.base)?;
+        if let (Some(name), Some(selected)) = (profile, selected) {
+            config
+                .apply(selected)
+                .with_context(|| format!("In profile {}", name))?;
+        }
+        config.profiles = profiles;
         Ok(config)
     }
 
@@ -73,6 +796,20 @@ impl KeySyncConfig {
     pub fn default_config_string() -> &'static str {
         r#"
 # KeySync config.
+# Any `keysync client` option can be set here under its long name, with
+# underscores. Options on the command line or in KEYSYNC_* environment
+# variables take precedence.
+# server_address: 192.168.1.2:1234
+# Or several, tried in order until one answers:
+# server_address: [192.168.1.2:1234, backup.example.com:1234]
+# client_id: living-room
+# tags: [kiosk]
+# channel: game
+# secret: s3cret
+# connect_timeout_ms: 2000
+# codec: json
+# compression: deflate
+
 # devices: (optional) List of keyboard devices to monitor.
 #   Each entry can be a device path (starting with /) or a regex for the device name.
 #   If omitted (null), all detected keyboards will be monitored.
@@ -82,6 +819,12 @@ impl KeySyncConfig {
 #   - MyKeyboard        # A substring match (regex)
 #   - '^My Keyboard$'   # An anchored regex
 
+# Keys are named as in linux/input-event-codes.h, in any case and with or
+# without KEY_ (KEY_ESC, esc), by alias (ctrl, shift, alt, meta) or by code
+# (30, 0x1e). Note a bare 1 is code 1 (KEY_ESC); the digit key is KEY_1.
+# A range like KEY_1..KEY_9 maps key by key, in code order, onto a range of
+# the same length, or all onto a single key.
+
 # incoming: Maps key presses received FROM the server to your local machine.
 #   Format: "REMOTE_KEY_NAME": "LOCAL_KEY_NAME"
 incoming:
@@ -93,6 +836,19 @@ incoming:
   # If the server sends KEY_F1, your local machine will interpret it as KEY_F2.
   # "KEY_F1": "KEY_F2"
 
+  # Example 3: Map the number row to the function keys.
+  # KEY_1..KEY_9: KEY_F1..KEY_F9
+
+  # Example 4: Humanize the timing. Wait a random 10-40ms before pressing,
+  # then hold the key down for 30-80ms. A single number is a fixed duration.
+  # "KEY_SPACE":
+  #   key: "KEY_SPACE"
+  #   delay_ms: [10, 40]
+  #   hold_ms: [30, 80]
+
+# humanize_seed: (optional) Makes the random timing above reproducible.
+# humanize_seed: 42
+
 # outgoing: Maps key presses FROM your local machine to be sent TO the server.
 #   Format: "LOCAL_KEY_NAME": "KEY_TO_SEND"
 outgoing:
@@ -103,7 +859,133 @@ outgoing:
   # Example 2: Send KEY_X as is.
   # If you press X on your keyboard, KEY_X will be sent to the server.
   # "KEY_X": "KEY_X"
+
+# acknowledge: (optional) Outgoing keys (as sent to the server) that every
+#   receiver must acknowledge. Unacknowledged keys are retransmitted, and
+#   receivers that never acknowledge them are logged.
+# acknowledge:
+#   - KEY_ESC
+
+# schedule: (optional) Outgoing keys (as sent to the server) that all receivers
+#   should press at the same moment, the given number of milliseconds after
+#   the key was pressed here. Clocks are synchronized through the server.
+# schedule:
+#   KEY_1: 50
+
+# deny: (optional) Incoming keys (after mapping) that are never pressed, in
+#   addition to the built-in KEY_POWER, KEY_POWER2, KEY_SLEEP, KEY_SUSPEND,
+#   KEY_WAKEUP and KEY_SYSRQ. Entries can be a key name, a key code or a range.
+# deny:
+#   - KEY_DELETE
+#   - KEY_F1..KEY_F12
+
+# profiles: (optional) Named variations on the settings above, picked with
+#   `keysync client --profile NAME`. A profile's incoming, outgoing and
+#   schedule entries are added to the ones above, replacing those for the same
+#   key; anything else it sets replaces the setting above.
+# profiles:
+#   game:
+#     outgoing:
+#       "KEY_CAPSLOCK": "KEY_ESC"
+#     devices:
+#       - Gamepad
+
+# profile_hotkey: (optional) Local keys that, held together, switch to the next
+#   profile while the client runs. The last key pressed is not sent.
+# profile_hotkey: [KEY_LEFTCTRL, KEY_LEFTALT, KEY_P]
 "#
         .trim_start()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load(yaml: &str, profile: Option<&str>) -> anyhow::Result<KeySyncConfig> {
+        KeySyncConfig::from_reader(yaml.as_bytes(), profile)
+    }
+
+    #[test]
+    fn maps_ranges_key_by_key() {
+        // By code: a..d is a, s, d, like the keyboard row.
+        let pairs = parse_mapping("a..d", "KEY_1..KEY_3").unwrap();
+        assert_eq!(
+            pairs,
+            [
+                (KeyCode::KEY_A, KeyCode::KEY_1),
+                (KeyCode::KEY_S, KeyCode::KEY_2),
+                (KeyCode::KEY_D, KeyCode::KEY_3),
+            ]
+        );
+    }
+
+    #[test]
+    fn maps_a_range_onto_one_key() {
+        let pairs = parse_mapping("f1..f3", "esc").unwrap();
+        assert_eq!(
+            pairs,
+            [
+                (KeyCode::KEY_F1, KeyCode::KEY_ESC),
+                (KeyCode::KEY_F2, KeyCode::KEY_ESC),
+                (KeyCode::KEY_F3, KeyCode::KEY_ESC),
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_ranges_of_different_lengths() {
+        assert_eq!(
+            parse_mapping("f1..f3", " f5..f6 "),
+            Err("f1..f3 covers 3 key(s) but f5..f6 covers 2".to_string())
+        );
+        assert!(parse_mapping("f1", "f5..f6").is_err());
+    }
+
+    #[test]
+    fn rejects_a_key_mapped_twice() {
+        let error = load("incoming:\n  a: b\n  KEY_A: c\n", None).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "incoming KEY_A is mapped by both KEY_A and a"
+        );
+        let error = load("outgoing:\n  f1..f3: esc\n  '0x3c': tab\n", None).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "outgoing KEY_F2 is mapped by both 0x3c and f1..f3"
+        );
+    }
+
+    #[test]
+    fn a_profile_overrides_one_key_of_a_base_range() {
+        let yaml = "\
+incoming:
+  f1..f3: KEY_1..KEY_3
+profiles:
+  game:
+    incoming:
+      KEY_F2: esc
+";
+        let base = load(yaml, None).unwrap();
+        assert_eq!(base.incoming[&KeyCode::KEY_F2], KeyCode::KEY_2);
+
+        let game = load(yaml, Some("game")).unwrap();
+        assert_eq!(game.incoming.len(), 3);
+        assert_eq!(game.incoming[&KeyCode::KEY_F1], KeyCode::KEY_1);
+        assert_eq!(game.incoming[&KeyCode::KEY_F2], KeyCode::KEY_ESC);
+        assert_eq!(game.incoming[&KeyCode::KEY_F3], KeyCode::KEY_3);
+        assert_eq!(game.profiles, ["game"]);
+    }
+
+    #[test]
+    fn takes_one_server_address_or_several() {
+        let one: ClientSettings = serde_norway::from_str("server_address: a:1234\n").unwrap();
+        assert_eq!(one.server_address.unwrap(), ["a:1234"]);
+        let many: ClientSettings =
+            serde_norway::from_str("server_address: [a:1234, b:1234]\nchannel: game\n").unwrap();
+        assert_eq!(many.server_address.unwrap(), ["a:1234", "b:1234"]);
+        assert_eq!(many.channel.as_deref(), Some("game"));
+        let none: ClientSettings = serde_norway::from_str("client_id: x\n").unwrap();
+        assert!(none.server_address.is_none());
+    }
+}
@@ -0,0 +1,395 @@
+use anyhow::{Context, Result};
+use evdev::{Device, KeyCode};
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use crate::config::{self, KeySyncConfig};
+use crate::key_filter::KeyRange;
+use crate::keyboard::KeyboardMonitor;
+
+/// Prints every problem with the client config file at `path`, each with
+/// the line it is on, and returns whether the client can use the file.
+pub fn check(path: &Path) -> Result<bool> {
+    let text = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read config file {}", path.display()))?;
+    let mut report = Report {
+        path,
+        text: &text,
+        errors: 0,
+        warnings: 0,
+    };
+
+    match config::lint(&text) {
+        Ok(findings) => {
+            for finding in findings {
+                let message = if finding.section.is_empty() {
+                    finding.message
+                } else {
+                    format!("{}: {}", finding.section, finding.message)
+                };
+                let at = (&[finding.section][..], Token::Text(&finding.token));
+                report.print(finding.error, Some(at), &message);
+            }
+        }
+        Err(e) => {
+            let line = e.location().map(|location| location.line());
+            report.print_at(true, line, &e.to_string());
+        }
+    }
+
+    if report.errors == 0 {
+        check_profiles(&mut report)?;
+    }
+
+    if report.errors + report.warnings == 0 {
+        println!("{}: OK", path.display());
+    } else {
+        println!(
+            "{}: {} error(s), {} warning(s)",
+            path.display(),
+            report.errors,
+            report.warnings
+        );
+    }
+    Ok(report.errors == 0)
+}
+
+struct Report<'a> {
+    path: &'a Path,
+    text: &'a str,
+    errors: usize,
+    warnings: usize,
+}
+
+/// What a problem points at in the file.
+enum Token<'a> {
+    /// This text, as a whole word.
+    Text(&'a str),
+    /// An entry naming this key, however it is spelled.
+    Key(KeyCode),
+}
+
+impl Report<'_> {
+    /// Prints a problem, pointing at the line `token` first appears on in
+    /// the first of `sections` (like `profiles.game.incoming`) that has it.
+    fn print(&mut self, error: bool, at: Option<(&[String], Token)>, message: &str) {
+        let line = at.and_then(|(sections, token)| {
+            sections
+                .iter()
+                .find_map(|section| line_of(self.text, section, &token))
+        });
+        self.print_at(error, line, message);
+    }
+
+    fn print_at(&mut self, error: bool, line: Option<usize>, message: &str) {
+        let severity = if error {
+            self.errors += 1;
+            "error"
+        } else {
+            self.warnings += 1;
+            "warning"
+        };
+        match line {
+            Some(line) => println!(
+                "{}:{}: {}: {}",
+                self.path.display(),
+                line,
+                severity,
+                message
+            ),
+            None => println!("{}: {}: {}", self.path.display(), severity, message),
+        }
+    }
+}
+
+/// Checks the base settings and every profile as the client would load them.
+/// Problems shared by several profiles are only reported once.
+fn check_profiles(report: &mut Report) -> Result<()> {
+    let base = KeySyncConfig::from_reader(report.text.as_bytes(), None)?;
+    let profiles: Vec<Option<String>> = [None]
+        .into_iter()
+        .chain(base.profiles.iter().cloned().map(Some))
+        .collect();
+
+    let mut reported = HashSet::new();
+    // Listed when first needed; `Some(None)` if they can't be.
+    let mut keyboards: Option<Option<Vec<(PathBuf, Device)>>> = None;
+    for profile in profiles {
+        let config = KeySyncConfig::from_reader(report.text.as_bytes(), profile.as_deref())?;
+        let prefix = profile
+            .as_ref()
+            .map_or(String::new(), |name| format!("profile {}: ", name));
+        // A profile's settings come from the profile, then from the base.
+        let sections = |field: &str| {
+            let in_profile = profile
+                .as_ref()
+                .map_or(String::new(), |name| format!("profiles.{}.{}", name, field));
+            [in_profile, field.to_string()]
+        };
+
+        for (field, key, message) in mapping_warnings(&config) {
+            if reported.insert((format!("{:?}", key), message.clone())) {
+                let at = (&sections(field)[..], Token::Key(key));
+                report.print(false, Some(at), &format!("{}{}", prefix, message));
+            }
+        }
+
+        for selector in config.devices.iter().flatten() {
+            if !reported.insert((selector.clone(), String::new())) {
+                continue;
+            }
+            let keyboards = keyboards.get_or_insert_with(|| {
+                KeyboardMonitor::find_keyboards(None)
+                    .map_err(|e| {
+                        let message = format!("could not list keyboards to check devices: {:#}", e);
+                        report.print(false, None, &message);
+                    })
+                    .ok()
+            });
+            let Some(keyboards) = keyboards else {
+                continue;
+            };
+            if !KeyboardMonitor::selects_any(selector, keyboards)? {
+                let message = format!(
+                    "{}device selector '{}' matches no keyboard",
+                    prefix, selector
+                );
+                let at = (&sections("devices")[..], Token::Text(selector));
+                report.print(false, Some(at), &message);
+            }
+        }
+
+        let virtual_keys: BTreeSet<KeyCode> = config.incoming.values().copied().collect();
+        if !virtual_keys.is_empty()
+            && reported.insert((format!("{:?}", virtual_keys), String::new()))
+        {
+            println!(
+                "{}: note: {}the virtual keyboard will register {}",
+                report.path.display(),
+                prefix,
+                key_list(&virtual_keys)
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Mappings that are valid but likely mistakes, each with the setting and
+/// key to point at in the file.
+fn mapping_warnings(config: &KeySyncConfig) -> Vec<(&'static str, KeyCode, String)> {
+    let mut warnings = Vec::new();
+
+    let mut pressed_by: BTreeMap<KeyCode, BTreeSet<KeyCode>> = BTreeMap::new();
+    for (remote, local) in &config.incoming {
+        pressed_by.entry(*local).or_default().insert(*remote);
+    }
+    for (local, remotes) in pressed_by.iter().filter(|(_, remotes)| remotes.len() > 1) {
+        warnings.push((
+            "incoming",
+            *remotes.first().unwrap(),
+            format!("incoming {} all press {:?}", key_list(remotes), local),
+        ));
+    }
+
+    let mut sent_for: BTreeMap<KeyCode, BTreeSet<KeyCode>> = BTreeMap::new();
+    for (local, remote) in &config.outgoing {
+        sent_for.entry(*remote).or_default().insert(*local);
+    }
+    for (remote, locals) in sent_for.iter().filter(|(_, locals)| locals.len() > 1) {
+        warnings.push((
+            "outgoing",
+            *locals.first().unwrap(),
+            format!("outgoing {} are all sent as {:?}", key_list(locals), remote),
+        ));
+    }
+
+    let acknowledged = config.acknowledge.iter().map(|key| (key, "acknowledge"));
+    let scheduled = config.schedule.keys().map(|key| (key, "schedule"));
+    let mut unsent: Vec<_> = acknowledged
+        .chain(scheduled)
+        .filter(|(key, _)| !sent_for.contains_key(key))
+        .collect();
+    unsent.sort();
+    for (key, field) in unsent {
+        warnings.push((
+            field,
+            *key,
+            format!(
+                "{:?} is listed under {}, but no outgoing mapping sends it",
+                key, field
+            ),
+        ));
+    }
+
+    let mut denied: Vec<_> = config
+        .incoming
+        .iter()
+        .filter(|(_, local)| !config.key_filter.allows(local.code()))
+        .collect();
+    denied.sort();
+    for (remote, local) in denied {
+        warnings.push((
+            "incoming",
+            *remote,
+            format!(
+                "incoming {:?} maps to {:?}, which is denied and will never be pressed",
+                remote, local
+            ),
+        ));
+    }
+
+    warnings
+}
+
+fn key_list<'a>(keys: impl IntoIterator<Item = &'a KeyCode>) -> String {
+    keys.into_iter()
+        .map(|key| format!("{:?}", key))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// The number of the first line in `section` of the file that `token`
+/// appears on, outside comments. The base settings don't include the
+/// profiles.
+fn line_of(text: &str, section: &str, token: &Token) -> Option<usize> {
+    let lines: Vec<(usize, &str)> = text
+        .lines()
+        .enumerate()
+        .map(|(i, line)| (i + 1, strip_comment(line)))
+        .collect();
+    let path: Vec<&str> = section.split('.').filter(|s| !s.is_empty()).collect();
+    let mut scope = lines.clone();
+    if path.first() != Some(&"profiles") {
+        let profiles: Vec<usize> = block(&lines, "profiles")
+            .map(|block| block.iter().map(|(number, _)| *number).collect())
+            .unwrap_or_default();
+        scope.retain(|(number, _)| !profiles.contains(number));
+    }
+    for key in path {
+        scope = block(&scope, key)?;
+    }
+    scope
+        .iter()
+        .find(|(_, line)| matches(line, token))
+        .map(|(number, _)| *number)
+}
+
+/// The lines of the setting `key` in `lines`, which hold the settings of one
+/// section: the value after the key, then every line indented further.
+fn block<'a>(lines: &[(usize, &'a str)], key: &str) -> Option<Vec<(usize, &'a str)>> {
+    let indent = |line: &str| line.len() - line.trim_start().len();
+    let mut lines = lines.iter().filter(|(_, line)| !line.trim().is_empty());
+    let level = indent(lines.clone().next()?.1);
+    let (number, value) = lines.by_ref().find_map(|(number, line)| {
+        let (name, value) = line.split_once(':')?;
+        let name = name.trim().trim_matches(|c| c == '"' || c == '\'');
+        (indent(line) == level && name == key).then_some((*number, value))
+    })?;
+    let mut block = vec![(number, value)];
+    block.extend(lines.take_while(|(_, line)| indent(line) > level));
+    Some(block)
+}
+
+fn matches(line: &str, token: &Token) -> bool {
+    match *token {
+        Token::Text(token) => {
+            let is_word = |c: char| c.is_alphanumeric() || c == '_';
+            line.match_indices(token).any(|(i, _)| {
+                !line[..i].ends_with(is_word) && !line[i + token.len()..].starts_with(is_word)
+            })
+        }
+        Token::Key(key) => entries(line)
+            .any(|entry| KeyRange::from_str(entry).is_ok_and(|range| range.contains(key.code()))),
+    }
+}
+
+/// The keys of the mappings and the items of the lists on a line, like `a`
+/// in `a: b`, `- a` and `[a, b]`.
+fn entries(line: &str) -> impl Iterator<Item = &str> {
+    let line = line.trim();
+    let flow = line
+        .strip_prefix(['[', '{'])
+        .and_then(|inner| inner.strip_suffix([']', '}']));
+    let entries = match flow {
+        Some(inner) => inner.split(',').collect(),
+        None => vec![line.strip_prefix("- ").unwrap_or(line)],
+    };
+    entries.into_iter().map(|entry| {
+        let key = entry.split_once(':').map_or(entry, |(key, _)| key);
+        key.trim().trim_matches(|c| c == '"' || c == '\'')
+    })
+}
+
+fn strip_comment(line: &str) -> &str {
+    let comment = line
+        .char_indices()
+        .find(|&(i, c)| c == '#' && (i == 0 || line[..i].ends_with(char::is_whitespace)));
+    match comment {
+        Some((i, _)) => &line[..i],
+        None => line,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = "\
+incoming:
+  esc: f1           # KEY_A is mentioned in this comment
+  0x1e: b
+outgoing: {ctrl: alt, 'q..e': x}
+acknowledge:
+  - a
+profiles:
+  game:
+    incoming:
+      escape: f2
+      30: c
+    acknowledge: [KEY_A]
+";
+
+    fn key(name: &str) -> Token<'static> {
+        Token::Key(crate::key_filter::parse_key(name).unwrap())
+    }
+
+    #[test]
+    fn finds_keys_however_they_are_spelled() {
+        assert_eq!(line_of(CONFIG, "incoming", &key("KEY_ESC")), Some(2));
+        assert_eq!(line_of(CONFIG, "incoming", &key("KEY_A")), Some(3));
+        assert_eq!(line_of(CONFIG, "outgoing", &key("KEY_LEFTCTRL")), Some(4));
+        assert_eq!(line_of(CONFIG, "outgoing", &key("KEY_W")), Some(4));
+        assert_eq!(line_of(CONFIG, "acknowledge", &key("KEY_A")), Some(6));
+    }
+
+    #[test]
+    fn looks_only_in_the_section() {
+        // Not the value of a mapping, nor another setting.
+        assert_eq!(line_of(CONFIG, "incoming", &key("KEY_F1")), None);
+        assert_eq!(line_of(CONFIG, "outgoing", &key("KEY_ESC")), None);
+        assert_eq!(
+            line_of(CONFIG, "profiles.game.incoming", &key("KEY_ESC")),
+            Some(10)
+        );
+        assert_eq!(
+            line_of(CONFIG, "profiles.game.incoming", &key("KEY_A")),
+            Some(11)
+        );
+        assert_eq!(
+            line_of(CONFIG, "profiles.game.acknowledge", &key("KEY_A")),
+            Some(12)
+        );
+        assert_eq!(
+            line_of(CONFIG, "profiles.game.outgoing", &key("KEY_A")),
+            None
+        );
+        // The base settings don't include the profiles.
+        assert_eq!(line_of(CONFIG, "", &Token::Text("escape")), None);
+        assert_eq!(
+            line_of(CONFIG, "profiles.game", &Token::Text("escape")),
+            Some(10)
+        );
+    }
+}
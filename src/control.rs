@@ -0,0 +1,189 @@
+use anyhow::{Context, Result};
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::thread;
+
+/// A profile command for the running client, one per line on the control
+/// socket.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProfileRequest {
+    /// `profile`: show the active profile.
+    Active,
+    /// `profile next`: switch to the next profile, after the last one back to
+    /// the base settings.
+    Next,
+    /// `profile NAME`, or `profile base` for just the base settings.
+    Select(Option<String>),
+}
+
+impl FromStr for ProfileRequest {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        let words: Vec<&str> = s.split_whitespace().collect();
+        match words.as_slice() {
+            ["profile"] => Ok(ProfileRequest::Active),
+            ["profile", "next"] => Ok(ProfileRequest::Next),
+            ["profile", "base"] => Ok(ProfileRequest::Select(None)),
+            ["profile", name] => Ok(ProfileRequest::Select(Some(name.to_string()))),
+            _ => Err(format!(
+                "unknown command: {} (try: profile, profile next, profile NAME, profile base)",
+                s.trim()
+            )),
+        }
+    }
+}
+
+/// The listening control socket, removed when dropped.
+pub struct ControlSocket {
+    path: PathBuf,
+}
+
+impl Drop for ControlSocket {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.path) {
+            tracing::warn!(path = %self.path.display(), error = %e, "Failed to remove control socket");
+        }
+    }
+}
+
+/// Creates a Unix socket at `path` that only the current user can connect
+/// to, from the start: it is bound in a private directory, restricted, then
+/// moved into place.
+fn bind_private(path: &Path) -> Result<UnixListener> {
+    let dir = path.parent().unwrap_or(Path::new("."));
+    let name = path
+        .file_name()
+        .context("Control socket path has no file name")?;
+    let staging = dir.join(format!(
+        ".{}.{}",
+        name.to_string_lossy(),
+        std::process::id()
+    ));
+    fs::DirBuilder::new()
+        .mode(0o700)
+        .create(&staging)
+        .with_context(|| format!("Failed to create {}", staging.display()))?;
+    let staged = staging.join("socket");
+    let listener = UnixListener::bind(&staged)
+        .with_context(|| format!("Failed to listen on {}", path.display()))
+        .and_then(|listener| {
+            fs::set_permissions(&staged, fs::Permissions::from_mode(0o600))
+                .with_context(|| format!("Failed to restrict access to {}", path.display()))?;
+            fs::rename(&staged, path)
+                .with_context(|| format!("Failed to move socket to {}", path.display()))?;
+            Ok(listener)
+        });
+    let _ = fs::remove_file(&staged);
+    let _ = fs::remove_dir(&staging);
+    listener
+}
+
+/// Listens for requests on a Unix socket at `path`, which only the current
+/// user may connect to, answering each line with `ok ...` or `error ...`.
+/// The socket is removed when the returned guard is dropped.
+pub fn serve(
+    path: &Path,
+    handle: impl Fn(ProfileRequest) -> Result<String> + Send + Sync + 'static,
+) -> Result<ControlSocket> {
+    match fs::symlink_metadata(path) {
+        // Left behind by a client that didn't get to clean up.
+        Ok(meta) if meta.file_type().is_socket() => fs::remove_file(path)
+            .with_context(|| format!("Failed to remove stale socket {}", path.display()))?,
+        Ok(_) => anyhow::bail!("{} already exists and is not a socket", path.display()),
+        Err(_) => {}
+    }
+    let listener = bind_private(path)?;
+    tracing::info!(path = %path.display(), "Control socket listening");
+
+    let handle = Arc::new(handle);
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let handle = Arc::clone(&handle);
+                    thread::spawn(move || {
+                        if let Err(e) = answer(stream, &*handle) {
+                            tracing::debug!(error = %e, "Control connection failed");
+                        }
+                    });
+                }
+                Err(e) => tracing::warn!(error = %e, "Failed to accept control connection"),
+            }
+        }
+    });
+    Ok(ControlSocket {
+        path: path.to_path_buf(),
+    })
+}
+
+fn answer(stream: UnixStream, handle: &dyn Fn(ProfileRequest) -> Result<String>) -> Result<()> {
+    let mut writer = stream.try_clone()?;
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let reply = match line.parse::<ProfileRequest>() {
+            Ok(request) => match handle(request) {
+                Ok(message) => format!("ok {}", message),
+                Err(e) => format!("error {:#}", e),
+            },
+            Err(e) => format!("error {}", e),
+        };
+        writeln!(writer, "{}", reply)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_requests() {
+        assert_eq!("profile".parse(), Ok(ProfileRequest::Active));
+        assert_eq!(" profile  next ".parse(), Ok(ProfileRequest::Next));
+        assert_eq!("profile base".parse(), Ok(ProfileRequest::Select(None)));
+        assert_eq!(
+            "profile game".parse(),
+            Ok(ProfileRequest::Select(Some("game".to_string())))
+        );
+        assert!("profile a b".parse::<ProfileRequest>().is_err());
+        assert!("reload".parse::<ProfileRequest>().is_err());
+    }
+
+    #[test]
+    fn serves_a_private_socket_and_removes_it() {
+        let dir = std::env::temp_dir().join(format!("keysync-control-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("control.sock");
+
+        let socket = serve(&path, |request| Ok(format!("{:?}", request))).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        // Only the socket is left behind, not the staging directory.
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+        let stream = UnixStream::connect(&path).unwrap();
+        writeln!(&stream, "profile next\nbogus").unwrap();
+        let mut lines = BufReader::new(&stream).lines();
+        assert_eq!(lines.next().unwrap().unwrap(), "ok Next");
+        assert!(
+            lines
+                .next()
+                .unwrap()
+                .unwrap()
+                .starts_with("error unknown command")
+        );
+
+        drop(socket);
+        assert!(!path.exists());
+        fs::remove_dir(&dir).unwrap();
+    }
+}
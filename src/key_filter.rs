@@ -0,0 +1,356 @@
+use evdev::KeyCode;
+use std::collections::HashMap;
+use std::fmt;
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use crate::protocol::KEY_MAX;
+
+/// Keys that can power off, suspend or otherwise take over a machine.
+pub const DANGEROUS_KEYS: [&str; 6] = [
+    "KEY_POWER",
+    "KEY_POWER2",
+    "KEY_SLEEP",
+    "KEY_SUSPEND",
+    "KEY_WAKEUP",
+    "KEY_SYSRQ",
+];
+
+/// Chords that reboot or log out of a machine.
+pub const DANGEROUS_CHORDS: [&str; 3] = ["ctrl+alt+delete", "ctrl+alt+kpdot", "ctrl+alt+backspace"];
+
+/// Modifiers that are matched on either side of the keyboard.
+const MODIFIER_SIDES: [(KeyCode, KeyCode); 4] = [
+    (KeyCode::KEY_LEFTCTRL, KeyCode::KEY_RIGHTCTRL),
+    (KeyCode::KEY_LEFTALT, KeyCode::KEY_RIGHTALT),
+    (KeyCode::KEY_LEFTSHIFT, KeyCode::KEY_RIGHTSHIFT),
+    (KeyCode::KEY_LEFTMETA, KeyCode::KEY_RIGHTMETA),
+];
+
+/// Short names for common keys, on top of the kernel's names.
+const ALIASES: [(&str, &str); 16] = [
+    ("CTRL", "KEY_LEFTCTRL"),
+    ("CONTROL", "KEY_LEFTCTRL"),
+    ("SHIFT", "KEY_LEFTSHIFT"),
+    ("ALT", "KEY_LEFTALT"),
+    ("ALTGR", "KEY_RIGHTALT"),
+    ("META", "KEY_LEFTMETA"),
+    ("SUPER", "KEY_LEFTMETA"),
+    ("WIN", "KEY_LEFTMETA"),
+    ("ESCAPE", "KEY_ESC"),
+    ("RETURN", "KEY_ENTER"),
+    ("DEL", "KEY_DELETE"),
+    ("INS", "KEY_INSERT"),
+    ("PGUP", "KEY_PAGEUP"),
+    ("PGDN", "KEY_PAGEDOWN"),
+    ("BKSP", "KEY_BACKSPACE"),
+    ("CAPS", "KEY_CAPSLOCK"),
+];
+
+/// A key code or an inclusive range of them, written as a key name
+/// (`KEY_POWER`), a code (`116`, `0x74`) or `start..end` of either.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyRange(RangeInclusive<u16>);
+
+impl KeyRange {
+    pub fn contains(&self, key: u16) -> bool {
+        self.0.contains(&key)
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = KeyCode> + use<> {
+        self.0.clone().map(KeyCode::new)
+    }
+}
+
+/// The name of every key code the kernel defines, like `KEY_ESC`.
+pub fn key_names() -> Vec<String> {
+    (0..=KEY_MAX)
+        .map(|code| format!("{:?}", KeyCode::new(code)))
+        .filter(|name| !name.starts_with("unknown"))
+        .collect()
+}
+
+/// Parses a key written as a code (`30`, `0x1e`) or a name, ignoring case:
+/// the kernel's (`KEY_ESC`, `BTN_LEFT`), the same without `KEY_` (`esc`,
+/// `f1`) or an alias (`ctrl`).
+pub fn parse_key(s: &str) -> Result<KeyCode, String> {
+    let s = s.trim();
+    if s.contains("..") {
+        return Err(format!("expected a single key, not the range {}", s));
+    }
+    let upper = s.to_ascii_uppercase();
+    let code = if let Some(hex) = upper.strip_prefix("0X") {
+        u16::from_str_radix(hex, 16).ok()
+    } else if s.starts_with(|c: char| c.is_ascii_digit()) {
+        s.parse().ok()
+    } else {
+        let alias = ALIASES
+            .iter()
+            .find(|(alias, _)| *alias == upper)
+            .map(|(_, name)| *name);
+        [
+            Some(upper.clone()),
+            alias.map(str::to_string),
+            Some(format!("KEY_{}", upper)),
+        ]
+        .into_iter()
+        .flatten()
+        .find_map(|name| KeyCode::from_str(&name).ok())
+        .map(|key| key.code())
+    };
+    match code {
+        Some(code) if code <= KEY_MAX => Ok(KeyCode::new(code)),
+        Some(code) => Err(format!("key code {} is out of range", code)),
+        None => Err(format!("unknown key: {}", s)),
+    }
+}
+
+impl FromStr for KeyRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        let (start, end) = match s.split_once("..") {
+            Some((start, end)) => (parse_key(start)?.code(), parse_key(end)?.code()),
+            None => {
+                let key = parse_key(s)?.code();
+                (key, key)
+            }
+        };
+        if start > end {
+            return Err(format!("key range {} is empty", s));
+        }
+        Ok(KeyRange(start..=end))
+    }
+}
+
+impl fmt::Display for KeyRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (start, end) = (*self.0.start(), *self.0.end());
+        if start == end {
+            write!(f, "{:?}", KeyCode::new(start))
+        } else {
+            write!(f, "{:?}..{:?}", KeyCode::new(start), KeyCode::new(end))
+        }
+    }
+}
+
+/// Decides which key codes may pass: those in the allowlist, if there is one,
+/// that are not in the denylist.
+#[derive(Debug, Clone, Default)]
+pub struct KeyFilter {
+    pub allow: Vec<KeyRange>,
+    pub deny: Vec<KeyRange>,
+}
+
+impl KeyFilter {
+    pub fn allows(&self, key: u16) -> bool {
+        (self.allow.is_empty() || self.allow.iter().any(|range| range.contains(key)))
+            && !self.deny.iter().any(|range| range.contains(key))
+    }
+}
+
+/// Keys pressed together, written with `+` (`ctrl+alt+delete`); the last
+/// one completes it. Modifiers match either side of the keyboard.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Chord(Vec<KeyCode>);
+
+impl Chord {
+    fn completed_by(&self, key: u16) -> bool {
+        self.0.last().is_some_and(|last| same_key(*last, key))
+    }
+
+    /// The keys to be held before the last one.
+    fn held(&self) -> &[KeyCode] {
+        &self.0[..self.0.len() - 1]
+    }
+}
+
+/// Whether `key` is `expected`, or the other side's copy of that modifier.
+fn same_key(expected: KeyCode, key: u16) -> bool {
+    expected.code() == key
+        || MODIFIER_SIDES.iter().any(|&(left, right)| {
+            [left, right].contains(&expected) && [left.code(), right.code()].contains(&key)
+        })
+}
+
+impl FromStr for Chord {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        let keys = s.split('+').map(parse_key).collect::<Result<Vec<_>, _>>()?;
+        if keys.len() < 2 {
+            return Err(format!("chord {} needs at least two keys", s));
+        }
+        Ok(Chord(keys))
+    }
+}
+
+impl fmt::Display for Chord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names: Vec<String> = self.0.iter().map(|key| format!("{:?}", key)).collect();
+        write!(f, "{}", names.join("+"))
+    }
+}
+
+/// Remembers when one sender last pressed each key. Events only carry key
+/// presses, so a key counts as held for `window` after it was pressed.
+#[derive(Default)]
+pub struct ChordTracker {
+    pressed: HashMap<u16, Instant>,
+}
+
+impl ChordTracker {
+    /// Records a press of `key`, returning the chord in `deny` it would
+    /// complete, if any.
+    pub fn press<'a>(
+        &mut self,
+        deny: &'a [Chord],
+        window: Duration,
+        key: u16,
+    ) -> Option<&'a Chord> {
+        self.press_at(deny, window, key, Instant::now())
+    }
+
+    fn press_at<'a>(
+        &mut self,
+        deny: &'a [Chord],
+        window: Duration,
+        key: u16,
+        now: Instant,
+    ) -> Option<&'a Chord> {
+        self.pressed
+            .retain(|_, at| now.saturating_duration_since(*at) <= window);
+        let denied = deny.iter().find(|chord| {
+            chord.completed_by(key)
+                && chord.held().iter().all(|held| {
+                    self.pressed
+                        .keys()
+                        .any(|&pressed| pressed != key && same_key(*held, pressed))
+                })
+        });
+        self.pressed.insert(key, now);
+        denied
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_names_in_any_case_with_or_without_key() {
+        for name in ["KEY_ESC", "key_esc", "Esc", "ESC", " esc "] {
+            assert_eq!(parse_key(name), Ok(KeyCode::KEY_ESC), "{}", name);
+        }
+        assert_eq!(parse_key("f1"), Ok(KeyCode::KEY_F1));
+        assert_eq!(parse_key("btn_left"), Ok(KeyCode::BTN_LEFT));
+    }
+
+    #[test]
+    fn parses_aliases() {
+        assert_eq!(parse_key("ctrl"), Ok(KeyCode::KEY_LEFTCTRL));
+        assert_eq!(parse_key("AltGr"), Ok(KeyCode::KEY_RIGHTALT));
+        assert_eq!(parse_key("win"), Ok(KeyCode::KEY_LEFTMETA));
+        assert_eq!(parse_key("escape"), Ok(KeyCode::KEY_ESC));
+    }
+
+    #[test]
+    fn parses_codes() {
+        assert_eq!(parse_key("30"), Ok(KeyCode::KEY_A));
+        assert_eq!(parse_key("0x1e"), Ok(KeyCode::KEY_A));
+        assert_eq!(parse_key("0X1E"), Ok(KeyCode::KEY_A));
+        // A digit is a key code, not the key with that label.
+        assert_eq!(parse_key("1"), Ok(KeyCode::KEY_ESC));
+        assert!(parse_key("0x10000").is_err());
+        assert_eq!(
+            parse_key(&(KEY_MAX + 1).to_string()),
+            Err(format!("key code {} is out of range", KEY_MAX + 1))
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_keys_and_ranges() {
+        assert_eq!(parse_key("nope"), Err("unknown key: nope".to_string()));
+        assert!(parse_key("a..z").is_err());
+    }
+
+    #[test]
+    fn parses_ranges() {
+        let range = KeyRange::from_str("f1..F3").unwrap();
+        let keys: Vec<_> = range.keys().collect();
+        assert_eq!(keys, [KeyCode::KEY_F1, KeyCode::KEY_F2, KeyCode::KEY_F3]);
+        assert_eq!(KeyRange::from_str("0x1e").unwrap().to_string(), "KEY_A");
+        assert_eq!(range.to_string(), "KEY_F1..KEY_F3");
+        assert!(KeyRange::from_str("f3..f1").is_err());
+    }
+
+    #[test]
+    fn filters_keys() {
+        let filter = KeyFilter {
+            allow: vec![KeyRange::from_str("1..100").unwrap()],
+            deny: vec![KeyRange::from_str("esc").unwrap()],
+        };
+        assert!(filter.allows(KeyCode::KEY_A.code()));
+        assert!(!filter.allows(KeyCode::KEY_ESC.code()));
+        assert!(!filter.allows(KeyCode::KEY_POWER.code()));
+        assert!(KeyFilter::default().allows(KeyCode::KEY_POWER.code()));
+    }
+
+    #[test]
+    fn parses_chords() {
+        let chord = Chord::from_str("Ctrl+alt+DEL").unwrap();
+        assert_eq!(chord.to_string(), "KEY_LEFTCTRL+KEY_LEFTALT+KEY_DELETE");
+        assert!(Chord::from_str("delete").is_err());
+        assert_eq!(
+            Chord::from_str("ctrl+nope"),
+            Err("unknown key: nope".to_string())
+        );
+    }
+
+    fn chords() -> Vec<Chord> {
+        DANGEROUS_CHORDS
+            .iter()
+            .map(|chord| Chord::from_str(chord).unwrap())
+            .collect()
+    }
+
+    fn press(tracker: &mut ChordTracker, key: KeyCode, now: Instant) -> Option<String> {
+        tracker
+            .press_at(&chords(), Duration::from_millis(500), key.code(), now)
+            .map(ToString::to_string)
+    }
+
+    #[test]
+    fn denies_the_key_completing_a_chord() {
+        let start = Instant::now();
+        let mut tracker = ChordTracker::default();
+        assert_eq!(press(&mut tracker, KeyCode::KEY_RIGHTCTRL, start), None);
+        assert_eq!(press(&mut tracker, KeyCode::KEY_LEFTALT, start), None);
+        assert_eq!(
+            press(&mut tracker, KeyCode::KEY_DELETE, start).as_deref(),
+            Some("KEY_LEFTCTRL+KEY_LEFTALT+KEY_DELETE")
+        );
+        assert!(press(&mut tracker, KeyCode::KEY_KPDOT, start).is_some());
+    }
+
+    #[test]
+    fn allows_the_keys_on_their_own() {
+        let start = Instant::now();
+        let mut tracker = ChordTracker::default();
+        assert_eq!(press(&mut tracker, KeyCode::KEY_DELETE, start), None);
+        assert_eq!(press(&mut tracker, KeyCode::KEY_LEFTCTRL, start), None);
+        // Alt is missing.
+        assert_eq!(press(&mut tracker, KeyCode::KEY_DELETE, start), None);
+    }
+
+    #[test]
+    fn modifiers_stop_counting_after_the_window() {
+        let start = Instant::now();
+        let mut tracker = ChordTracker::default();
+        press(&mut tracker, KeyCode::KEY_LEFTCTRL, start);
+        press(&mut tracker, KeyCode::KEY_LEFTALT, start);
+        let later = start + Duration::from_millis(501);
+        assert_eq!(press(&mut tracker, KeyCode::KEY_DELETE, later), None);
+    }
+}
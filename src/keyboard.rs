@@ -1,31 +1,64 @@
 use anyhow::{Context, Result};
 use evdev::{Device, KeyCode};
 use regex::Regex;
+use std::collections::{HashMap, HashSet};
 use std::fs;
-use std::path::Path;
-use std::sync::mpsc;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, mpsc};
 use std::thread;
+use std::time::Duration;
 
-use crate::config::{KeyCodeMap, KeySyncConfig};
+use crate::clock::system_time_micros;
+use crate::config::{KeySyncConfig, LiveConfig};
 use crate::protocol::KeyEvent;
 
+/// How often the monitor checks whether the `devices` selectors changed.
+const CONFIG_POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// A thread reading one keyboard.
+struct Monitor {
+    name: String,
+    /// Set to stop just this keyboard.
+    stop: Arc<AtomicBool>,
+    handle: thread::JoinHandle<Result<()>>,
+}
+
+impl Monitor {
+    fn join(self) -> Result<()> {
+        self.handle
+            .join()
+            .map_err(|e| anyhow::anyhow!("Error joining keyboard thread {}: {:?}", self.name, e))?
+    }
+}
+
 pub struct KeyboardMonitor {
-    config: KeySyncConfig,
+    config: LiveConfig,
     sender: mpsc::Sender<KeyEvent>,
-    client_id: String,
+    /// Set to make every monitoring thread return.
+    stop: Arc<AtomicBool>,
+    /// Called when the `profile_hotkey` keys are held down together.
+    on_hotkey: Arc<dyn Fn() + Send + Sync>,
 }
 
 impl KeyboardMonitor {
-    pub fn new(sender: mpsc::Sender<KeyEvent>, config: KeySyncConfig, client_id: String) -> Self {
+    pub fn new(
+        sender: mpsc::Sender<KeyEvent>,
+        config: LiveConfig,
+        stop: Arc<AtomicBool>,
+        on_hotkey: impl Fn() + Send + Sync + 'static,
+    ) -> Self {
         KeyboardMonitor {
             config,
             sender,
-            client_id,
+            stop,
+            on_hotkey: Arc::new(on_hotkey),
         }
     }
 
-    fn build_device_selectors(&self) -> Result<Vec<DeviceSelector>> {
-        if let Some(devices) = self.config.devices.as_ref() {
+    fn build_device_selectors(devices: Option<&Vec<String>>) -> Result<Vec<DeviceSelector>> {
+        if let Some(devices) = devices {
             let mut selectors = Vec::new();
             for entry in devices {
                 if entry.starts_with('/') {
@@ -43,8 +76,10 @@ impl KeyboardMonitor {
         }
     }
 
-    pub fn find_keyboards(&self) -> Result<Vec<Device>> {
-        let selectors = self.build_device_selectors()?;
+    /// Opens the keyboards matching the `devices` selectors, or every
+    /// keyboard without them.
+    pub fn find_keyboards(devices: Option<&Vec<String>>) -> Result<Vec<(PathBuf, Device)>> {
+        let selectors = Self::build_device_selectors(devices)?;
 
         let mut devices = Vec::new();
         let input_path = Path::new("/dev/input");
@@ -67,7 +102,7 @@ impl KeyboardMonitor {
                             continue;
                         }
                     }
-                    devices.push(device)
+                    devices.push((path, device))
                 }
                 None => continue,
             }
@@ -76,6 +111,14 @@ impl KeyboardMonitor {
         Ok(devices)
     }
 
+    /// Whether the `devices` entry `selector` picks any of `keyboards`.
+    pub fn selects_any(selector: &str, keyboards: &[(PathBuf, Device)]) -> Result<bool> {
+        let selectors = Self::build_device_selectors(Some(&vec![selector.to_string()]))?;
+        Ok(keyboards
+            .iter()
+            .any(|(path, device)| selectors.iter().any(|sel| sel.matches(path, device.name()))))
+    }
+
     fn is_event_device(path: &Path) -> bool {
         path.file_name()
             .and_then(|name| name.to_str())
@@ -133,10 +176,9 @@ impl KeyboardMonitor {
     }
 
     fn process_key_event(
-        outgoing_map: &KeyCodeMap,
+        config: &KeySyncConfig,
         event: evdev::InputEvent,
         sender: &mpsc::Sender<KeyEvent>,
-        client_id: &str,
     ) {
         if event.event_type() != evdev::EventType::KEY || event.value() != 1 {
             return;
@@ -144,7 +186,7 @@ impl KeyboardMonitor {
 
         let key = evdev::KeyCode::new(event.code());
 
-        let mapped_key = match outgoing_map.get(&key) {
+        let mapped_key = match config.outgoing.get(&key) {
             Some(mapped_key) => {
                 tracing::info!(original = ?key, mapped = ?mapped_key, "Key pressed and mapped");
                 *mapped_key
@@ -152,9 +194,19 @@ impl KeyboardMonitor {
             None => return,
         };
 
+        let timestamp_us = system_time_micros(event.timestamp());
         let key_event = KeyEvent {
             key: mapped_key.0,
-            client_id: client_id.to_string(),
+            sender: 0,
+            seq: 0,
+            id: 0,
+            ack: config.acknowledge.contains(&mapped_key),
+            timestamp_us,
+            sent_at_us: 0,
+            execute_at_us: config
+                .schedule
+                .get(&mapped_key)
+                .map(|delay| timestamp_us + delay.as_micros() as u64),
         };
 
         if let Err(e) = sender.send(key_event) {
@@ -163,63 +215,157 @@ impl KeyboardMonitor {
     }
 
     fn monitor_keyboard(
-        outgoing_map: &KeyCodeMap,
+        config: &LiveConfig,
         device: &mut Device,
         sender: &mpsc::Sender<KeyEvent>,
-        client_id: String,
+        on_hotkey: &(dyn Fn() + Send + Sync),
+        stop: &AtomicBool,
+        stop_this: &AtomicBool,
     ) -> Result<()> {
-        loop {
-            for event in device
-                .fetch_events()
-                .context("Failed to fetch events from keyboard device")?
-            {
-                Self::process_key_event(outgoing_map, event, sender, &client_id);
+        // Polled, so that a stop request is noticed without a key press.
+        device
+            .set_nonblocking(true)
+            .context("Failed to make keyboard device non-blocking")?;
+        let mut hotkey = HotkeyWatch::default();
+        while !stop.load(Ordering::Relaxed) && !stop_this.load(Ordering::Relaxed) {
+            match device.fetch_events() {
+                Ok(events) => {
+                    for event in events {
+                        // Per event, so keys after a profile switch use the new maps.
+                        let config = config.get();
+                        let (ready, completed) = hotkey.feed(&config.profile_hotkey, event);
+                        if completed {
+                            on_hotkey();
+                        }
+                        for event in ready {
+                            Self::process_key_event(&config, event, sender);
+                        }
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => {
+                    return Err(e).context("Failed to fetch events from keyboard device");
+                }
             }
 
             std::thread::sleep(std::time::Duration::from_millis(5));
         }
+        Ok(())
     }
 
+    /// Monitors the selected keyboards until stopped, adopting and dropping
+    /// keyboards as the `devices` selectors in the config change.
     pub fn start(&self) -> Result<()> {
-        let keyboards = self.find_keyboards()?;
+        let mut selected = self.config.get().devices.clone();
+        let mut monitors = HashMap::new();
+        self.select_keyboards(selected.as_ref(), &mut monitors)?;
 
-        if keyboards.is_empty() {
+        if monitors.is_empty() {
             return Err(anyhow::anyhow!("No keyboards found!"));
         }
 
-        tracing::info!(count = keyboards.len(), "Found keyboards");
+        tracing::info!(count = monitors.len(), "Found keyboards");
+
+        while !self.stop.load(Ordering::Relaxed) {
+            thread::sleep(CONFIG_POLL_INTERVAL);
+
+            // A keyboard that fails stops the client, as it always has.
+            let finished: Vec<PathBuf> = monitors
+                .iter()
+                .filter(|(_, monitor)| monitor.handle.is_finished())
+                .map(|(path, _)| path.clone())
+                .collect();
+            for path in finished {
+                if let Some(monitor) = monitors.remove(&path) {
+                    monitor.join()?;
+                }
+            }
 
-        self.start_keyboard_monitors(keyboards)
+            let devices = self.config.get().devices.clone();
+            if devices != selected {
+                tracing::info!(devices = ?devices, "Keyboard selection changed");
+                if let Err(e) = self.select_keyboards(devices.as_ref(), &mut monitors) {
+                    tracing::error!(error = %format!("{:#}", e), "Failed to select keyboards");
+                }
+                if monitors.is_empty() {
+                    tracing::warn!("No keyboards match the config; not monitoring any");
+                }
+                selected = devices;
+            }
+        }
+
+        monitors
+            .into_values()
+            .map(Monitor::join)
+            .fold(Ok(()), Result::and)
     }
 
-    fn start_keyboard_monitors(&self, keyboards: Vec<Device>) -> Result<()> {
-        let mut handles = Vec::new();
+    /// Starts monitoring the keyboards `devices` selects that aren't
+    /// monitored yet, and stops monitoring the ones it no longer selects.
+    fn select_keyboards(
+        &self,
+        devices: Option<&Vec<String>>,
+        monitors: &mut HashMap<PathBuf, Monitor>,
+    ) -> Result<()> {
+        let keyboards = Self::find_keyboards(devices)?;
+        let wanted: HashSet<&PathBuf> = keyboards.iter().map(|(path, _)| path).collect();
+
+        let dropped: Vec<PathBuf> = monitors
+            .keys()
+            .filter(|path| !wanted.contains(path))
+            .cloned()
+            .collect();
+        for path in dropped {
+            if let Some(monitor) = monitors.remove(&path) {
+                tracing::info!(name = %monitor.name, path = ?path, "No longer monitoring keyboard");
+                monitor.stop.store(true, Ordering::Relaxed);
+                if let Err(e) = monitor.join() {
+                    tracing::warn!(error = %format!("{:#}", e), "Keyboard failed while stopping");
+                }
+            }
+        }
 
-        for (i, mut keyboard) in keyboards.into_iter().enumerate() {
-            let sender = self.sender.clone();
-            let outgoing_map = self.config.outgoing.clone();
-            let client_id = self.client_id.clone();
+        for (path, keyboard) in keyboards {
+            monitors
+                .entry(path)
+                .or_insert_with(|| self.spawn_monitor(keyboard));
+        }
+        Ok(())
+    }
 
-            let handle = thread::spawn(move || -> Result<()> {
+    fn spawn_monitor(&self, mut keyboard: Device) -> Monitor {
+        let sender = self.sender.clone();
+        let config = self.config.clone();
+        let on_hotkey = Arc::clone(&self.on_hotkey);
+        let stop = Arc::clone(&self.stop);
+        let stop_this = Arc::new(AtomicBool::new(false));
+        let name = keyboard.name().unwrap_or("unnamed").to_string();
+
+        let handle = {
+            let stop_this = Arc::clone(&stop_this);
+            thread::spawn(move || -> Result<()> {
                 tracing::info!(
                     name = keyboard.name(),
                     physical = keyboard.physical_path(),
                     "Monitoring keyboard"
                 );
 
-                Self::monitor_keyboard(&outgoing_map, &mut keyboard, &sender, client_id)
-            });
-
-            handles.push((i, handle));
-        }
+                Self::monitor_keyboard(
+                    &config,
+                    &mut keyboard,
+                    &sender,
+                    &*on_hotkey,
+                    &stop,
+                    &stop_this,
+                )
+            })
+        };
 
-        for (i, handle) in handles {
-            handle
-                .join()
-                .map_err(|e| anyhow::anyhow!("Error joining keyboard thread {}: {:?}", i, e))??;
+        Monitor {
+            name,
+            stop: stop_this,
+            handle,
         }
-
-        Ok(())
     }
 }
 
@@ -242,3 +388,126 @@ impl DeviceSelector {
         }
     }
 }
+
+/// Follows the keys held on one keyboard to spot the profile hotkey. Presses
+/// of its keys are held back until the combo either completes, and they are
+/// dropped, or breaks, and they are let through after all.
+#[derive(Default)]
+struct HotkeyWatch {
+    held: HashSet<KeyCode>,
+    pending: Vec<evdev::InputEvent>,
+}
+
+impl HotkeyWatch {
+    /// Returns the events to handle now, in order, and whether `event`
+    /// completed the hotkey.
+    fn feed(
+        &mut self,
+        hotkey: &[KeyCode],
+        event: evdev::InputEvent,
+    ) -> (Vec<evdev::InputEvent>, bool) {
+        if event.event_type() != evdev::EventType::KEY {
+            return (vec![event], false);
+        }
+        let key = KeyCode::new(event.code());
+        match event.value() {
+            0 => {
+                self.held.remove(&key);
+            }
+            1 => {
+                self.held.insert(key);
+            }
+            _ => {}
+        }
+
+        if event.value() == 1 && hotkey.contains(&key) {
+            if hotkey.iter().all(|k| self.held.contains(k)) {
+                self.pending.clear();
+                return (Vec::new(), true);
+            }
+            self.pending.push(event);
+            return (Vec::new(), false);
+        }
+
+        // Another key, or letting go of part of the combo.
+        let breaks_combo = match event.value() {
+            1 => true,
+            0 => self.pending.iter().any(|p| p.code() == event.code()),
+            _ => false,
+        };
+        let mut ready = if breaks_combo {
+            std::mem::take(&mut self.pending)
+        } else {
+            Vec::new()
+        };
+        ready.push(event);
+        (ready, false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use evdev::{EventType, InputEvent};
+
+    const HOTKEY: [KeyCode; 2] = [KeyCode::KEY_LEFTCTRL, KeyCode::KEY_P];
+
+    fn key(key: KeyCode, value: i32) -> InputEvent {
+        InputEvent::new(EventType::KEY.0, key.code(), value)
+    }
+
+    fn codes(events: &[InputEvent]) -> Vec<(u16, i32)> {
+        events.iter().map(|e| (e.code(), e.value())).collect()
+    }
+
+    #[test]
+    fn drops_every_key_of_a_completed_hotkey() {
+        let mut watch = HotkeyWatch::default();
+        let (ready, completed) = watch.feed(&HOTKEY, key(KeyCode::KEY_LEFTCTRL, 1));
+        assert!(ready.is_empty() && !completed);
+        let (ready, completed) = watch.feed(&HOTKEY, key(KeyCode::KEY_P, 1));
+        assert!(ready.is_empty() && completed);
+        // The releases go through, but have nothing held back to send.
+        let (ready, _) = watch.feed(&HOTKEY, key(KeyCode::KEY_P, 0));
+        assert_eq!(codes(&ready), [(KeyCode::KEY_P.code(), 0)]);
+        let (ready, _) = watch.feed(&HOTKEY, key(KeyCode::KEY_LEFTCTRL, 0));
+        assert_eq!(codes(&ready), [(KeyCode::KEY_LEFTCTRL.code(), 0)]);
+    }
+
+    #[test]
+    fn sends_held_back_keys_once_the_combo_breaks() {
+        let mut watch = HotkeyWatch::default();
+        watch.feed(&HOTKEY, key(KeyCode::KEY_LEFTCTRL, 1));
+        let (ready, completed) = watch.feed(&HOTKEY, key(KeyCode::KEY_C, 1));
+        assert!(!completed);
+        assert_eq!(
+            codes(&ready),
+            [
+                (KeyCode::KEY_LEFTCTRL.code(), 1),
+                (KeyCode::KEY_C.code(), 1)
+            ]
+        );
+    }
+
+    #[test]
+    fn sends_a_held_back_key_when_it_is_released_alone() {
+        let mut watch = HotkeyWatch::default();
+        watch.feed(&HOTKEY, key(KeyCode::KEY_P, 1));
+        let (ready, _) = watch.feed(&HOTKEY, key(KeyCode::KEY_P, 2));
+        assert_eq!(codes(&ready), [(KeyCode::KEY_P.code(), 2)]);
+        let (ready, completed) = watch.feed(&HOTKEY, key(KeyCode::KEY_P, 0));
+        assert!(!completed);
+        assert_eq!(
+            codes(&ready),
+            [(KeyCode::KEY_P.code(), 1), (KeyCode::KEY_P.code(), 0)]
+        );
+    }
+
+    #[test]
+    fn passes_everything_through_without_a_hotkey() {
+        let mut watch = HotkeyWatch::default();
+        let (ready, completed) = watch.feed(&[], key(KeyCode::KEY_P, 1));
+        assert!(!completed);
+        assert_eq!(codes(&ready), [(KeyCode::KEY_P.code(), 1)]);
+    }
+}
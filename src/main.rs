@@ -1,14 +1,40 @@
-use anyhow::Result;
-use clap::{Parser, Subcommand};
+use anyhow::{Context, Result};
+use clap::parser::ValueSource;
+use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser, Subcommand};
+use std::path::PathBuf;
 use std::process;
+use std::time::Duration;
 
+use client::ClientOptions;
+use config::{ClientSettings, KeySyncConfig};
+use key_filter::{Chord, DANGEROUS_CHORDS, DANGEROUS_KEYS, KeyFilter, KeyRange};
+use offline_queue::{OfflinePolicy, OfflineQueueConfig};
+use protocol::{Codec, Compression};
+use rate_limit::{Rate, RateLimitConfig};
+use reconnectable_stream::ReconnectPolicy;
+use server::ReplayConfig;
+use server_config::ServerConfig;
+
+mod ack;
+mod admin;
 mod client;
+mod clock;
 mod config;
+mod config_check;
+mod control;
+mod key_filter;
 mod keyboard;
+mod metrics;
+mod offline_queue;
 mod protocol;
+mod rate_limit;
 mod reconnectable_stream;
+mod routing;
+mod scheduler;
 mod server;
+mod server_config;
 mod utils;
+mod watch;
 
 #[derive(Parser)]
 #[command(version, about, long_about = None)]
@@ -21,35 +47,401 @@ struct Cli {
 enum Commands {
     /// Run in server mode
     Server {
+        /// YAML file with server settings, reloaded on change or SIGHUP
+        #[arg(short, long)]
+        config: Option<PathBuf>,
         /// Address to listen on
         #[arg(short, long, default_value = "0.0.0.0:1234")]
         bind_address: String,
+        /// Number of recent key events kept to replay to reconnecting clients
+        #[arg(long, default_value_t = 256)]
+        replay_buffer_size: usize,
+        /// Maximum age of replayed key events, in milliseconds
+        #[arg(long, default_value_t = 5_000)]
+        replay_max_age_ms: u64,
+        /// Key events per second accepted from each client (0 for no limit)
+        #[arg(long, default_value_t = RateLimitConfig::default().client.per_sec)]
+        rate_limit: f64,
+        /// Key events a client may send at once before its rate limit applies
+        #[arg(long, default_value_t = RateLimitConfig::default().client.burst)]
+        rate_limit_burst: u32,
+        /// Key events per second accepted from each client for a single key (0 for no limit)
+        #[arg(long, default_value_t = RateLimitConfig::default().key.per_sec)]
+        key_rate_limit: f64,
+        /// Events for a single key a client may send at once before its rate limit applies
+        #[arg(long, default_value_t = RateLimitConfig::default().key.burst)]
+        key_rate_limit_burst: u32,
+        /// Only relay these keys: a name, a code or a range like KEY_F1..KEY_F12 (repeatable)
+        #[arg(long = "allow-key")]
+        allow_keys: Vec<KeyRange>,
+        /// Never relay these keys (repeatable; replaces the default list)
+        #[arg(long = "deny-key", default_values = DANGEROUS_KEYS)]
+        deny_keys: Vec<KeyRange>,
+        /// Never relay the last key of this chord, like ctrl+alt+delete, while
+        /// the others are held (repeatable; replaces the default list)
+        #[arg(long = "deny-chord", default_values = DANGEROUS_CHORDS)]
+        deny_chords: Vec<Chord>,
+        /// How long after its press a key counts as held for --deny-chord, in milliseconds
+        #[arg(long, default_value_t = 500)]
+        chord_window_ms: u64,
+        /// Serve the HTTP admin API on this address, e.g. 127.0.0.1:1235
+        #[arg(long)]
+        admin_address: Option<String>,
+        /// Bearer token the admin API requires (needed unless it listens on loopback only)
+        #[arg(long, env = "KEYSYNC_ADMIN_TOKEN", hide_env_values = true)]
+        admin_token: Option<String>,
+        /// Serve Prometheus metrics on this address, e.g. 127.0.0.1:9100
+        #[arg(long)]
+        metrics_address: Option<String>,
+        /// Refuse new clients once this many are connected
+        #[arg(long)]
+        max_clients: Option<usize>,
+        /// Only accept clients presenting this secret (repeatable)
+        #[arg(
+            long = "auth-secret",
+            env = "KEYSYNC_AUTH_SECRETS",
+            value_delimiter = ',',
+            hide_env_values = true
+        )]
+        auth_secrets: Vec<String>,
+        /// Only accept clients joining this channel (repeatable; any channel if unset)
+        #[arg(long = "channel")]
+        channels: Vec<String>,
+        /// When shutting down, ask clients to wait this long before reconnecting, in seconds
+        #[arg(long, default_value_t = 5)]
+        shutdown_retry_after_secs: u64,
     },
-    /// Run in client mode
+    /// Run in client mode. Options can also be set in the config file, or in
+    /// the environment variables listed below, which take precedence over it.
     Client {
-        /// Server address to connect to
-        #[arg(short, long, default_value = "127.0.0.1:1234")]
-        server_address: String,
+        /// Config file to use instead of looking in $KEYSYNC_CONFIG,
+        /// $XDG_CONFIG_HOME/keysync/config.yaml and /etc/keysync/config.yaml
+        #[arg(short, long)]
+        config: Option<PathBuf>,
+        /// Server address to connect to; with several (repeatable or comma separated), each is
+        /// tried in order until one answers
+        #[arg(
+            short,
+            long,
+            env = "KEYSYNC_SERVER",
+            value_delimiter = ',',
+            default_value = "127.0.0.1:1234"
+        )]
+        server_address: Vec<String>,
+        /// Name to introduce ourselves to the server with (default: user name plus a random number)
+        #[arg(long, env = "KEYSYNC_CLIENT_ID")]
+        client_id: Option<String>,
+        /// Give up after this many failed reconnection attempts (retries forever if unset)
+        #[arg(long, env = "KEYSYNC_MAX_RECONNECT_ATTEMPTS")]
+        max_reconnect_attempts: Option<u32>,
+        /// How long to wait for a server to accept a connection, in milliseconds
+        #[arg(long, env = "KEYSYNC_CONNECT_TIMEOUT_MS", default_value_t = 5_000)]
+        connect_timeout_ms: u64,
+        /// Upper bound for the delay between reconnection attempts, in milliseconds
+        #[arg(
+            long,
+            env = "KEYSYNC_MAX_RECONNECT_BACKOFF_MS",
+            default_value_t = 10_000
+        )]
+        max_reconnect_backoff_ms: u64,
+        /// Maximum number of key events buffered while disconnected from the server
+        #[arg(long, env = "KEYSYNC_OFFLINE_QUEUE_SIZE", default_value_t = 64)]
+        offline_queue_size: usize,
+        /// How long a buffered key event stays eligible for sending, in milliseconds
+        #[arg(long, env = "KEYSYNC_OFFLINE_TTL_MS", default_value_t = 1_000)]
+        offline_ttl_ms: u64,
+        /// What to send once the connection is back
+        #[arg(long, env = "KEYSYNC_OFFLINE_POLICY", value_enum, default_value_t = OfflinePolicy::DropStale)]
+        offline_policy: OfflinePolicy,
+        /// How long to wait for receivers to acknowledge a key event, in milliseconds
+        #[arg(long, env = "KEYSYNC_ACK_TIMEOUT_MS", default_value_t = 500)]
+        ack_timeout_ms: u64,
+        /// How many times an unacknowledged key event is sent again before giving up
+        #[arg(long, env = "KEYSYNC_ACK_RETRANSMITS", default_value_t = 2)]
+        ack_retransmits: u32,
+        /// Drop incoming key events that were pressed longer ago than this, in milliseconds
+        #[arg(long, env = "KEYSYNC_MAX_EVENT_AGE_MS")]
+        max_event_age_ms: Option<u64>,
+        /// Send key events pressed within this many milliseconds of each other as one frame
+        #[arg(long, env = "KEYSYNC_BATCH_WINDOW_MS", default_value_t = 0)]
+        batch_window_ms: u64,
+        /// Compression to negotiate with the server
+        #[arg(long, env = "KEYSYNC_COMPRESSION", value_enum, default_value_t = Compression::None)]
+        compression: Compression,
+        /// Wire format to talk to the server in
+        #[arg(long, env = "KEYSYNC_CODEC", value_enum, default_value_t = Codec::Bitcode)]
+        codec: Codec,
+        /// Label shown to server operators; may be repeated
+        #[arg(long = "tag", env = "KEYSYNC_TAGS", value_delimiter = ',')]
+        tags: Vec<String>,
+        /// Channel to join; only clients in the same channel exchange keys (default: "default")
+        #[arg(long, env = "KEYSYNC_CHANNEL")]
+        channel: Option<String>,
+        /// Auth secret to present, for servers that require one
+        #[arg(long, env = "KEYSYNC_SECRET", hide_env_values = true)]
+        secret: Option<String>,
+        /// Serve Prometheus metrics on this address, e.g. 127.0.0.1:9101
+        #[arg(long, env = "KEYSYNC_METRICS_ADDRESS")]
+        metrics_address: Option<String>,
+        /// Use this profile from the config file on top of its base mappings
+        #[arg(short, long, env = "KEYSYNC_PROFILE")]
+        profile: Option<String>,
+        /// Take commands such as `profile next` on this Unix socket
+        #[arg(long, env = "KEYSYNC_CONTROL_SOCKET")]
+        control_socket: Option<PathBuf>,
+    },
+    /// Work with the client config file
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommand,
     },
 }
 
-fn run() -> Result<()> {
-    let cli = Cli::parse();
+#[derive(Subcommand)]
+enum ConfigCommand {
+    /// Check the config file for mistakes, with the line of each
+    Check {
+        /// Config file to check instead of the one the client would load
+        #[arg(short, long)]
+        config: Option<PathBuf>,
+    },
+}
+
+/// Picks an option's value: the command line's if it was given there or in
+/// the environment, else the config file's, else the command line default.
+fn pick<T>(matches: &ArgMatches, id: &str, cli: T, file: Option<T>) -> T {
+    match matches.value_source(id) {
+        Some(ValueSource::CommandLine | ValueSource::EnvVariable) => cli,
+        _ => file.unwrap_or(cli),
+    }
+}
+
+/// Server options given on the command line, by the dotted path of the same
+/// setting in the server config file.
+fn overridden(matches: &ArgMatches) -> server_config::Overridden {
+    const SETTINGS: [(&str, &str); 18] = [
+        ("bind_address", "bind_address"),
+        ("admin_address", "admin_address"),
+        ("admin_token", "admin_token"),
+        ("metrics_address", "metrics_address"),
+        ("max_clients", "max_clients"),
+        ("auth_secrets", "auth.secrets"),
+        ("channels", "channels"),
+        ("replay_buffer_size", "replay.buffer_size"),
+        ("replay_max_age_ms", "replay.max_age_ms"),
+        ("rate_limit", "rate_limit.per_sec"),
+        ("rate_limit_burst", "rate_limit.burst"),
+        ("key_rate_limit", "rate_limit.key_per_sec"),
+        ("key_rate_limit_burst", "rate_limit.key_burst"),
+        ("allow_keys", "keys.allow"),
+        ("deny_keys", "keys.deny"),
+        ("deny_chords", "keys.deny_chords"),
+        ("chord_window_ms", "keys.chord_window_ms"),
+        ("shutdown_retry_after_secs", "shutdown_retry_after_secs"),
+    ];
+    SETTINGS
+        .iter()
+        .filter(|(id, _)| {
+            matches!(
+                matches.value_source(id),
+                Some(ValueSource::CommandLine | ValueSource::EnvVariable)
+            )
+        })
+        .map(|(_, path)| path.to_string())
+        .collect()
+}
+
+/// Runs the chosen command, returning the process exit code.
+fn run() -> Result<i32> {
+    let matches = Cli::command().get_matches();
+    let cli = Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
 
     match &cli.command {
         Commands::Server {
-            bind_address: listen_addr,
+            config: config_path,
+            bind_address,
+            replay_buffer_size,
+            replay_max_age_ms,
+            rate_limit,
+            rate_limit_burst,
+            key_rate_limit,
+            key_rate_limit_burst,
+            allow_keys,
+            deny_keys,
+            deny_chords,
+            chord_window_ms,
+            admin_address,
+            admin_token,
+            metrics_address,
+            max_clients,
+            auth_secrets,
+            channels,
+            shutdown_retry_after_secs,
         } => {
-            server::run(listen_addr)?;
+            let config = ServerConfig {
+                bind_address: bind_address.clone(),
+                admin_address: admin_address.clone(),
+                admin_token: admin_token.clone(),
+                metrics_address: metrics_address.clone(),
+                max_clients: *max_clients,
+                auth_secrets: auth_secrets.clone(),
+                channels: channels.clone(),
+                routes: Vec::new(),
+                replay: ReplayConfig {
+                    capacity: *replay_buffer_size,
+                    max_age: Duration::from_millis(*replay_max_age_ms),
+                },
+                rate_limit: RateLimitConfig {
+                    client: Rate {
+                        per_sec: *rate_limit,
+                        burst: *rate_limit_burst,
+                    },
+                    key: Rate {
+                        per_sec: *key_rate_limit,
+                        burst: *key_rate_limit_burst,
+                    },
+                },
+                key_filter: KeyFilter {
+                    allow: allow_keys.clone(),
+                    deny: deny_keys.clone(),
+                },
+                deny_chords: deny_chords.clone(),
+                chord_window: Duration::from_millis(*chord_window_ms),
+                shutdown_retry_after: Duration::from_secs(*shutdown_retry_after_secs),
+            };
+            match config_path {
+                Some(path) => {
+                    let matches = matches
+                        .subcommand_matches("server")
+                        .expect("server subcommand was parsed");
+                    let (config, reloader) =
+                        server_config::load(path.clone(), config, overridden(matches))?;
+                    server::run(config, Some(reloader))?;
+                }
+                None => server::run(config, None)?,
+            }
         }
         Commands::Client {
-            server_address: server_addr,
+            config: config_path,
+            server_address,
+            client_id,
+            max_reconnect_attempts,
+            connect_timeout_ms,
+            max_reconnect_backoff_ms,
+            offline_queue_size,
+            offline_ttl_ms,
+            offline_policy,
+            ack_timeout_ms,
+            ack_retransmits,
+            max_event_age_ms,
+            batch_window_ms,
+            compression,
+            codec,
+            tags,
+            channel,
+            secret,
+            metrics_address,
+            profile,
+            control_socket,
         } => {
-            client::run(server_addr)?;
+            let config_path = client::find_config(config_path.as_deref())?;
+            let file = ClientSettings::load(&config_path)?;
+            let matches = matches
+                .subcommand_matches("client")
+                .expect("client subcommand was parsed");
+            let ms = |id, cli: u64, file| Duration::from_millis(pick(matches, id, cli, file));
+            let options = ClientOptions {
+                server_addresses: pick(
+                    matches,
+                    "server_address",
+                    server_address.clone(),
+                    file.server_address,
+                ),
+                client_id: pick(
+                    matches,
+                    "client_id",
+                    client_id.clone(),
+                    file.client_id.map(Some),
+                ),
+                reconnect: ReconnectPolicy {
+                    max_attempts: pick(
+                        matches,
+                        "max_reconnect_attempts",
+                        *max_reconnect_attempts,
+                        file.max_reconnect_attempts.map(Some),
+                    ),
+                    max_backoff: ms(
+                        "max_reconnect_backoff_ms",
+                        *max_reconnect_backoff_ms,
+                        file.max_reconnect_backoff_ms,
+                    ),
+                    connect_timeout: ms(
+                        "connect_timeout_ms",
+                        *connect_timeout_ms,
+                        file.connect_timeout_ms,
+                    ),
+                    ..Default::default()
+                },
+                offline_queue: OfflineQueueConfig {
+                    capacity: pick(
+                        matches,
+                        "offline_queue_size",
+                        *offline_queue_size,
+                        file.offline_queue_size,
+                    ),
+                    ttl: ms("offline_ttl_ms", *offline_ttl_ms, file.offline_ttl_ms),
+                    policy: pick(
+                        matches,
+                        "offline_policy",
+                        *offline_policy,
+                        file.offline_policy,
+                    ),
+                },
+                ack_timeout: ms("ack_timeout_ms", *ack_timeout_ms, file.ack_timeout_ms),
+                ack_retransmits: pick(
+                    matches,
+                    "ack_retransmits",
+                    *ack_retransmits,
+                    file.ack_retransmits,
+                ),
+                max_event_age: pick(
+                    matches,
+                    "max_event_age_ms",
+                    *max_event_age_ms,
+                    file.max_event_age_ms.map(Some),
+                )
+                .map(Duration::from_millis),
+                batch_window: ms("batch_window_ms", *batch_window_ms, file.batch_window_ms),
+                codec: pick(matches, "codec", *codec, file.codec),
+                tags: pick(matches, "tags", tags.clone(), file.tags),
+                channel: pick(matches, "channel", channel.clone(), file.channel.map(Some)),
+                secret: pick(matches, "secret", secret.clone(), file.secret.map(Some)),
+                metrics_address: pick(
+                    matches,
+                    "metrics_address",
+                    metrics_address.clone(),
+                    file.metrics_address.map(Some),
+                ),
+                compression: pick(matches, "compression", *compression, file.compression),
+                profile: pick(matches, "profile", profile.clone(), file.profile.map(Some)),
+                control_socket: pick(
+                    matches,
+                    "control_socket",
+                    control_socket.clone(),
+                    file.control_socket.map(Some),
+                ),
+            };
+            return Ok(client::run(&config_path, options)?.exit_code());
+        }
+        Commands::Config {
+            command: ConfigCommand::Check { config },
+        } => {
+            let path = KeySyncConfig::locate(config.as_deref()).context("No config file found")?;
+            return Ok(if config_check::check(&path)? { 0 } else { 1 });
         }
     }
 
-    Ok(())
+    Ok(0)
 }
 
 fn main() {
@@ -62,8 +454,11 @@ fn main() {
         .with_target(false)
         .init();
 
-    if let Err(e) = run() {
-        tracing::error!(cause = e.source(), "Application error: {}", e);
-        process::exit(1);
+    match run() {
+        Ok(code) => process::exit(code),
+        Err(e) => {
+            tracing::error!(cause = e.source(), "Application error: {}", e);
+            process::exit(1);
+        }
     }
 }
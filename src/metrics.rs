@@ -0,0 +1,183 @@
+use anyhow::Result;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::Duration;
+use tiny_http::{Header, Response};
+
+/// Upper bounds, in seconds, of the latency histogram buckets.
+const LATENCY_BUCKETS: [f64; 12] = [
+    0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5,
+];
+
+#[derive(Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Counters that share a name and differ in the value of one label.
+#[derive(Default)]
+pub struct CounterVec(Mutex<BTreeMap<String, u64>>);
+
+impl CounterVec {
+    pub fn add(&self, label: &str, n: u64) {
+        *self.0.lock().unwrap().entry(label.to_string()).or_default() += n;
+    }
+
+    pub fn inc(&self, label: &str) {
+        self.add(label, 1);
+    }
+}
+
+/// A histogram of durations with fixed latency buckets.
+pub struct Histogram {
+    counts: [AtomicU64; LATENCY_BUCKETS.len()],
+    count: AtomicU64,
+    sum_us: AtomicU64,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self {
+            counts: std::array::from_fn(|_| AtomicU64::new(0)),
+            count: AtomicU64::new(0),
+            sum_us: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Histogram {
+    pub fn observe(&self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        if let Some(bucket) = LATENCY_BUCKETS.iter().position(|&le| seconds <= le) {
+            self.counts[bucket].fetch_add(1, Ordering::Relaxed);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_us
+            .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    /// Observes a duration in microseconds that may be negative because of
+    /// clock estimation error; those count as zero.
+    pub fn observe_us(&self, us: i64) {
+        self.observe(Duration::from_micros(us.max(0) as u64));
+    }
+}
+
+/// Builds a response in the Prometheus text exposition format.
+#[derive(Default)]
+pub struct Encoder {
+    out: String,
+}
+
+impl Encoder {
+    fn header(&mut self, name: &str, kind: &str, help: &str) {
+        let _ = writeln!(self.out, "# HELP {} {}", name, help);
+        let _ = writeln!(self.out, "# TYPE {} {}", name, kind);
+    }
+
+    pub fn gauge(&mut self, name: &str, help: &str, value: f64) {
+        self.header(name, "gauge", help);
+        let _ = writeln!(self.out, "{} {}", name, value);
+    }
+
+    pub fn counter(&mut self, name: &str, help: &str, value: u64) {
+        self.header(name, "counter", help);
+        let _ = writeln!(self.out, "{} {}", name, value);
+    }
+
+    /// Writes one counter per `(label value, count)` pair.
+    pub fn counters<'a>(
+        &mut self,
+        name: &str,
+        help: &str,
+        label: &str,
+        values: impl IntoIterator<Item = (&'a str, u64)>,
+    ) {
+        self.header(name, "counter", help);
+        for (value, count) in values {
+            let _ = writeln!(
+                self.out,
+                "{}{{{}=\"{}\"}} {}",
+                name,
+                label,
+                escape(value),
+                count
+            );
+        }
+    }
+
+    pub fn counter_vec(&mut self, name: &str, help: &str, label: &str, counters: &CounterVec) {
+        let values = counters.0.lock().unwrap();
+        self.counters(
+            name,
+            help,
+            label,
+            values.iter().map(|(value, count)| (value.as_str(), *count)),
+        );
+    }
+
+    pub fn histogram(&mut self, name: &str, help: &str, histogram: &Histogram) {
+        self.header(name, "histogram", help);
+        let mut cumulative = 0;
+        for (le, count) in LATENCY_BUCKETS.iter().zip(&histogram.counts) {
+            cumulative += count.load(Ordering::Relaxed);
+            let _ = writeln!(self.out, "{}_bucket{{le=\"{}\"}} {}", name, le, cumulative);
+        }
+        let count = histogram.count.load(Ordering::Relaxed);
+        let sum = histogram.sum_us.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+        let _ = writeln!(self.out, "{}_bucket{{le=\"+Inf\"}} {}", name, count);
+        let _ = writeln!(self.out, "{}_sum {}", name, sum);
+        let _ = writeln!(self.out, "{}_count {}", name, count);
+    }
+
+    pub fn finish(self) -> String {
+        self.out
+    }
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Serves `GET /metrics` on `addr` from a background thread, rendering a
+/// fresh snapshot with `render` for every scrape.
+pub fn serve(addr: &str, render: impl Fn() -> String + Send + 'static) -> Result<()> {
+    let server = tiny_http::Server::http(addr)
+        .map_err(|e| anyhow::anyhow!("Failed to bind metrics endpoint to {}: {}", addr, e))?;
+    tracing::info!("Serving metrics on http://{}/metrics", addr);
+
+    thread::spawn(move || {
+        for request in server.incoming_requests() {
+            let response = if request.url().split('?').next() == Some("/metrics") {
+                Response::from_string(render()).with_header(
+                    Header::from_bytes("Content-Type", "text/plain; version=0.0.4")
+                        .expect("static header is valid"),
+                )
+            } else {
+                Response::from_string("not found\n").with_status_code(404)
+            };
+            if let Err(e) = request.respond(response) {
+                tracing::warn!(error = %e, "Failed to answer metrics request");
+            }
+        }
+    });
+    Ok(())
+}
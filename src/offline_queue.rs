@@ -0,0 +1,253 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use crate::protocol::KeyEvent;
+
+/// What to do with key events buffered while the server was unreachable,
+/// once the connection comes back.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum OfflinePolicy {
+    /// Discard everything that was buffered.
+    DropAll,
+    /// Send buffered events that are younger than the TTL, discard the rest.
+    DropStale,
+    /// Send everything that was buffered, however old.
+    SendAll,
+}
+
+#[derive(Debug, Clone)]
+pub struct OfflineQueueConfig {
+    pub capacity: usize,
+    pub ttl: Duration,
+    pub policy: OfflinePolicy,
+}
+
+impl Default for OfflineQueueConfig {
+    fn default() -> Self {
+        Self {
+            capacity: 64,
+            ttl: Duration::from_millis(1_000),
+            policy: OfflinePolicy::DropStale,
+        }
+    }
+}
+
+struct QueuedEvent {
+    event: KeyEvent,
+    queued_at: Instant,
+}
+
+/// A bounded buffer of outgoing key events held while disconnected.
+///
+/// When full, the oldest event is evicted to make room for the newest one.
+pub struct OfflineQueue {
+    config: OfflineQueueConfig,
+    events: VecDeque<QueuedEvent>,
+}
+
+impl OfflineQueue {
+    pub fn new(config: OfflineQueueConfig) -> Self {
+        Self {
+            config,
+            events: VecDeque::new(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty()
+    }
+
+    pub fn push(&mut self, event: KeyEvent) {
+        self.push_at(event, Instant::now());
+    }
+
+    fn push_at(&mut self, event: KeyEvent, now: Instant) {
+        if self.config.capacity == 0 {
+            tracing::debug!(
+                key = event.key,
+                "Offline queue disabled; dropping key event"
+            );
+            return;
+        }
+        if self.events.len() >= self.config.capacity
+            && let Some(evicted) = self.events.pop_front()
+        {
+            tracing::warn!(
+                key = evicted.event.key,
+                "Offline queue full; dropping oldest key event"
+            );
+        }
+        self.events.push_back(QueuedEvent {
+            event,
+            queued_at: now,
+        });
+    }
+
+    /// Drops events that can no longer be sent under the configured policy.
+    pub fn expire(&mut self) {
+        self.expire_at(Instant::now());
+    }
+
+    fn expire_at(&mut self, now: Instant) {
+        if self.config.policy != OfflinePolicy::DropStale {
+            return;
+        }
+        let ttl = self.config.ttl;
+        let before = self.events.len();
+        self.events
+            .retain(|queued| now.saturating_duration_since(queued.queued_at) <= ttl);
+        let expired = before - self.events.len();
+        if expired > 0 {
+            tracing::warn!(
+                expired,
+                ttl_ms = ttl.as_millis(),
+                "Dropped stale queued key events"
+            );
+        }
+    }
+
+    /// Sends the queued events through `send` in one go, according to the
+    /// policy. If that fails, the events stay queued.
+    pub fn flush<E>(&mut self, send: impl FnOnce(&[KeyEvent]) -> Result<(), E>) -> Result<(), E> {
+        self.flush_at(send, Instant::now())
+    }
+
+    fn flush_at<E>(
+        &mut self,
+        send: impl FnOnce(&[KeyEvent]) -> Result<(), E>,
+        now: Instant,
+    ) -> Result<(), E> {
+        match self.config.policy {
+            OfflinePolicy::DropAll => {
+                if !self.events.is_empty() {
+                    tracing::warn!(
+                        dropped = self.events.len(),
+                        "Discarding key events queued while offline"
+                    );
+                    self.events.clear();
+                }
+                return Ok(());
+            }
+            OfflinePolicy::DropStale => self.expire_at(now),
+            OfflinePolicy::SendAll => {}
+        }
+
+        if self.events.is_empty() {
+            return Ok(());
+        }
+        tracing::info!(
+            count = self.events.len(),
+            "Sending key events queued while offline"
+        );
+        let events: Vec<KeyEvent> = self.events.iter().map(|q| q.event.clone()).collect();
+        send(&events)?;
+        self.events.clear();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TTL: Duration = Duration::from_millis(1_000);
+
+    fn queue(capacity: usize, policy: OfflinePolicy) -> OfflineQueue {
+        OfflineQueue::new(OfflineQueueConfig {
+            capacity,
+            ttl: TTL,
+            policy,
+        })
+    }
+
+    fn event(id: u64) -> KeyEvent {
+        KeyEvent {
+            key: 30,
+            id,
+            ..Default::default()
+        }
+    }
+
+    /// The ids `flush_at` sends at `now`, or `None` if it sends nothing.
+    fn flushed(queue: &mut OfflineQueue, now: Instant) -> Option<Vec<u64>> {
+        let mut sent = None;
+        queue
+            .flush_at(
+                |events| {
+                    sent = Some(events.iter().map(|event| event.id).collect());
+                    Ok::<_, ()>(())
+                },
+                now,
+            )
+            .unwrap();
+        sent
+    }
+
+    #[test]
+    fn drop_all_discards_everything() {
+        let start = Instant::now();
+        let mut queue = queue(8, OfflinePolicy::DropAll);
+        queue.push_at(event(1), start);
+        queue.push_at(event(2), start);
+        assert_eq!(flushed(&mut queue, start), None);
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn drop_stale_sends_only_events_within_the_ttl() {
+        let start = Instant::now();
+        let mut queue = queue(8, OfflinePolicy::DropStale);
+        queue.push_at(event(1), start);
+        queue.push_at(event(2), start + Duration::from_millis(1));
+        queue.push_at(event(3), start + Duration::from_millis(500));
+        // Event 1 is just over the TTL, event 2 exactly at it.
+        let now = start + TTL + Duration::from_millis(1);
+        assert_eq!(flushed(&mut queue, now), Some(vec![2, 3]));
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn drop_stale_expires_while_offline() {
+        let start = Instant::now();
+        let mut queue = queue(8, OfflinePolicy::DropStale);
+        queue.push_at(event(1), start);
+        queue.expire_at(start + TTL);
+        assert!(!queue.is_empty());
+        queue.expire_at(start + TTL * 2);
+        assert!(queue.is_empty());
+        assert_eq!(flushed(&mut queue, start + TTL * 2), None);
+    }
+
+    #[test]
+    fn send_all_sends_everything_however_old() {
+        let start = Instant::now();
+        let mut queue = queue(8, OfflinePolicy::SendAll);
+        queue.push_at(event(1), start);
+        queue.push_at(event(2), start);
+        queue.expire_at(start + TTL * 60);
+        assert_eq!(flushed(&mut queue, start + TTL * 60), Some(vec![1, 2]));
+    }
+
+    #[test]
+    fn evicts_the_oldest_event_when_full() {
+        let start = Instant::now();
+        let mut queue = queue(2, OfflinePolicy::SendAll);
+        for id in 1..=3 {
+            queue.push_at(event(id), start);
+        }
+        assert_eq!(flushed(&mut queue, start), Some(vec![2, 3]));
+
+        let mut disabled = self::queue(0, OfflinePolicy::SendAll);
+        disabled.push_at(event(1), start);
+        assert!(disabled.is_empty());
+    }
+
+    #[test]
+    fn keeps_events_when_sending_fails() {
+        let start = Instant::now();
+        let mut queue = queue(8, OfflinePolicy::SendAll);
+        queue.push_at(event(1), start);
+        assert!(queue.flush_at(|_| Err(()), start).is_err());
+        assert_eq!(flushed(&mut queue, start), Some(vec![1]));
+    }
+}
@@ -1,18 +1,393 @@
+use flate2::Compression as DeflateLevel;
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
 use serde::{Deserialize, Serialize};
+use std::io::{self, Read, Write};
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+/// Frames larger than this are rejected rather than allocated.
+pub const MAX_FRAME_SIZE: usize = 64 * 1024;
+
+/// Key events per `Batch`, keeping batches well under `MAX_FRAME_SIZE`.
+pub const MAX_BATCH_LEN: usize = 256;
+
+/// The highest key code the kernel defines (`KEY_MAX` in input-event-codes.h).
+pub const KEY_MAX: u16 = 0x2ff;
+
+/// Longest accepted `client_id` or tag, in bytes.
+pub const MAX_CLIENT_ID_LEN: usize = 64;
+/// The channel of clients that don't name one.
+pub const DEFAULT_CHANNEL: &str = "default";
+/// Most tags a client may present.
+pub const MAX_TAGS: usize = 16;
+
+/// Payloads shorter than this aren't worth compressing.
+const COMPRESSION_THRESHOLD: usize = 64;
+
+/// Identifies a client within the server's lifetime. Assigned in the
+/// handshake and kept across resumed sessions, so frames can refer to a
+/// client without repeating its `client_id`.
+pub type PeerId = u32;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
 pub struct KeyEvent {
     pub key: u16,
-    pub client_id: String,
+    /// The sending client. Always set by the server from the connection the
+    /// event arrived on, whatever the client sent, so it can't be spoofed.
+    pub sender: PeerId,
+    /// Position in the server's broadcast order, assigned by the server.
+    /// Clients send 0.
+    pub seq: u64,
+    /// Increasing per sending client, so receivers can drop duplicates.
+    pub id: u64,
+    /// Whether receivers should acknowledge this event.
+    pub ack: bool,
+    /// When the key was pressed, from the evdev event. Like all timestamps
+    /// on the wire, in microseconds since the Unix epoch on the server's clock.
+    pub timestamp_us: u64,
+    /// When the sender wrote this event to the server.
+    pub sent_at_us: u64,
+    /// When receivers should press the key. `None` presses it on arrival.
+    pub execute_at_us: Option<u64>,
 }
 
 impl KeyEvent {
-    pub fn to_payload(&self) -> Result<Vec<u8>, bitcode::Error> {
-        // TODO: compression?
-        bitcode::serialize(self)
+    /// Checks what the server can't fill in itself.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.key > KEY_MAX {
+            return Err(format!("key code {} is out of range", self.key));
+        }
+        if self.id == 0 {
+            return Err("event id must not be 0".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// Sent by clients to estimate their clock offset to the server.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Ping {
+    /// The client's local clock when sending.
+    pub sent_us: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Pong {
+    /// Echoed from the `Ping`.
+    pub sent_us: u64,
+    /// The server's clock when it answered.
+    pub server_us: u64,
+}
+
+/// Tells the sender of an acknowledged event which clients it went out to.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Receipt {
+    pub id: u64,
+    pub recipients: Vec<PeerId>,
+}
+
+/// Sent by a receiver for an event with `ack` set; relayed to the sender.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Ack {
+    /// The event's sender.
+    pub sender: PeerId,
+    pub id: u64,
+    /// Filled in by the server.
+    pub receiver: PeerId,
+}
+
+/// Announces the `client_id` behind a `PeerId`. The server sends one for every
+/// known client after `Welcome`, and to everyone when a new client joins.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Peer {
+    pub id: PeerId,
+    pub client_id: String,
+}
+
+/// How messages are serialized. The server speaks both, translating between
+/// clients, and picks up a connection's codec from the first byte it sends.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, clap::ValueEnum)]
+pub enum Codec {
+    /// Compact binary frames, each prefixed with its length.
+    #[default]
+    Bitcode,
+    /// One JSON message per line, e.g. `{"Ping":{"sent_us":0}}`. Handy for
+    /// debugging with `nc`, or for clients written in other languages.
+    Json,
+}
+
+impl Codec {
+    /// Guesses the codec from the first byte a client sent: a length prefix
+    /// never starts with anything but 0, as frames are at most 64KiB.
+    pub fn detect(first_byte: u8) -> Self {
+        if first_byte == b'{' || first_byte.is_ascii_whitespace() {
+            Codec::Json
+        } else {
+            Codec::Bitcode
+        }
+    }
+}
+
+/// Compression of bitcode frames. JSON is never compressed.
+#[derive(
+    Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, Hash, clap::ValueEnum,
+)]
+pub enum Compression {
+    #[default]
+    None,
+    Deflate,
+}
+
+/// Sent by the client as the first message on every connection.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Hello {
+    pub client_id: String,
+    /// Present when reconnecting, to receive what was broadcast while away.
+    pub resume: Option<Resume>,
+    /// Compression the client supports, in order of preference.
+    #[serde(default)]
+    pub compression: Vec<Compression>,
+    /// Free-form labels for operators, e.g. the machine's role.
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// Only clients in the same channel exchange key events. `None` is
+    /// `DEFAULT_CHANNEL`.
+    #[serde(default)]
+    pub channel: Option<String>,
+    /// One of the server's auth secrets, if it requires one.
+    #[serde(default)]
+    pub secret: Option<String>,
+}
+
+impl Hello {
+    pub fn validate(&self) -> Result<(), String> {
+        validate_name("client_id", &self.client_id)?;
+        if self.tags.len() > MAX_TAGS {
+            return Err(format!("at most {} tags are allowed", MAX_TAGS));
+        }
+        if let Some(channel) = &self.channel {
+            validate_name("channel", channel)?;
+        }
+        self.tags
+            .iter()
+            .try_for_each(|tag| validate_name("tag", tag))
+    }
+
+    pub fn channel(&self) -> &str {
+        self.channel.as_deref().unwrap_or(DEFAULT_CHANNEL)
     }
+}
+
+fn validate_name(what: &str, name: &str) -> Result<(), String> {
+    if name.is_empty() || name.len() > MAX_CLIENT_ID_LEN {
+        return Err(format!(
+            "{} must be 1 to {} bytes long",
+            what, MAX_CLIENT_ID_LEN
+        ));
+    }
+    if name.chars().any(char::is_control) {
+        return Err(format!("{} must not contain control characters", what));
+    }
+    Ok(())
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Resume {
+    pub session_token: String,
+    /// The `seq` of the last key event received before the disconnect.
+    pub last_seq: u64,
+}
+
+/// The server's reply to `Hello`. `Peer` announcements and any replayed key
+/// events follow it, encoded with the negotiated `compression`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Welcome {
+    pub session_token: String,
+    pub peer_id: PeerId,
+    pub resumed: bool,
+    pub replayed: u32,
+    pub compression: Compression,
+}
+
+/// Sent by either side just before it closes the connection on purpose.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Goodbye {
+    pub reason: String,
+    /// From the server: how long clients should wait before reconnecting.
+    #[serde(default)]
+    pub retry_after_ms: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum Message {
+    Hello(Hello),
+    Welcome(Welcome),
+    Key(KeyEvent),
+    Receipt(Receipt),
+    Ack(Ack),
+    Ping(Ping),
+    Pong(Pong),
+    Peer(Peer),
+    /// Key events produced within a short window, sent as one frame.
+    Batch(Vec<KeyEvent>),
+    Goodbye(Goodbye),
+}
+
+/// How messages are encoded on one connection. The handshake is always
+/// uncompressed; everything after it uses what the handshake negotiated.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct Wire {
+    pub codec: Codec,
+    pub compression: Compression,
+}
+
+impl Wire {
+    /// The encoding of a connection's handshake.
+    pub fn handshake(codec: Codec) -> Self {
+        Wire {
+            codec,
+            compression: Compression::None,
+        }
+    }
+
+    /// Encodes `message` as a complete frame, ready to be written.
+    pub fn encode(&self, message: &Message) -> io::Result<Vec<u8>> {
+        if self.codec == Codec::Json {
+            let mut line = serde_json::to_vec(message)?;
+            line.push(b'\n');
+            return Ok(line);
+        }
+
+        let encoded = bitcode::serialize(message)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let payload = match self.compression {
+            Compression::None => encoded,
+            Compression::Deflate => {
+                // A leading flag byte says whether this payload was worth deflating.
+                let mut payload = vec![0];
+                if encoded.len() >= COMPRESSION_THRESHOLD {
+                    let mut encoder = DeflateEncoder::new(vec![1], DeflateLevel::fast());
+                    encoder.write_all(&encoded)?;
+                    let deflated = encoder.finish()?;
+                    if deflated.len() < encoded.len() {
+                        payload = deflated;
+                    } else {
+                        payload.extend_from_slice(&encoded);
+                    }
+                } else {
+                    payload.extend_from_slice(&encoded);
+                }
+                payload
+            }
+        };
+        frame(&payload)
+    }
+
+    /// Reads one frame, returning its payload for `decode`.
+    pub fn read_frame<R: Read>(&self, reader: &mut R) -> io::Result<Vec<u8>> {
+        match self.codec {
+            Codec::Bitcode => read_frame(reader),
+            Codec::Json => read_line(reader),
+        }
+    }
+
+    /// Decodes a frame payload read with `read_frame`.
+    pub fn decode(&self, payload: &[u8]) -> io::Result<Message> {
+        if self.codec == Codec::Json {
+            return Ok(serde_json::from_slice(payload)?);
+        }
+
+        let invalid = |e: String| io::Error::new(io::ErrorKind::InvalidData, e);
+        let inflated;
+        let encoded = match self.compression {
+            Compression::None => payload,
+            Compression::Deflate => match payload.split_first() {
+                Some((0, rest)) => rest,
+                Some((1, rest)) => {
+                    let mut buf = Vec::new();
+                    DeflateDecoder::new(rest)
+                        .take(MAX_FRAME_SIZE as u64 + 1)
+                        .read_to_end(&mut buf)?;
+                    if buf.len() > MAX_FRAME_SIZE {
+                        return Err(invalid("decompressed frame is too large".to_string()));
+                    }
+                    inflated = buf;
+                    &inflated
+                }
+                _ => return Err(invalid("invalid compression flag".to_string())),
+            },
+        };
+        bitcode::deserialize(encoded).map_err(|e| invalid(e.to_string()))
+    }
+
+    pub fn write_message<W: Write>(&self, writer: &mut W, message: &Message) -> io::Result<()> {
+        writer.write_all(&self.encode(message)?)
+    }
+
+    /// Reads one frame and decodes it; for the handshake, where any failure
+    /// ends the connection.
+    pub fn read_message<R: Read>(&self, reader: &mut R) -> io::Result<Message> {
+        self.decode(&self.read_frame(reader)?)
+    }
+}
+
+/// Prefixes `payload` with its length as a big-endian u32.
+///
+/// Frames go out in a single write so that frames from different writers
+/// sharing a socket can't interleave.
+fn frame(payload: &[u8]) -> io::Result<Vec<u8>> {
+    if payload.len() > MAX_FRAME_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "frame of {} bytes exceeds maximum of {}",
+                payload.len(),
+                MAX_FRAME_SIZE
+            ),
+        ));
+    }
+    let mut frame = Vec::with_capacity(4 + payload.len());
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    frame.extend_from_slice(payload);
+    Ok(frame)
+}
+
+/// Reads one length-prefixed frame, returning its payload.
+fn read_frame<R: Read>(reader: &mut R) -> io::Result<Vec<u8>> {
+    let mut header = [0; 4];
+    reader.read_exact(&mut header)?;
+    let len = u32::from_be_bytes(header) as usize;
+    if len > MAX_FRAME_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "frame of {} bytes exceeds maximum of {}",
+                len, MAX_FRAME_SIZE
+            ),
+        ));
+    }
+    let mut payload = vec![0; len];
+    reader.read_exact(&mut payload)?;
+    Ok(payload)
+}
 
-    pub fn from_slice(slice: &[u8]) -> Result<Self, bitcode::Error> {
-        bitcode::deserialize(slice)
+/// Reads one non-blank line, without the newline. Reads a byte at a time, so
+/// nothing past the line is consumed; JSON is for debugging, not throughput.
+fn read_line<R: Read>(reader: &mut R) -> io::Result<Vec<u8>> {
+    let mut line = Vec::new();
+    let mut byte = [0];
+    loop {
+        reader.read_exact(&mut byte)?;
+        match byte[0] {
+            b'\n' if line.iter().all(u8::is_ascii_whitespace) => line.clear(),
+            b'\n' => return Ok(line),
+            _ if line.len() >= MAX_FRAME_SIZE => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("line exceeds maximum of {} bytes", MAX_FRAME_SIZE),
+                ));
+            }
+            b => line.push(b),
+        }
     }
 }
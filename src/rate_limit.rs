@@ -0,0 +1,297 @@
+use std::collections::HashMap;
+use std::time::Instant;
+
+use crate::protocol::KeyEvent;
+
+/// A client is disconnected once this many of its events have been dropped
+/// within a minute.
+const MAX_RATE_LIMITED_PER_MINUTE: u32 = 100;
+
+/// A sustained rate with some room for bursts.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Rate {
+    /// Tokens added per second; 0 disables the limit.
+    pub per_sec: f64,
+    /// Most tokens the bucket can hold.
+    pub burst: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimitConfig {
+    /// Key events from one client.
+    pub client: Rate,
+    /// Key events from one client for the same key code.
+    pub key: Rate,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            client: Rate {
+                per_sec: 100.0,
+                burst: 200,
+            },
+            key: Rate {
+                per_sec: 20.0,
+                burst: 40,
+            },
+        }
+    }
+}
+
+struct TokenBucket {
+    rate: Rate,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: Rate, now: Instant) -> Self {
+        Self {
+            rate,
+            tokens: rate.burst as f64,
+            updated: now,
+        }
+    }
+
+    fn set_rate(&mut self, rate: Rate) {
+        self.rate = rate;
+        self.tokens = self.tokens.min(rate.burst as f64);
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate.per_sec).min(self.rate.burst as f64);
+        self.updated = now;
+    }
+
+    fn has_token(&mut self, now: Instant) -> bool {
+        self.rate.per_sec <= 0.0 || {
+            self.refill(now);
+            self.tokens >= 1.0
+        }
+    }
+
+    fn is_full(&mut self, now: Instant) -> bool {
+        self.rate.per_sec <= 0.0 || {
+            self.refill(now);
+            self.tokens >= self.rate.burst as f64
+        }
+    }
+
+    fn take(&mut self) {
+        if self.rate.per_sec > 0.0 {
+            self.tokens -= 1.0;
+        }
+    }
+}
+
+/// Token buckets for the key events of one client: one for the client as a
+/// whole and one per key code, so a stuck key is cut off well before it uses
+/// up the client's budget. Kept across reconnects, so that reconnecting
+/// doesn't refill them.
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    client: TokenBucket,
+    keys: HashMap<u16, TokenBucket>,
+    /// Drained by dropped events; once empty, the client is a flood.
+    tolerance: TokenBucket,
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig) -> Self {
+        Self::new_at(config, Instant::now())
+    }
+
+    fn new_at(config: RateLimitConfig, now: Instant) -> Self {
+        Self {
+            config,
+            client: TokenBucket::new(config.client, now),
+            keys: HashMap::new(),
+            tolerance: TokenBucket::new(
+                Rate {
+                    per_sec: MAX_RATE_LIMITED_PER_MINUTE as f64 / 60.0,
+                    burst: MAX_RATE_LIMITED_PER_MINUTE,
+                },
+                now,
+            ),
+        }
+    }
+
+    /// Switches to new limits, keeping the tokens already in the buckets.
+    pub fn reconfigure(&mut self, config: RateLimitConfig) {
+        if config == self.config {
+            return;
+        }
+        self.config = config;
+        self.client.set_rate(config.client);
+        for bucket in self.keys.values_mut() {
+            bucket.set_rate(config.key);
+        }
+    }
+
+    /// Removes the events that are over the limit, returning how many were
+    /// removed.
+    pub fn admit(&mut self, events: &mut Vec<KeyEvent>) -> usize {
+        self.admit_at(events, Instant::now())
+    }
+
+    fn admit_at(&mut self, events: &mut Vec<KeyEvent>, now: Instant) -> usize {
+        let before = events.len();
+        events.retain(|event| {
+            let key = self
+                .keys
+                .entry(event.key)
+                .or_insert_with(|| TokenBucket::new(self.config.key, now));
+            if key.has_token(now) && self.client.has_token(now) {
+                key.take();
+                self.client.take();
+                true
+            } else {
+                false
+            }
+        });
+        before - events.len()
+    }
+
+    /// Whether every bucket has filled up again, so that forgetting this
+    /// client would make no difference.
+    pub fn is_rested(&mut self) -> bool {
+        self.is_rested_at(Instant::now())
+    }
+
+    fn is_rested_at(&mut self, now: Instant) -> bool {
+        self.client.is_full(now)
+            && self.tolerance.is_full(now)
+            && self.keys.values_mut().all(|bucket| bucket.is_full(now))
+    }
+
+    /// Counts `dropped` events against the client, returning false once it
+    /// has had too many dropped recently.
+    pub fn tolerate(&mut self, dropped: usize) -> bool {
+        self.tolerate_at(dropped, Instant::now())
+    }
+
+    fn tolerate_at(&mut self, dropped: usize, now: Instant) -> bool {
+        (0..dropped).all(|_| {
+            let ok = self.tolerance.has_token(now);
+            self.tolerance.take();
+            ok
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn events(keys: &[u16]) -> Vec<KeyEvent> {
+        keys.iter()
+            .map(|&key| KeyEvent {
+                key,
+                ..Default::default()
+            })
+            .collect()
+    }
+
+    fn config(client: (f64, u32), key: (f64, u32)) -> RateLimitConfig {
+        RateLimitConfig {
+            client: Rate {
+                per_sec: client.0,
+                burst: client.1,
+            },
+            key: Rate {
+                per_sec: key.0,
+                burst: key.1,
+            },
+        }
+    }
+
+    #[test]
+    fn admits_a_burst_then_refills_over_time() {
+        let start = Instant::now();
+        let mut limiter = RateLimiter::new_at(config((10.0, 5), (100.0, 100)), start);
+
+        let mut batch = events(&[1, 2, 3, 4, 5, 6, 7]);
+        assert_eq!(limiter.admit_at(&mut batch, start), 2);
+        assert_eq!(batch.len(), 5);
+
+        // 10 per second: one token every 100ms.
+        let mut batch = events(&[1, 2]);
+        assert_eq!(
+            limiter.admit_at(&mut batch, start + Duration::from_millis(100)),
+            1
+        );
+
+        // Never more than the burst, however long the client was idle.
+        let mut batch = events(&[1; 8]);
+        let later = start + Duration::from_secs(60);
+        assert_eq!(limiter.admit_at(&mut batch, later), 8 - 5);
+    }
+
+    #[test]
+    fn limits_each_key_separately() {
+        let start = Instant::now();
+        let mut limiter = RateLimiter::new_at(config((100.0, 100), (1.0, 2)), start);
+
+        let mut batch = events(&[30, 30, 30, 31, 31]);
+        assert_eq!(limiter.admit_at(&mut batch, start), 1);
+        assert_eq!(batch.iter().filter(|event| event.key == 30).count(), 2);
+        assert_eq!(batch.iter().filter(|event| event.key == 31).count(), 2);
+    }
+
+    #[test]
+    fn a_zero_rate_disables_the_limit() {
+        let start = Instant::now();
+        let mut limiter = RateLimiter::new_at(config((0.0, 0), (0.0, 0)), start);
+        let mut batch = events(&[1; 1000]);
+        assert_eq!(limiter.admit_at(&mut batch, start), 0);
+    }
+
+    #[test]
+    fn reconfigure_caps_tokens_at_the_new_burst() {
+        let start = Instant::now();
+        let mut limiter = RateLimiter::new_at(config((10.0, 50), (100.0, 100)), start);
+        limiter.reconfigure(config((10.0, 3), (100.0, 100)));
+        let mut batch = events(&[1; 5]);
+        assert_eq!(limiter.admit_at(&mut batch, start), 2);
+    }
+
+    #[test]
+    fn disconnects_after_too_many_drops_per_minute() {
+        let start = Instant::now();
+        let mut limiter = RateLimiter::new_at(RateLimitConfig::default(), start);
+        let max = MAX_RATE_LIMITED_PER_MINUTE as usize;
+
+        assert!(limiter.tolerate_at(max - 1, start));
+        assert!(limiter.tolerate_at(1, start));
+        assert!(!limiter.tolerate_at(1, start));
+
+        // The allowance comes back over a minute.
+        let mut limiter = RateLimiter::new_at(RateLimitConfig::default(), start);
+        assert!(limiter.tolerate_at(max, start));
+        assert!(limiter.tolerate_at(max / 2, start + Duration::from_secs(31)));
+        assert!(!limiter.tolerate_at(max, start + Duration::from_secs(32)));
+    }
+
+    #[test]
+    fn rested_once_every_bucket_is_full() {
+        let start = Instant::now();
+        let mut limiter = RateLimiter::new_at(config((10.0, 5), (1.0, 2)), start);
+        assert!(limiter.is_rested_at(start));
+
+        let mut batch = events(&[1, 1]);
+        limiter.admit_at(&mut batch, start);
+        assert!(!limiter.is_rested_at(start));
+        // The client bucket is full again after 200ms, the key's after 2s.
+        assert!(!limiter.is_rested_at(start + Duration::from_secs(1)));
+        assert!(limiter.is_rested_at(start + Duration::from_secs(2)));
+
+        // A dropped event comes back after 0.6s.
+        let later = start + Duration::from_secs(2);
+        assert!(limiter.tolerate_at(1, later));
+        assert!(!limiter.is_rested_at(later));
+        assert!(limiter.is_rested_at(later + Duration::from_secs(1)));
+    }
+}
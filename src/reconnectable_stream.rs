@@ -1,43 +1,223 @@
 use std::io::{self, Read, Write};
-use std::net::{TcpStream, ToSocketAddrs};
+use std::net::{Shutdown, SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, mpsc};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use anyhow::{Context, Result};
+use rand::Rng;
 
-const INITIAL_BACKOFF_MS: u64 = 50;
-const MAX_BACKOFF_MS: u64 = 10_000;
-const CONNECTION_TIMEOUT_SECS: u64 = 5;
+use crate::protocol::{Codec, Compression, Hello, Message, PeerId, Resume, Welcome, Wire};
 
+const HANDSHAKE_TIMEOUT_SECS: u64 = 5;
+
+/// Controls how `ReconnectableTcpStream::reconnect` retries.
+#[derive(Debug, Clone)]
+pub struct ReconnectPolicy {
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    /// Give up after this many failed attempts. `None` retries forever.
+    pub max_attempts: Option<u32>,
+    /// How long to wait for a server to accept a connection.
+    pub connect_timeout: Duration,
+}
+
+impl ReconnectPolicy {
+    /// The delay before attempt number `attempt` (from 1), before jitter:
+    /// doubling from `initial_backoff`, but never above `max_backoff`.
+    fn backoff(&self, attempt: u32) -> Duration {
+        let doublings = attempt.saturating_sub(1).min(31);
+        self.initial_backoff
+            .saturating_mul(1 << doublings)
+            .min(self.max_backoff)
+    }
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self {
+            initial_backoff: Duration::from_millis(50),
+            max_backoff: Duration::from_millis(10_000),
+            max_attempts: None,
+            connect_timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionState {
+    Connected,
+    Disconnected,
+    Reconnecting {
+        attempt: u32,
+    },
+    /// The reconnect policy was exhausted; the stream will not reconnect again.
+    GaveUp,
+    /// Closed on purpose with `close`; the stream will not reconnect again.
+    Closed,
+}
+
+struct Connection {
+    stream: Option<TcpStream>,
+    /// How messages are encoded on `stream`, as negotiated in its handshake.
+    wire: Wire,
+    // Bumped on every successful (re)connect, so handles sharing this
+    // connection can tell whether somebody else already reconnected.
+    generation: u64,
+    state: ConnectionState,
+    listeners: Vec<mpsc::Sender<ConnectionState>>,
+}
+
+impl Connection {
+    fn set_state(&mut self, state: ConnectionState) {
+        if self.state == state {
+            return;
+        }
+        self.state = state;
+        self.listeners.retain(|tx| tx.send(state).is_ok());
+    }
+}
+
+/// How the client introduces itself to the server.
+#[derive(Debug, Clone, Default)]
+pub struct ClientIdentity {
+    pub client_id: String,
+    /// Labels shown to server operators.
+    pub tags: Vec<String>,
+    /// The channel to join; the server's default if unset.
+    pub channel: Option<String>,
+    /// Presented to servers that require an auth secret.
+    pub secret: Option<String>,
+}
+
+/// What the server needs to resume this client's session on reconnect.
+struct Session {
+    identity: ClientIdentity,
+    codec: Codec,
+    /// Offered to the server in every handshake.
+    compression: Vec<Compression>,
+    token: Option<String>,
+    peer_id: Option<PeerId>,
+    last_seq: u64,
+}
+
+impl Session {
+    /// Takes on the session the server agreed to in `welcome`.
+    fn welcomed(&mut self, welcome: Welcome) {
+        if self.peer_id.is_some_and(|id| id != welcome.peer_id) {
+            tracing::warn!(peer_id = welcome.peer_id, "Server assigned a new peer id");
+        }
+        if !welcome.resumed {
+            // A new session starts a new sequence, e.g. after a server
+            // restart; an old `last_seq` would hide everything up to it.
+            self.last_seq = 0;
+        }
+        self.token = Some(welcome.session_token);
+        self.peer_id = Some(welcome.peer_id);
+    }
+}
+
+struct Shared {
+    /// Tried in order on every attempt.
+    server_addrs: Vec<String>,
+    policy: ReconnectPolicy,
+    session: Mutex<Session>,
+    connection: Mutex<Connection>,
+    // Serializes reconnect attempts between handles.
+    reconnect_lock: Mutex<()>,
+    reconnect_attempts: AtomicU64,
+    /// The server asked us not to come back before this.
+    retry_not_before: Mutex<Option<Instant>>,
+}
+
+/// Observes a `ReconnectableTcpStream` without holding a socket, e.g. for
+/// metrics.
+#[derive(Clone)]
+pub struct StreamMonitor {
+    shared: Arc<Shared>,
+}
+
+impl StreamMonitor {
+    pub fn state(&self) -> ConnectionState {
+        self.shared.connection.lock().unwrap().state
+    }
+
+    /// Reconnection attempts made so far, successful or not.
+    pub fn reconnect_attempts(&self) -> u64 {
+        self.shared.reconnect_attempts.load(Ordering::Relaxed)
+    }
+}
+
+/// A TCP stream to the server that can be re-established on demand.
+///
+/// `Read` and `Write` never reconnect on their own: an error or EOF marks the
+/// connection as disconnected and is returned to the caller, who decides when
+/// to call `reconnect`. Handles created by `try_clone` share one underlying
+/// connection, so a reconnect through any handle is picked up by the others
+/// on their next read or write.
+///
+/// Every connection starts with a `Hello`/`Welcome` handshake. After a
+/// reconnect, the handshake presents the previous session token and the last
+/// received sequence number, and the server follows its `Welcome` with any key
+/// events that were missed. The handshake also negotiates the `Wire` that
+/// later messages on the connection are encoded with.
 pub struct ReconnectableTcpStream {
+    shared: Arc<Shared>,
     stream: Option<TcpStream>,
-    server_addr: String,
-    current_backoff: Duration,
+    wire: Wire,
+    generation: u64,
 }
 
 impl ReconnectableTcpStream {
-    pub fn new<A: ToSocketAddrs>(server_addr: A) -> Result<Self> {
-        // Convert to string for storage and future reconnects
-        let addr_str = match server_addr.to_socket_addrs()?.next() {
-            Some(addr) => addr.to_string(),
-            None => return Err(anyhow::anyhow!("Invalid server address")),
-        };
+    pub fn new(
+        server_addrs: &[String],
+        policy: ReconnectPolicy,
+        identity: ClientIdentity,
+        codec: Codec,
+        compression: Compression,
+    ) -> Result<Self> {
+        tracing::info!(server_addrs = ?server_addrs, "Connecting to server");
 
-        tracing::info!(server_addr = %addr_str, "Connecting to server");
+        let session = Mutex::new(Session {
+            identity,
+            codec,
+            compression: match compression {
+                Compression::None => Vec::new(),
+                other => vec![other],
+            },
+            token: None,
+            peer_id: None,
+            last_seq: 0,
+        });
+        let (stream, wire) = establish(server_addrs, policy.connect_timeout, &session).context(
+            format!("Failed to connect to server at {}", server_addrs.join(", ")),
+        )?;
 
-        // Set a timeout for the initial connection
-        let stream = TcpStream::connect_timeout(
-            &addr_str.parse().context("Failed to parse server address")?,
-            Duration::from_secs(CONNECTION_TIMEOUT_SECS),
-        )
-        .context(format!("Failed to connect to server at {}", addr_str))?;
+        tracing::info!(peer = ?stream.peer_addr().ok(), "Connected to server");
 
-        tracing::info!(server_addr = %addr_str, "Connected to server");
+        let handle_stream = stream.try_clone().context("Failed to clone stream")?;
+        let shared = Shared {
+            server_addrs: server_addrs.to_vec(),
+            policy,
+            session,
+            connection: Mutex::new(Connection {
+                stream: Some(stream),
+                wire,
+                generation: 0,
+                state: ConnectionState::Connected,
+                listeners: Vec::new(),
+            }),
+            reconnect_lock: Mutex::new(()),
+            reconnect_attempts: AtomicU64::new(0),
+            retry_not_before: Mutex::new(None),
+        };
 
         Ok(Self {
-            stream: Some(stream),
-            server_addr: addr_str,
-            current_backoff: Duration::from_millis(INITIAL_BACKOFF_MS),
+            shared: Arc::new(shared),
+            stream: Some(handle_stream),
+            wire,
+            generation: 0,
         })
     }
 
@@ -48,34 +228,166 @@ impl ReconnectableTcpStream {
         };
 
         Ok(Self {
+            shared: Arc::clone(&self.shared),
             stream: cloned_stream,
-            server_addr: self.server_addr.clone(),
-            current_backoff: self.current_backoff,
+            wire: self.wire,
+            generation: self.generation,
         })
     }
 
-    fn reconnect(&mut self) -> io::Result<()> {
-        // Try to reconnect with exponential backoff
+    pub fn state(&self) -> ConnectionState {
+        self.shared.connection.lock().unwrap().state
+    }
+
+    pub fn monitor(&self) -> StreamMonitor {
+        StreamMonitor {
+            shared: Arc::clone(&self.shared),
+        }
+    }
+
+    /// Records that the key event with sequence number `seq` was received, so
+    /// a resumed session only replays what came after it.
+    pub fn record_received(&self, seq: u64) {
+        let mut session = self.shared.session.lock().unwrap();
+        session.last_seq = session.last_seq.max(seq);
+    }
+
+    /// How messages on this handle's connection are encoded; use it to decode
+    /// frames read from this handle.
+    pub fn wire(&self) -> Wire {
+        self.wire
+    }
+
+    /// Reads the next frame; decode it with `wire()`.
+    pub fn read_frame(&mut self) -> io::Result<Vec<u8>> {
+        self.current_stream()?;
+        let wire = self.wire;
+        wire.read_frame(self)
+    }
+
+    /// Encodes `message` for the current connection and writes it as one frame.
+    pub fn write_message(&mut self, message: &Message) -> io::Result<()> {
+        self.current_stream()?;
+        let frame = self.wire.encode(message)?;
+        self.write_all(&frame)
+    }
+
+    /// Holds off the next reconnect until `retry_after` from now, plus up to
+    /// half as long again so that clients told the same thing spread out.
+    pub fn defer_reconnect(&self, retry_after: Duration) {
+        let spread = (retry_after / 2).mul_f64(rand::rng().random::<f64>());
+        *self.shared.retry_not_before.lock().unwrap() = Some(Instant::now() + retry_after + spread);
+    }
+
+    /// Returns a channel that receives every subsequent connection state change.
+    pub fn subscribe(&self) -> mpsc::Receiver<ConnectionState> {
+        let (tx, rx) = mpsc::channel();
+        self.shared.connection.lock().unwrap().listeners.push(tx);
+        rx
+    }
+
+    /// Shuts the connection down for good: every handle's reads and writes
+    /// fail from now on, and `reconnect` gives up right away.
+    pub fn close(&mut self) {
+        self.stream = None;
+        let mut conn = self.shared.connection.lock().unwrap();
+        if let Some(stream) = conn.stream.take() {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+        conn.set_state(ConnectionState::Closed);
+    }
+
+    /// Marks the connection as lost and shuts the socket down, which also
+    /// wakes up any other handle blocked reading from it.
+    fn mark_disconnected(&mut self) {
+        self.stream = None;
+        let mut conn = self.shared.connection.lock().unwrap();
+        if conn.generation != self.generation {
+            // Somebody already replaced the connection we were using.
+            return;
+        }
+        if let Some(stream) = conn.stream.take() {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+        if conn.state == ConnectionState::Connected {
+            conn.set_state(ConnectionState::Disconnected);
+        }
+    }
+
+    /// Re-establishes the connection, resolving the server address again on
+    /// every attempt. If another handle has reconnected in the meantime, this
+    /// adopts its connection instead of opening a new one.
+    pub fn reconnect(&mut self) -> io::Result<()> {
+        self.mark_disconnected();
+        let shared = Arc::clone(&self.shared);
+        let _guard = shared.reconnect_lock.lock().unwrap();
+
+        if self.adopt_current()? {
+            return Ok(());
+        }
+
+        let policy = &shared.policy;
         let mut attempt = 1;
 
         loop {
+            if policy.max_attempts.is_some_and(|max| attempt > max) {
+                shared
+                    .connection
+                    .lock()
+                    .unwrap()
+                    .set_state(ConnectionState::GaveUp);
+                return Err(io::Error::new(
+                    io::ErrorKind::NotConnected,
+                    format!("Gave up reconnecting after {} attempts", attempt - 1),
+                ));
+            }
+
+            {
+                let mut conn = shared.connection.lock().unwrap();
+                if conn.state == ConnectionState::Closed {
+                    return Err(io::Error::new(
+                        io::ErrorKind::NotConnected,
+                        "Connection was closed",
+                    ));
+                }
+                conn.set_state(ConnectionState::Reconnecting { attempt });
+            }
+            shared.reconnect_attempts.fetch_add(1, Ordering::Relaxed);
+
+            let mut delay = jittered(policy.backoff(attempt));
+            if let Some(not_before) = shared.retry_not_before.lock().unwrap().take() {
+                delay = delay.max(not_before.saturating_duration_since(Instant::now()));
+            }
             tracing::warn!(
                 attempt = attempt,
-                backoff_ms = self.current_backoff.as_millis(),
+                backoff_ms = delay.as_millis(),
                 "Connection lost; reconnecting"
             );
+            thread::sleep(delay);
 
-            thread::sleep(self.current_backoff);
-
-            match TcpStream::connect_timeout(
-                &self.server_addr.parse().unwrap(),
-                Duration::from_secs(CONNECTION_TIMEOUT_SECS),
+            match establish(
+                &shared.server_addrs,
+                policy.connect_timeout,
+                &shared.session,
             ) {
-                Ok(stream) => {
-                    tracing::info!(server_addr = %self.server_addr, "Reconnected to server successfully");
-                    self.stream = Some(stream);
-                    // Reset backoff on success
-                    self.current_backoff = Duration::from_millis(INITIAL_BACKOFF_MS);
+                Ok((stream, wire)) => {
+                    tracing::info!(peer = ?stream.peer_addr().ok(), "Reconnected to server successfully");
+                    let handle_stream = stream.try_clone()?;
+                    let mut conn = shared.connection.lock().unwrap();
+                    if conn.state == ConnectionState::Closed {
+                        let _ = stream.shutdown(Shutdown::Both);
+                        return Err(io::Error::new(
+                            io::ErrorKind::NotConnected,
+                            "Connection was closed",
+                        ));
+                    }
+                    conn.stream = Some(stream);
+                    conn.wire = wire;
+                    conn.generation += 1;
+                    conn.set_state(ConnectionState::Connected);
+                    self.generation = conn.generation;
+                    self.stream = Some(handle_stream);
+                    self.wire = wire;
                     return Ok(());
                 }
                 Err(e) => {
@@ -84,43 +396,165 @@ impl ReconnectableTcpStream {
                         error = %e,
                         "Reconnection attempt failed"
                     );
-                    // Increase backoff exponentially (2x), capped at max_backoff
-                    self.current_backoff = Duration::from_millis(
-                        (self.current_backoff.as_millis() as u64 * 2).min(MAX_BACKOFF_MS),
-                    );
                     attempt += 1;
                 }
             }
         }
     }
+
+    /// Switches to the shared connection if it is newer than the one this
+    /// handle used. Returns whether it did.
+    fn adopt_current(&mut self) -> io::Result<bool> {
+        let conn = self.shared.connection.lock().unwrap();
+        match conn.state {
+            ConnectionState::GaveUp => {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotConnected,
+                    "Reconnection was given up",
+                ));
+            }
+            ConnectionState::Closed => {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotConnected,
+                    "Connection was closed",
+                ));
+            }
+            _ => {}
+        }
+        match &conn.stream {
+            Some(stream) if conn.generation != self.generation => {
+                self.stream = Some(stream.try_clone()?);
+                self.wire = conn.wire;
+                self.generation = conn.generation;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    /// Returns this handle's socket, first switching over to a connection
+    /// that another handle has re-established since.
+    fn current_stream(&mut self) -> io::Result<&mut TcpStream> {
+        self.adopt_current()?;
+        self.stream
+            .as_mut()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotConnected, "Not connected to server"))
+    }
+}
+
+/// Resolves `server_addr` and connects to the first address that accepts.
+fn connect(server_addr: &str, timeout: Duration) -> io::Result<TcpStream> {
+    let addrs: Vec<SocketAddr> = server_addr.to_socket_addrs()?.collect();
+    let mut last_err = io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("No addresses resolved for {}", server_addr),
+    );
+    for addr in addrs {
+        match TcpStream::connect_timeout(&addr, timeout) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_err = e,
+        }
+    }
+    Err(last_err)
+}
+
+/// Connects to the first of `server_addrs` that accepts and welcomes this
+/// client, and performs the session handshake.
+fn establish(
+    server_addrs: &[String],
+    timeout: Duration,
+    session: &Mutex<Session>,
+) -> io::Result<(TcpStream, Wire)> {
+    let mut last_err = io::Error::new(io::ErrorKind::InvalidInput, "No server address given");
+    for server_addr in server_addrs {
+        let result = connect(server_addr, timeout).and_then(|mut stream| {
+            let wire = handshake(&mut stream, session)?;
+            Ok((stream, wire))
+        });
+        match result {
+            Ok(connected) => return Ok(connected),
+            Err(e) => {
+                if server_addrs.len() > 1 {
+                    tracing::warn!(server_addr = %server_addr, error = %e, "Failed to connect; trying the next server");
+                }
+                last_err = e;
+            }
+        }
+    }
+    Err(last_err)
+}
+
+/// Introduces this client on a fresh connection, resuming the previous
+/// session if there is one. Returns the negotiated encoding.
+fn handshake(stream: &mut TcpStream, session: &Mutex<Session>) -> io::Result<Wire> {
+    let (codec, hello) = {
+        let session = session.lock().unwrap();
+        let identity = &session.identity;
+        let hello = Hello {
+            client_id: identity.client_id.clone(),
+            resume: session.token.clone().map(|session_token| Resume {
+                session_token,
+                last_seq: session.last_seq,
+            }),
+            compression: session.compression.clone(),
+            tags: identity.tags.clone(),
+            channel: identity.channel.clone(),
+            secret: identity.secret.clone(),
+        };
+        (session.codec, hello)
+    };
+
+    stream.set_read_timeout(Some(Duration::from_secs(HANDSHAKE_TIMEOUT_SECS)))?;
+    let handshake_wire = Wire::handshake(codec);
+    handshake_wire.write_message(stream, &Message::Hello(hello))?;
+    let reply = handshake_wire.read_message(stream);
+    stream.set_read_timeout(None)?;
+
+    match reply? {
+        Message::Welcome(welcome) => {
+            tracing::info!(
+                peer_id = welcome.peer_id,
+                resumed = welcome.resumed,
+                replayed = welcome.replayed,
+                compression = ?welcome.compression,
+                "Session established"
+            );
+            let compression = welcome.compression;
+            session.lock().unwrap().welcomed(welcome);
+            Ok(Wire { codec, compression })
+        }
+        Message::Goodbye(goodbye) => Err(io::Error::new(
+            io::ErrorKind::ConnectionRefused,
+            format!("Server refused the connection: {}", goodbye.reason),
+        )),
+        other => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Expected welcome from server, got {:?}", other),
+        )),
+    }
+}
+
+/// Picks a delay uniformly between half and all of `backoff`, so clients that
+/// lost the server at the same moment don't all come back at once.
+fn jittered(backoff: Duration) -> Duration {
+    let half = backoff / 2;
+    half + half.mul_f64(rand::rng().random::<f64>())
 }
 
 impl Read for ReconnectableTcpStream {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        loop {
-            match &mut self.stream {
-                Some(stream) => {
-                    match stream.read(buf) {
-                        Ok(0) => {
-                            // Connection closed
-                            self.stream = None;
-                            self.reconnect()?;
-                            continue;
-                        }
-                        Ok(n) => return Ok(n),
-                        Err(e) => {
-                            // Any error triggers reconnection
-                            tracing::warn!(error = ?e, "Read error, attempting reconnect");
-                            self.stream = None;
-                            self.reconnect()?;
-                            continue;
-                        }
-                    }
-                }
-                None => {
-                    self.reconnect()?;
-                    continue;
-                }
+        let result = self.current_stream()?.read(buf);
+        match result {
+            Ok(0) if !buf.is_empty() => {
+                tracing::warn!("Server closed the connection");
+                self.mark_disconnected();
+                Ok(0)
+            }
+            Ok(n) => Ok(n),
+            Err(e) => {
+                tracing::warn!(error = ?e, "Read error");
+                self.mark_disconnected();
+                Err(e)
             }
         }
     }
@@ -128,26 +562,12 @@ impl Read for ReconnectableTcpStream {
 
 impl Write for ReconnectableTcpStream {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        loop {
-            match &mut self.stream {
-                Some(stream) => {
-                    match stream.write(buf) {
-                        Ok(n) => return Ok(n),
-                        Err(e) => {
-                            // Any error triggers reconnection
-                            tracing::warn!(error = ?e, "Write error, attempting reconnect");
-                            self.stream = None;
-                            self.reconnect()?;
-                            continue;
-                        }
-                    }
-                }
-                None => {
-                    self.reconnect()?;
-                    continue;
-                }
-            }
+        let result = self.current_stream()?.write(buf);
+        if let Err(e) = &result {
+            tracing::warn!(error = ?e, "Write error");
+            self.mark_disconnected();
         }
+        result
     }
 
     fn flush(&mut self) -> io::Result<()> {
@@ -157,3 +577,118 @@ impl Write for ReconnectableTcpStream {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(initial_ms: u64, max_ms: u64) -> ReconnectPolicy {
+        ReconnectPolicy {
+            initial_backoff: Duration::from_millis(initial_ms),
+            max_backoff: Duration::from_millis(max_ms),
+            ..ReconnectPolicy::default()
+        }
+    }
+
+    #[test]
+    fn tries_each_server_in_order() {
+        let closed = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let open = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let wire = Wire::handshake(Codec::Bitcode);
+            let Message::Hello(hello) = wire.read_message(&mut stream).unwrap() else {
+                panic!("expected hello");
+            };
+            let welcome = Welcome {
+                session_token: "token".to_string(),
+                peer_id: 7,
+                resumed: false,
+                replayed: 0,
+                compression: Compression::None,
+            };
+            wire.write_message(&mut stream, &Message::Welcome(welcome))
+                .unwrap();
+            hello.client_id
+        });
+
+        let session = Mutex::new(session());
+        let addrs = [closed.to_string(), open.to_string()];
+        establish(&addrs, Duration::from_secs(1), &session).unwrap();
+        assert_eq!(server.join().unwrap(), "test");
+        assert_eq!(session.lock().unwrap().peer_id, Some(7));
+    }
+
+    fn session() -> Session {
+        Session {
+            identity: ClientIdentity {
+                client_id: "test".to_string(),
+                ..ClientIdentity::default()
+            },
+            codec: Codec::Bitcode,
+            compression: Vec::new(),
+            token: None,
+            peer_id: None,
+            last_seq: 0,
+        }
+    }
+
+    fn welcome(token: &str, resumed: bool) -> Welcome {
+        Welcome {
+            session_token: token.to_string(),
+            peer_id: 1,
+            resumed,
+            replayed: 0,
+            compression: Compression::None,
+        }
+    }
+
+    #[test]
+    fn a_resumed_session_keeps_its_place() {
+        let mut session = session();
+        session.welcomed(welcome("a", false));
+        session.last_seq = 40;
+        session.welcomed(welcome("a", true));
+        assert_eq!(session.last_seq, 40);
+        assert_eq!(session.token.as_deref(), Some("a"));
+    }
+
+    #[test]
+    fn a_new_session_starts_from_the_beginning() {
+        let mut session = session();
+        session.welcomed(welcome("a", false));
+        session.last_seq = 40;
+        // The server restarted, or the session expired.
+        session.welcomed(welcome("b", false));
+        assert_eq!(session.last_seq, 0);
+        assert_eq!(session.token.as_deref(), Some("b"));
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_maximum() {
+        let policy = policy(50, 300);
+        let delays: Vec<u128> = (1..=6)
+            .map(|attempt| policy.backoff(attempt).as_millis())
+            .collect();
+        assert_eq!(delays, [50, 100, 200, 300, 300, 300]);
+        assert_eq!(policy.backoff(u32::MAX), Duration::from_millis(300));
+    }
+
+    #[test]
+    fn the_maximum_applies_to_the_first_attempt_too() {
+        assert_eq!(policy(50, 20).backoff(1), Duration::from_millis(20));
+    }
+
+    #[test]
+    fn jitter_stays_within_half_and_all_of_the_backoff() {
+        let backoff = Duration::from_millis(100);
+        for _ in 0..100 {
+            let delay = jittered(backoff);
+            assert!(delay >= backoff / 2 && delay <= backoff, "{:?}", delay);
+        }
+    }
+}
@@ -0,0 +1,130 @@
+use std::fmt;
+use std::str::FromStr;
+
+/// What the server knows about a connected client when routing its events.
+#[derive(Debug, Clone)]
+pub struct Member {
+    pub client_id: String,
+    pub channel: String,
+    pub tags: Vec<String>,
+}
+
+/// Picks out clients: `*` for any, `tag:NAME` for those with a tag, or a
+/// `client_id`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Selector {
+    Any,
+    Tag(String),
+    ClientId(String),
+}
+
+impl Selector {
+    fn matches(&self, member: &Member) -> bool {
+        match self {
+            Selector::Any => true,
+            Selector::Tag(tag) => member.tags.contains(tag),
+            Selector::ClientId(client_id) => member.client_id == *client_id,
+        }
+    }
+}
+
+impl FromStr for Selector {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        let s = s.trim();
+        if s == "*" {
+            return Ok(Selector::Any);
+        }
+        match s.strip_prefix("tag:") {
+            Some("") => Err("tag: needs a tag name".to_string()),
+            Some(tag) => Ok(Selector::Tag(tag.to_string())),
+            None if s.is_empty() => Err("empty client selector".to_string()),
+            None => Ok(Selector::ClientId(s.to_string())),
+        }
+    }
+}
+
+impl fmt::Display for Selector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Selector::Any => write!(f, "*"),
+            Selector::Tag(tag) => write!(f, "tag:{}", tag),
+            Selector::ClientId(client_id) => write!(f, "{}", client_id),
+        }
+    }
+}
+
+/// Sends the key events of clients matching `from` to those matching `to`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Route {
+    pub from: Selector,
+    pub to: Vec<Selector>,
+}
+
+/// Whether an event from `sender` goes to `receiver`: only within a channel,
+/// and, once there are any `routes`, only along one of them.
+pub fn delivers(routes: &[Route], sender: &Member, receiver: &Member) -> bool {
+    sender.channel == receiver.channel
+        && (routes.is_empty()
+            || routes.iter().any(|route| {
+                route.from.matches(sender) && route.to.iter().any(|to| to.matches(receiver))
+            }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn member(client_id: &str, channel: &str, tags: &[&str]) -> Member {
+        Member {
+            client_id: client_id.to_string(),
+            channel: channel.to_string(),
+            tags: tags.iter().map(|tag| tag.to_string()).collect(),
+        }
+    }
+
+    fn route(from: &str, to: &[&str]) -> Route {
+        Route {
+            from: from.parse().unwrap(),
+            to: to.iter().map(|to| to.parse().unwrap()).collect(),
+        }
+    }
+
+    #[test]
+    fn parses_selectors() {
+        assert_eq!("*".parse(), Ok(Selector::Any));
+        assert_eq!("tag:lead".parse(), Ok(Selector::Tag("lead".to_string())));
+        assert_eq!(
+            "alice-1".parse(),
+            Ok(Selector::ClientId("alice-1".to_string()))
+        );
+        assert!("tag:".parse::<Selector>().is_err());
+        assert!("".parse::<Selector>().is_err());
+    }
+
+    #[test]
+    fn everyone_in_a_channel_without_routes() {
+        let a = member("a", "one", &[]);
+        assert!(delivers(&[], &a, &member("b", "one", &[])));
+        assert!(!delivers(&[], &a, &member("c", "two", &[])));
+    }
+
+    #[test]
+    fn only_along_routes_once_there_are_any() {
+        let routes = [route("tag:lead", &["*"]), route("b", &["c", "tag:echo"])];
+        let lead = member("a", "one", &["lead"]);
+        let b = member("b", "one", &[]);
+        let c = member("c", "one", &[]);
+        let echo = member("d", "one", &["echo"]);
+
+        assert!(delivers(&routes, &lead, &b));
+        assert!(delivers(&routes, &lead, &lead));
+        assert!(delivers(&routes, &b, &c));
+        assert!(delivers(&routes, &b, &echo));
+        assert!(!delivers(&routes, &b, &lead));
+        assert!(!delivers(&routes, &c, &b));
+        // Routes never cross channels.
+        assert!(!delivers(&routes, &lead, &member("e", "two", &[])));
+    }
+}
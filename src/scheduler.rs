@@ -0,0 +1,300 @@
+use anyhow::Result;
+use evdev::{KeyCode, uinput::VirtualDevice};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::io;
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::clock::now_micros;
+use crate::config::{DurationRange, Humanize};
+
+/// Converts a time on the local wall clock (microseconds since the Unix
+/// epoch) to an `Instant`, so it can be waited for with a monotonic clock.
+pub fn instant_at(local_us: u64) -> Instant {
+    let now = Instant::now();
+    let now_us = now_micros();
+    if local_us >= now_us {
+        now + Duration::from_micros(local_us - now_us)
+    } else {
+        now.checked_sub(Duration::from_micros(now_us - local_us))
+            .unwrap_or(now)
+    }
+}
+
+/// Picks the randomized delays and hold durations configured per mapping.
+pub struct Humanizer {
+    rng: StdRng,
+}
+
+impl Humanizer {
+    /// A seed makes the sequence of picks reproducible.
+    pub fn new(seed: Option<u64>) -> Self {
+        let rng = match seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_os_rng(),
+        };
+        Self { rng }
+    }
+
+    fn pick(&mut self, range: Option<DurationRange>) -> Duration {
+        match range {
+            Some(range) => self.rng.random_range(range.min..=range.max),
+            None => Duration::ZERO,
+        }
+    }
+
+    /// Returns the extra delay before pressing, and how long to hold the key.
+    pub fn timing(&mut self, settings: Option<&Humanize>) -> (Duration, Duration) {
+        let settings = settings.copied().unwrap_or_default();
+        (self.pick(settings.delay), self.pick(settings.hold))
+    }
+}
+
+/// Which press holds each key down. When a press comes while an earlier one
+/// still holds its key, the key is released and pressed again, so both show
+/// as separate presses, and it stays down until the later press's hold ends.
+#[derive(Default)]
+struct HeldKeys {
+    held: HashMap<KeyCode, u64>,
+}
+
+impl HeldKeys {
+    /// The key values to emit for `press` taking `key` down (1) or up (0).
+    fn transition(&mut self, press: u64, key: KeyCode, value: i32) -> Vec<i32> {
+        if value == 1 {
+            match self.held.insert(key, press) {
+                Some(_) => vec![0, 1],
+                None => vec![1],
+            }
+        } else if self.held.get(&key) == Some(&press) {
+            self.held.remove(&key);
+            vec![0]
+        } else {
+            // A later press has taken the key over; its own release lets go.
+            Vec::new()
+        }
+    }
+}
+
+struct Press {
+    at: Instant,
+    key: KeyCode,
+    hold: Duration,
+}
+
+enum Command {
+    Press(Press),
+    /// Drop pending presses, release every key that is down and stop,
+    /// replying once done.
+    ReleaseAll(mpsc::Sender<()>),
+    /// Release every key that is down and press keys on this device from now on.
+    SetDevice(VirtualDevice),
+}
+
+/// Presses keys on the virtual keyboard at requested moments, from its own
+/// thread so that waiting never holds up reading from the server.
+#[derive(Clone)]
+pub struct Scheduler {
+    tx: mpsc::Sender<Command>,
+}
+
+impl Scheduler {
+    pub fn spawn(device: VirtualDevice) -> Self {
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || run(device, rx));
+        Self { tx }
+    }
+
+    /// Presses `key` at `at`, or right away if that has already passed, and
+    /// releases it `hold` later.
+    pub fn press_at(&self, at: Instant, key: KeyCode, hold: Duration) -> Result<()> {
+        self.tx
+            .send(Command::Press(Press { at, key, hold }))
+            .map_err(|_| anyhow::anyhow!("Key scheduler thread has stopped"))
+    }
+
+    /// Releases every key that is held down, discards presses that haven't
+    /// happened yet and stops the scheduler, waiting up to `timeout`.
+    pub fn release_all(&self, timeout: Duration) -> Result<()> {
+        let (done_tx, done_rx) = mpsc::channel();
+        self.tx
+            .send(Command::ReleaseAll(done_tx))
+            .map_err(|_| anyhow::anyhow!("Key scheduler thread has stopped"))?;
+        done_rx
+            .recv_timeout(timeout)
+            .map_err(|_| anyhow::anyhow!("Timed out releasing keys"))
+    }
+
+    /// Switches to another virtual keyboard, releasing the keys held down on
+    /// the current one. Pending presses go to the new device.
+    pub fn set_device(&self, device: VirtualDevice) -> Result<()> {
+        self.tx
+            .send(Command::SetDevice(device))
+            .map_err(|_| anyhow::anyhow!("Key scheduler thread has stopped"))
+    }
+}
+
+/// A key going down (1) or up (0): when, in which order it arrived, and
+/// which press it belongs to.
+type Transition = (Instant, u64, u64, KeyCode, i32);
+
+fn run(mut device: VirtualDevice, rx: mpsc::Receiver<Command>) {
+    // Key down/up transitions ordered by due time, then by arrival, so that
+    // a press with no hold still goes down before it comes up.
+    let mut queue: BinaryHeap<Reverse<Transition>> = BinaryHeap::new();
+    let mut arrivals = 0;
+    let mut presses = 0;
+    let mut held = HeldKeys::default();
+
+    loop {
+        let received = match queue.peek() {
+            Some(Reverse((at, ..))) => {
+                rx.recv_timeout(at.saturating_duration_since(Instant::now()))
+            }
+            None => rx.recv().map_err(|_| mpsc::RecvTimeoutError::Disconnected),
+        };
+        match received {
+            Ok(Command::ReleaseAll(done)) => {
+                release_all(&mut device, &mut held);
+                tracing::debug!("Released all keys");
+                let _ = done.send(());
+                return;
+            }
+            Ok(Command::SetDevice(new_device)) => {
+                release_all(&mut device, &mut held);
+                device = new_device;
+            }
+            Ok(Command::Press(press)) => {
+                let (at, key) = (press.at, press.key);
+                queue.push(Reverse((at, arrivals, presses, key, 1)));
+                queue.push(Reverse((at + press.hold, arrivals + 1, presses, key, 0)));
+                arrivals += 2;
+                presses += 1;
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => {}
+            Err(mpsc::RecvTimeoutError::Disconnected) if queue.is_empty() => return,
+            Err(mpsc::RecvTimeoutError::Disconnected) => {
+                // Nothing more is coming; finish what is queued.
+                if let Some(Reverse((at, ..))) = queue.peek() {
+                    thread::sleep(at.saturating_duration_since(Instant::now()));
+                }
+            }
+        }
+
+        let now = Instant::now();
+        while let Some(Reverse((at, _, press, key, value))) = queue.peek().copied() {
+            if at > now {
+                break;
+            }
+            queue.pop();
+            for value in held.transition(press, key, value) {
+                if let Err(e) = emit_key(&mut device, key, value) {
+                    tracing::warn!(error = %e, key = ?key, "Failed to simulate key press");
+                }
+            }
+        }
+    }
+}
+
+fn release_all(device: &mut VirtualDevice, held: &mut HeldKeys) {
+    for (key, _) in held.held.drain() {
+        if let Err(e) = emit_key(device, key, 0) {
+            tracing::warn!(error = %e, key = ?key, "Failed to release key");
+        }
+    }
+}
+
+fn emit_key(device: &mut VirtualDevice, key: KeyCode, value: i32) -> io::Result<()> {
+    device.emit(&[*evdev::KeyEvent::new(key, value)])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    fn humanize() -> Humanize {
+        Humanize {
+            delay: Some(DurationRange {
+                min: ms(10),
+                max: ms(40),
+            }),
+            hold: Some(DurationRange {
+                min: ms(30),
+                max: ms(80),
+            }),
+        }
+    }
+
+    fn picks(seed: u64) -> Vec<(Duration, Duration)> {
+        let mut humanizer = Humanizer::new(Some(seed));
+        (0..100)
+            .map(|_| humanizer.timing(Some(&humanize())))
+            .collect()
+    }
+
+    #[test]
+    fn same_seed_picks_the_same_timing() {
+        assert_eq!(picks(42), picks(42));
+        assert_ne!(picks(42), picks(43));
+    }
+
+    #[test]
+    fn picks_within_configured_ranges() {
+        for (delay, hold) in picks(7) {
+            assert!((ms(10)..=ms(40)).contains(&delay), "{:?}", delay);
+            assert!((ms(30)..=ms(80)).contains(&hold), "{:?}", hold);
+        }
+    }
+
+    #[test]
+    fn no_humanizing_presses_at_once() {
+        let mut humanizer = Humanizer::new(Some(42));
+        assert_eq!(humanizer.timing(None), (Duration::ZERO, Duration::ZERO));
+        let fixed = Humanize {
+            delay: None,
+            hold: Some(DurationRange {
+                min: ms(50),
+                max: ms(50),
+            }),
+        };
+        assert_eq!(humanizer.timing(Some(&fixed)), (Duration::ZERO, ms(50)));
+    }
+
+    #[test]
+    fn separate_presses_go_down_and_up() {
+        let mut held = HeldKeys::default();
+        assert_eq!(held.transition(0, KeyCode::KEY_A, 1), [1]);
+        assert_eq!(held.transition(0, KeyCode::KEY_A, 0), [0]);
+        assert_eq!(held.transition(1, KeyCode::KEY_A, 1), [1]);
+        assert_eq!(held.transition(1, KeyCode::KEY_A, 0), [0]);
+    }
+
+    #[test]
+    fn overlapping_holds_press_twice_and_release_last() {
+        let mut held = HeldKeys::default();
+        assert_eq!(held.transition(0, KeyCode::KEY_A, 1), [1]);
+        // The second press comes while the first still holds the key.
+        assert_eq!(held.transition(1, KeyCode::KEY_A, 1), [0, 1]);
+        // The first hold ends, but the second press keeps the key down.
+        assert!(held.transition(0, KeyCode::KEY_A, 0).is_empty());
+        assert_eq!(held.transition(1, KeyCode::KEY_A, 0), [0]);
+        assert!(held.held.is_empty());
+    }
+
+    #[test]
+    fn holds_of_different_keys_are_independent() {
+        let mut held = HeldKeys::default();
+        assert_eq!(held.transition(0, KeyCode::KEY_A, 1), [1]);
+        assert_eq!(held.transition(1, KeyCode::KEY_B, 1), [1]);
+        assert_eq!(held.transition(0, KeyCode::KEY_A, 0), [0]);
+        assert_eq!(held.transition(1, KeyCode::KEY_B, 0), [0]);
+    }
+}
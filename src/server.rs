@@ -1,71 +1,343 @@
 use anyhow::{Context, Result};
-use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::io::{Read, Write};
-use std::net::{SocketAddr, TcpListener, TcpStream};
-use std::sync::{Arc, Mutex, mpsc};
+use rand::Rng;
+use serde::Serialize;
+use signal_hook::consts::SIGHUP;
+use signal_hook::iterator::Signals;
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, BufReader, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock, mpsc};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct KeyEvent {
-    pub key: String,
-    pub source: String,
-    pub client_id: u32,
+use crate::clock::{now_micros, system_time_micros};
+use crate::key_filter::{ChordTracker, KeyRange};
+use crate::metrics::{Counter, CounterVec, Encoder, Histogram};
+use crate::protocol::{
+    Ack, Codec, Compression, Goodbye, Hello, KeyEvent, MAX_BATCH_LEN, Message, Peer, PeerId, Pong,
+    Receipt, Welcome, Wire,
+};
+use crate::rate_limit::RateLimiter;
+use crate::routing::{self, Member};
+use crate::server_config::{ConfigReloader, ServerConfig};
+
+const HANDSHAKE_TIMEOUT_SECS: u64 = 5;
+/// How long a shutdown waits for clients to hang up before closing on them.
+const SHUTDOWN_GRACE_SECS: u64 = 2;
+/// A client that takes longer than this to accept a frame is disconnected,
+/// so that it can't hold up broadcasts to everyone else.
+const CLIENT_WRITE_TIMEOUT_SECS: u64 = 2;
+/// Bounds the time spent on a client that doesn't read its goodbye.
+const GOODBYE_WRITE_TIMEOUT_SECS: u64 = 1;
+/// A client is disconnected once this many of its frames have been rejected.
+const MAX_REJECTED_FRAMES: u32 = 10;
+/// Key events broadcast through the admin API come from this peer.
+const OPERATOR_PEER_ID: PeerId = 0;
+const OPERATOR_CLIENT_ID: &str = "operator";
+
+/// How much recent history is kept for clients resuming a session.
+#[derive(Debug, Clone)]
+pub struct ReplayConfig {
+    /// Number of recent key events kept for replay.
+    pub capacity: usize,
+    /// Events older than this are never replayed, and sessions that have been
+    /// disconnected for longer can no longer be resumed.
+    pub max_age: Duration,
+}
+
+/// Recently broadcast key events, in sequence order.
+struct History {
+    next_seq: u64,
+    events: VecDeque<Recorded>,
+}
+
+/// A broadcast key event, as kept for replay.
+struct Recorded {
+    at: Instant,
+    /// `None` for the operator's events, which go to everyone.
+    sender: Option<Arc<Member>>,
+    event: KeyEvent,
+}
+
+struct Session {
+    client_id: String,
+    channel: String,
+    peer_id: PeerId,
+    /// The connection that opened or last resumed the session. Only that one
+    /// can end or close it: after a resume, the one it replaced may notice
+    /// that it is gone only later.
+    connection: u64,
+    /// When the session's connection went away; `None` while connected.
+    disconnected_at: Option<Instant>,
+}
+
+/// Traffic from one connection, updated as its frames are read.
+#[derive(Default)]
+struct ClientStats {
+    /// Payload bytes of every frame received, accepted or not.
+    bytes_sent: AtomicU64,
+    /// Key events accepted for broadcast.
+    events_sent: AtomicU64,
+    /// Key events dropped for exceeding the rate limit.
+    events_rate_limited: AtomicU64,
+}
+
+/// A connected client, as kept in the broadcast set.
+struct ClientHandle {
+    peer_id: PeerId,
+    member: Arc<Member>,
+    connected_at: std::time::SystemTime,
+    stats: Arc<ClientStats>,
+    wire: Wire,
+    stream: TcpStream,
+}
+
+impl ClientHandle {
+    fn send(&self, message: &Message) -> io::Result<()> {
+        write_or_drop(&self.stream, &self.wire.encode(message)?)
+    }
+}
+
+/// Writes a whole frame to a client, or disconnects it. Part of the frame
+/// may have been written when the write times out, so nothing more can be
+/// sent on the socket; its reading thread then sees it closed and cleans up.
+fn write_or_drop(stream: &TcpStream, frame: &[u8]) -> io::Result<()> {
+    let result = (&*stream).write_all(frame);
+    if result.is_err() {
+        let _ = stream.shutdown(Shutdown::Both);
+    }
+    result
+}
+
+/// A connected client, as listed by the admin API.
+#[derive(Serialize)]
+pub struct ClientInfo {
+    pub peer_id: PeerId,
+    pub client_id: String,
+    pub address: SocketAddr,
+    pub channel: String,
+    pub tags: Vec<String>,
+    /// Microseconds since the Unix epoch.
+    pub connected_at_us: u64,
+    pub bytes_sent: u64,
+    pub events_sent: u64,
+    pub events_rate_limited: u64,
+}
+
+/// Counts of frames the server refused to relay, by reason.
+#[derive(Serialize)]
+pub struct RejectionCounts {
+    pub malformed: u64,
+    pub oversized: u64,
+    pub invalid: u64,
+    pub unexpected: u64,
+}
+
+#[derive(Serialize)]
+pub struct Health {
+    pub uptime_secs: u64,
+    pub connected_clients: usize,
+    /// Sessions that are connected or can still be resumed.
+    pub sessions: usize,
+    /// Sequence number of the most recent broadcast.
+    pub last_seq: u64,
+    pub replay_buffered: usize,
+    pub rejected: RejectionCounts,
+}
+
+/// What `ServerState::open_session` agreed with a client.
+struct Opened {
+    token: String,
+    peer_id: PeerId,
+    /// For a resumed session, the last sequence number the client saw.
+    resume_from: Option<u64>,
+}
+
+/// Why the server refused a frame from a client.
+#[derive(Debug, Clone, Copy)]
+enum Rejection {
+    /// Could not be decoded.
+    Malformed,
+    /// Larger than `MAX_FRAME_SIZE`.
+    Oversized,
+    /// Decoded, but with values the server won't relay.
+    Invalid,
+    /// A message clients aren't supposed to send.
+    Unexpected,
+}
+
+/// Rejected frames from all clients since the server started, by reason.
+#[derive(Default)]
+struct RejectedFrames {
+    malformed: AtomicU64,
+    oversized: AtomicU64,
+    invalid: AtomicU64,
+    unexpected: AtomicU64,
+}
+
+impl RejectedFrames {
+    /// Counts one rejection, returning the new count for its reason.
+    fn record(&self, reason: Rejection) -> u64 {
+        let counter = match reason {
+            Rejection::Malformed => &self.malformed,
+            Rejection::Oversized => &self.oversized,
+            Rejection::Invalid => &self.invalid,
+            Rejection::Unexpected => &self.unexpected,
+        };
+        counter.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    fn counts(&self) -> RejectionCounts {
+        RejectionCounts {
+            malformed: self.malformed.load(Ordering::Relaxed),
+            oversized: self.oversized.load(Ordering::Relaxed),
+            invalid: self.invalid.load(Ordering::Relaxed),
+            unexpected: self.unexpected.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[derive(Default)]
+struct ServerMetrics {
+    /// Key events accepted from each client, by `client_id`.
+    events_received: CounterVec,
+    /// Key events written to each client, by `client_id`.
+    events_broadcast: CounterVec,
+    /// Key events dropped by the rate limiter, by `client_id`.
+    events_rate_limited: CounterVec,
+    /// Key events dropped by the key policy, by `client_id`.
+    events_denied: CounterVec,
+    broadcast_write_errors: Counter,
+    broadcast_duration: Histogram,
+}
+
+pub struct ServerState {
+    clients: Mutex<HashMap<SocketAddr, ClientHandle>>,
+    history: Mutex<History>,
+    sessions: Mutex<HashMap<String, Session>>,
+    next_peer_id: Mutex<PeerId>,
+    rejected: RejectedFrames,
+    metrics: ServerMetrics,
+    /// Swapped as a whole when the config file is reloaded.
+    config: RwLock<Arc<ServerConfig>>,
+    started_at: Instant,
+    /// Ids for operator key events. Starts at the wall clock time so that
+    /// ids keep increasing across server restarts, as receivers expect.
+    next_operator_id: AtomicU64,
+    /// Set, with the retry delay to give clients, once the server has said
+    /// goodbye. Only changed and read under the clients lock.
+    shutting_down: Mutex<Option<Duration>>,
+    /// Numbers the accepted connections, to tell them apart in `Session`.
+    next_connection: AtomicU64,
+    /// Rate limiters by `client_id`, kept after a client disconnects until
+    /// its buckets have filled up again.
+    limiters: Mutex<HashMap<String, Arc<Mutex<RateLimiter>>>>,
 }
 
 pub struct Server {
-    clients: Arc<Mutex<HashMap<SocketAddr, TcpStream>>>,
+    state: Arc<ServerState>,
 }
 
 impl Server {
-    pub fn new() -> Self {
+    pub fn new(config: ServerConfig) -> Self {
         Server {
-            clients: Arc::new(Mutex::new(HashMap::new())),
+            state: Arc::new(ServerState {
+                clients: Mutex::new(HashMap::new()),
+                history: Mutex::new(History {
+                    next_seq: 0,
+                    events: VecDeque::new(),
+                }),
+                sessions: Mutex::new(HashMap::new()),
+                next_peer_id: Mutex::new(1),
+                rejected: RejectedFrames::default(),
+                metrics: ServerMetrics::default(),
+                config: RwLock::new(Arc::new(config)),
+                started_at: Instant::now(),
+                next_operator_id: AtomicU64::new(now_micros()),
+                shutting_down: Mutex::new(None),
+                next_connection: AtomicU64::new(1),
+                limiters: Mutex::new(HashMap::new()),
+            }),
         }
     }
 
-    pub fn start(&self, addr: &str) -> Result<(mpsc::Sender<()>, thread::JoinHandle<Result<()>>)> {
+    /// Reloads the config file on SIGHUP and whenever it changes, applying
+    /// whatever can change without a restart.
+    pub fn watch_config(&self, reloader: ConfigReloader) -> Result<()> {
+        let path = reloader.path().to_path_buf();
+        let reloader = Mutex::new(reloader);
+        let state = Arc::clone(&self.state);
+        let reload = Arc::new(move || {
+            let current = state.config();
+            if let Some(config) = reloader.lock().unwrap().reload(&current) {
+                state.reconfigure(config);
+            }
+        });
+
+        let on_change = Arc::clone(&reload);
+        crate::watch::watch_file(&path, move || on_change())?;
+
+        let mut signals = Signals::new([SIGHUP]).context("Failed to install SIGHUP handler")?;
+        thread::spawn(move || {
+            for _ in signals.forever() {
+                tracing::info!("Received SIGHUP, reloading config");
+                reload();
+            }
+        });
+        tracing::info!(path = %path.display(), "Watching server config for changes");
+        Ok(())
+    }
+
+    /// Serves Prometheus metrics on `addr`, in the background.
+    pub fn serve_metrics(&self, addr: &str) -> Result<()> {
+        let state = Arc::clone(&self.state);
+        crate::metrics::serve(addr, move || state.render_metrics())
+    }
+
+    /// Serves the admin API on `addr`, in the background.
+    pub fn serve_admin(&self, addr: &str) -> Result<()> {
+        crate::admin::spawn(addr, Arc::clone(&self.state))
+    }
+
+    /// Starts accepting clients in the background. Sending a duration on the
+    /// returned channel shuts the server down, telling clients to come back
+    /// after that long.
+    pub fn start(
+        &self,
+        addr: &str,
+    ) -> Result<(mpsc::Sender<Duration>, thread::JoinHandle<Result<()>>)> {
         let (shutdown_tx, shutdown_rx) = mpsc::channel();
 
         let listener =
             TcpListener::bind(addr).context(format!("Failed to bind to address: {}", addr))?;
         tracing::info!("Server listening on {}", addr);
 
-        let clients = Arc::clone(&self.clients);
+        let state = Arc::clone(&self.state);
         let handle = thread::spawn(move || -> Result<()> {
             listener
                 .set_nonblocking(true)
                 .context("Failed to set listener to non-blocking mode")?;
 
-            loop {
+            let mut handlers: Vec<thread::JoinHandle<()>> = Vec::new();
+            let retry_after: Duration = loop {
                 // Check for shutdown signal
-                if shutdown_rx.try_recv().is_ok() {
-                    tracing::info!("Server shutting down");
-                    break;
+                if let Ok(retry_after) = shutdown_rx.try_recv() {
+                    break retry_after;
                 }
 
                 match listener.accept() {
                     Ok((stream, addr)) => {
                         tracing::info!("Client connected: {}", addr);
 
-                        // Add client to the map
-                        {
-                            let mut clients_lock = clients.lock().unwrap();
-                            clients_lock.insert(
-                                addr,
-                                stream
-                                    .try_clone()
-                                    .context("Failed to clone client stream")?,
-                            );
-                        }
-
-                        let clients_clone = Arc::clone(&clients);
-                        thread::spawn(move || {
-                            if let Err(e) = handle_client(stream, clients_clone, addr) {
+                        let state = Arc::clone(&state);
+                        handlers.retain(|handler| !handler.is_finished());
+                        handlers.push(thread::spawn(move || {
+                            if let Err(e) = handle_client(stream, state, addr) {
                                 tracing::error!("Error handling client {}: {}", addr, e);
                             }
-                        });
+                        }));
                     }
                     Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
                         // No connection available, sleep a bit
@@ -76,7 +348,27 @@ impl Server {
                         return Err(anyhow::anyhow!("Error accepting connection: {}", e));
                     }
                 }
+            };
+
+            tracing::info!(
+                retry_after_ms = retry_after.as_millis() as u64,
+                "Server shutting down"
+            );
+            drop(listener);
+            let streams = state.say_goodbye(retry_after);
+
+            let deadline = Instant::now() + Duration::from_secs(SHUTDOWN_GRACE_SECS);
+            while handlers.iter().any(|handler| !handler.is_finished()) && Instant::now() < deadline
+            {
+                thread::sleep(Duration::from_millis(20));
+            }
+            for stream in &streams {
+                let _ = stream.shutdown(Shutdown::Both);
             }
+            for handler in handlers {
+                let _ = handler.join();
+            }
+            tracing::info!(clients = streams.len(), "Server stopped");
             Ok(())
         });
 
@@ -84,74 +376,1103 @@ impl Server {
     }
 }
 
-fn handle_client(
-    mut stream: TcpStream,
-    clients: Arc<Mutex<HashMap<SocketAddr, TcpStream>>>,
-    addr: SocketAddr,
-) -> Result<()> {
-    // Add client to the clients map
-    {
-        let mut clients_map = clients.lock().unwrap();
-        clients_map.insert(
+impl ServerState {
+    pub fn config(&self) -> Arc<ServerConfig> {
+        Arc::clone(&self.config.read().unwrap())
+    }
+
+    /// Switches to `config`. Connected clients are kept, and pick up the new
+    /// limits and key policy with their next frame.
+    fn reconfigure(&self, config: ServerConfig) {
+        {
+            let mut history = self.history.lock().unwrap();
+            let excess = history.events.len().saturating_sub(config.replay.capacity);
+            history.events.drain(..excess);
+        }
+        *self.config.write().unwrap() = Arc::new(config);
+    }
+
+    /// Resumes the session named in `hello` if it is still known, otherwise
+    /// starts a new one with a fresh peer id, on behalf of `connection`. A
+    /// `client_id` that is already connected can only be taken over with that
+    /// session's token.
+    /// The rate limiter for `client_id`, as its last connection left it.
+    fn limiter(&self, client_id: &str) -> Arc<Mutex<RateLimiter>> {
+        let mut limiters = self.limiters.lock().unwrap();
+        // Forget clients that are gone and would start afresh anyway.
+        limiters.retain(|_, limiter| {
+            Arc::strong_count(limiter) > 1 || !limiter.lock().unwrap().is_rested()
+        });
+        let config = self.config().rate_limit;
+        Arc::clone(
+            limiters
+                .entry(client_id.to_string())
+                .or_insert_with(|| Arc::new(Mutex::new(RateLimiter::new(config)))),
+        )
+    }
+
+    fn open_session(&self, hello: &Hello, connection: u64) -> Result<Opened> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let max_age = self.config().replay.max_age;
+        sessions.retain(|_, session| {
+            session
+                .disconnected_at
+                .is_none_or(|at| at.elapsed() <= max_age)
+        });
+
+        if let Some(resume) = &hello.resume
+            && let Some(session) = sessions.get_mut(&resume.session_token)
+            && session.client_id == hello.client_id
+            && session.channel == hello.channel()
+        {
+            session.connection = connection;
+            session.disconnected_at = None;
+            return Ok(Opened {
+                token: resume.session_token.clone(),
+                peer_id: session.peer_id,
+                resume_from: Some(resume.last_seq),
+            });
+        }
+
+        if sessions.values().any(|session| {
+            session.client_id == hello.client_id && session.disconnected_at.is_none()
+        }) {
+            return Err(anyhow::anyhow!(
+                "client_id {} is already connected",
+                hello.client_id
+            ));
+        }
+
+        let peer_id = {
+            let mut next_peer_id = self.next_peer_id.lock().unwrap();
+            let id = *next_peer_id;
+            *next_peer_id += 1;
+            id
+        };
+        let token = format!("{:016x}", rand::rng().random::<u64>());
+        sessions.insert(
+            token.clone(),
+            Session {
+                client_id: hello.client_id.clone(),
+                channel: hello.channel().to_string(),
+                peer_id,
+                connection,
+                disconnected_at: None,
+            },
+        );
+        Ok(Opened {
+            token,
+            peer_id,
+            resume_from: None,
+        })
+    }
+
+    /// Every client whose events may still be sent or replayed, and the
+    /// operator.
+    /// The operator and the clients in `channel`, connected or not.
+    fn peers(&self, channel: &str) -> Vec<Peer> {
+        let operator = Peer {
+            id: OPERATOR_PEER_ID,
+            client_id: OPERATOR_CLIENT_ID.to_string(),
+        };
+        let sessions = self.sessions.lock().unwrap();
+        std::iter::once(operator)
+            .chain(
+                sessions
+                    .values()
+                    .filter(|session| session.channel == channel)
+                    .map(|session| Peer {
+                        id: session.peer_id,
+                        client_id: session.client_id.clone(),
+                    }),
+            )
+            .collect()
+    }
+
+    /// Checks the client's auth secret and channel against the config,
+    /// returning why it is refused.
+    fn admit(&self, hello: &Hello) -> Result<(), String> {
+        let config = self.config();
+        if !config.auth_secrets.is_empty()
+            && !hello.secret.as_deref().is_some_and(|secret| {
+                config
+                    .auth_secrets
+                    .iter()
+                    .any(|known| crate::utils::secrets_match(known, secret))
+            })
+        {
+            return Err("authentication failed".to_string());
+        }
+        if !config.channels.is_empty()
+            && !config
+                .channels
+                .iter()
+                .any(|channel| channel == hello.channel())
+        {
+            return Err(format!("unknown channel {}", hello.channel()));
+        }
+        Ok(())
+    }
+
+    pub fn clients(&self) -> Vec<ClientInfo> {
+        let clients = self.clients.lock().unwrap();
+        let mut infos: Vec<ClientInfo> = clients
+            .iter()
+            .map(|(addr, client)| ClientInfo {
+                peer_id: client.peer_id,
+                client_id: client.member.client_id.clone(),
+                address: *addr,
+                channel: client.member.channel.clone(),
+                tags: client.member.tags.clone(),
+                connected_at_us: system_time_micros(client.connected_at),
+                bytes_sent: client.stats.bytes_sent.load(Ordering::Relaxed),
+                events_sent: client.stats.events_sent.load(Ordering::Relaxed),
+                events_rate_limited: client.stats.events_rate_limited.load(Ordering::Relaxed),
+            })
+            .collect();
+        infos.sort_by_key(|info| info.peer_id);
+        infos
+    }
+
+    /// Drops the connections of the client with `peer_id` and forgets its
+    /// session, so it can only come back as a new one. Returns whether the
+    /// client was connected.
+    pub fn kick(&self, peer_id: PeerId) -> bool {
+        let clients = self.clients.lock().unwrap();
+        let mut kicked = false;
+        for (addr, client) in clients.iter().filter(|(_, c)| c.peer_id == peer_id) {
+            tracing::info!(addr = %addr, client_id = %client.member.client_id, "Kicking client");
+            // The client's own thread notices and cleans up.
+            let _ = client.stream.shutdown(std::net::Shutdown::Both);
+            kicked = true;
+        }
+        self.sessions
+            .lock()
+            .unwrap()
+            .retain(|_, session| session.peer_id != peer_id);
+        kicked
+    }
+
+    pub fn allows_key(&self, key: u16) -> bool {
+        self.config().key_filter.allows(key)
+    }
+
+    /// Removes the events whose keys the server policy doesn't relay, or
+    /// that would complete a denied chord with the keys `chords` has seen
+    /// from this client.
+    fn apply_key_policy(
+        &self,
+        client_id: &str,
+        chords: &mut ChordTracker,
+        events: &mut Vec<KeyEvent>,
+    ) {
+        let config = self.config();
+        events.retain(|event| {
+            let key = evdev::KeyCode::new(event.key);
+            if !config.key_filter.allows(event.key) {
+                self.metrics.events_denied.inc(client_id);
+                tracing::warn!(client_id, key = ?key, "Dropping key event denied by server policy");
+                return false;
+            }
+            if let Some(chord) = chords.press(&config.deny_chords, config.chord_window, event.key) {
+                self.metrics.events_denied.inc(client_id);
+                tracing::warn!(
+                    client_id,
+                    key = ?key,
+                    %chord,
+                    "Dropping key event completing a chord denied by server policy"
+                );
+                return false;
+            }
+            true
+        });
+    }
+
+    /// Tells every client that the server is going away, removing them from
+    /// the broadcast set, and finishes writing to their sockets. Frames are
+    /// written straight to the sockets, so all that is left to send is in
+    /// the kernel's buffers, which go out ahead of the FIN. Returns the
+    /// sockets, which the clients are expected to close.
+    fn say_goodbye(&self, retry_after: Duration) -> Vec<TcpStream> {
+        // Taking the lock waits for any broadcast in progress to finish.
+        // Clients still in their handshake are turned away by `register`.
+        let clients: Vec<(SocketAddr, ClientHandle)> = {
+            let mut clients = self.clients.lock().unwrap();
+            *self.shutting_down.lock().unwrap() = Some(retry_after);
+            clients.drain().collect()
+        };
+        let goodbye = Message::Goodbye(Goodbye {
+            reason: "server shutting down".to_string(),
+            retry_after_ms: Some(retry_after.as_millis() as u64),
+        });
+        clients
+            .into_iter()
+            .map(|(addr, client)| {
+                let mut stream = &client.stream;
+                let _ =
+                    stream.set_write_timeout(Some(Duration::from_secs(GOODBYE_WRITE_TIMEOUT_SECS)));
+                if let Err(e) = client.wire.write_message(&mut stream, &goodbye) {
+                    tracing::warn!(addr = %addr, error = %e, "Failed to say goodbye to client");
+                }
+                let _ = stream.shutdown(Shutdown::Write);
+                client.stream
+            })
+            .collect()
+    }
+
+    /// Broadcasts a key press on behalf of the operator.
+    pub fn broadcast_operator_key(&self, key: u16) -> Result<()> {
+        if !self.allows_key(key) {
+            anyhow::bail!("{:?} is denied by server policy", evdev::KeyCode::new(key));
+        }
+        let now_us = now_micros();
+        let event = KeyEvent {
+            key,
+            id: self.next_operator_id.fetch_add(1, Ordering::Relaxed),
+            timestamp_us: now_us,
+            sent_at_us: now_us,
+            ..Default::default()
+        };
+        event.validate().map_err(|e| anyhow::anyhow!(e))?;
+        tracing::info!(key, "Broadcasting operator key event");
+        self.broadcast(vec![event], None)
+    }
+
+    fn render_metrics(&self) -> String {
+        let health = self.health();
+        let rejected = health.rejected;
+        let metrics = &self.metrics;
+        let mut encoder = Encoder::default();
+        encoder.gauge(
+            "keysync_connected_clients",
+            "Clients currently connected.",
+            health.connected_clients as f64,
+        );
+        encoder.gauge(
+            "keysync_sessions",
+            "Sessions that are connected or can still be resumed.",
+            health.sessions as f64,
+        );
+        encoder.counter_vec(
+            "keysync_events_received_total",
+            "Key events accepted from a client.",
+            "client_id",
+            &metrics.events_received,
+        );
+        encoder.counter_vec(
+            "keysync_events_broadcast_total",
+            "Key events written to a client.",
+            "client_id",
+            &metrics.events_broadcast,
+        );
+        encoder.counter_vec(
+            "keysync_events_rate_limited_total",
+            "Key events from a client dropped for exceeding the rate limit.",
+            "client_id",
+            &metrics.events_rate_limited,
+        );
+        encoder.counter_vec(
+            "keysync_events_denied_total",
+            "Key events from a client dropped by the server's key policy.",
+            "client_id",
+            &metrics.events_denied,
+        );
+        encoder.counter(
+            "keysync_broadcast_write_errors_total",
+            "Failed writes to clients while broadcasting.",
+            metrics.broadcast_write_errors.get(),
+        );
+        encoder.counters(
+            "keysync_frames_rejected_total",
+            "Frames from clients that were not relayed, by reason.",
+            "reason",
+            [
+                ("malformed", rejected.malformed),
+                ("oversized", rejected.oversized),
+                ("invalid", rejected.invalid),
+                ("unexpected", rejected.unexpected),
+            ],
+        );
+        encoder.histogram(
+            "keysync_broadcast_duration_seconds",
+            "Time taken to write a broadcast to every client.",
+            &metrics.broadcast_duration,
+        );
+        encoder.finish()
+    }
+
+    pub fn health(&self) -> Health {
+        let connected_clients = self.clients.lock().unwrap().len();
+        let (last_seq, replay_buffered) = {
+            let history = self.history.lock().unwrap();
+            (history.next_seq, history.events.len())
+        };
+        Health {
+            uptime_secs: self.started_at.elapsed().as_secs(),
+            connected_clients,
+            sessions: self.sessions.lock().unwrap().len(),
+            last_seq,
+            replay_buffered,
+            rejected: self.rejected.counts(),
+        }
+    }
+
+    /// Forgets a session whose client left on purpose through `connection`.
+    fn end_session(&self, token: &str, connection: u64) {
+        let mut sessions = self.sessions.lock().unwrap();
+        if sessions
+            .get(token)
+            .is_some_and(|session| session.connection == connection)
+        {
+            sessions.remove(token);
+        }
+    }
+
+    /// Starts the clock on resuming the session, once `connection` is gone,
+    /// unless another connection has resumed it since.
+    fn close_session(&self, token: &str, connection: u64) {
+        if let Some(session) = self.sessions.lock().unwrap().get_mut(token)
+            && session.connection == connection
+        {
+            session.disconnected_at = Some(Instant::now());
+        }
+    }
+
+    /// Sends the welcome, the known peers and any missed events, then adds
+    /// the client to the broadcast set and announces it to the others. This
+    /// happens under the clients lock so that no broadcast is missed or
+    /// duplicated in between.
+    fn register(
+        &self,
+        addr: SocketAddr,
+        stream: &TcpStream,
+        codec: Codec,
+        hello: &Hello,
+        opened: &Opened,
+        stats: Arc<ClientStats>,
+    ) -> Result<Wire> {
+        let max_age = self.config().replay.max_age;
+        let mut clients = self.clients.lock().unwrap();
+        if let Some(retry_after) = *self.shutting_down.lock().unwrap() {
+            let goodbye = Goodbye {
+                reason: "server shutting down".to_string(),
+                retry_after_ms: Some(retry_after.as_millis() as u64),
+            };
+            let _ = Wire::handshake(codec).write_message(&mut &*stream, &Message::Goodbye(goodbye));
+            let _ = stream.shutdown(Shutdown::Write);
+            return Err(anyhow::anyhow!(
+                "Rejected {}: server is shutting down",
+                addr
+            ));
+        }
+        // Checked here rather than earlier so that concurrent handshakes
+        // can't take the server past the limit.
+        if let Some(max_clients) = self.config().max_clients
+            && clients.len() >= max_clients
+        {
+            let goodbye = Goodbye {
+                reason: "server is full".to_string(),
+                retry_after_ms: None,
+            };
+            let _ = Wire::handshake(codec).write_message(&mut &*stream, &Message::Goodbye(goodbye));
+            return Err(anyhow::anyhow!(
+                "Rejected {}: server is full ({} clients)",
+                addr,
+                max_clients
+            ));
+        }
+        let member = Arc::new(Member {
+            client_id: hello.client_id.clone(),
+            channel: hello.channel().to_string(),
+            tags: hello.tags.clone(),
+        });
+        let config = self.config();
+        let routes = &config.routes;
+        let history = self.history.lock().unwrap();
+
+        let replay: Vec<KeyEvent> = match opened.resume_from {
+            Some(last_seq) => history
+                .events
+                .iter()
+                .filter(|recorded| {
+                    recorded.event.seq > last_seq
+                        && recorded.at.elapsed() <= max_age
+                        && recorded
+                            .sender
+                            .as_ref()
+                            .is_none_or(|sender| routing::delivers(routes, sender, &member))
+                })
+                .map(|recorded| recorded.event.clone())
+                .collect(),
+            None => Vec::new(),
+        };
+
+        // The first compression the client offers; all of them are supported.
+        let compression = match codec {
+            Codec::Bitcode => hello
+                .compression
+                .first()
+                .copied()
+                .unwrap_or(Compression::None),
+            Codec::Json => Compression::None,
+        };
+        let wire = Wire { codec, compression };
+
+        let mut writer = stream
+            .try_clone()
+            .context("Failed to clone client stream")?;
+        let welcome = Welcome {
+            session_token: opened.token.clone(),
+            peer_id: opened.peer_id,
+            resumed: opened.resume_from.is_some(),
+            replayed: replay.len() as u32,
+            compression,
+        };
+        Wire::handshake(codec)
+            .write_message(&mut writer, &Message::Welcome(welcome))
+            .context(format!("Failed to welcome {}", addr))?;
+        for peer in self.peers(&member.channel) {
+            wire.write_message(&mut writer, &Message::Peer(peer))
+                .context(format!("Failed to announce peers to {}", addr))?;
+        }
+        for chunk in replay.chunks(MAX_BATCH_LEN) {
+            wire.write_message(&mut writer, &Message::Batch(chunk.to_vec()))
+                .context(format!("Failed to replay events to {}", addr))?;
+        }
+        if !replay.is_empty() {
+            tracing::info!(addr = %addr, count = replay.len(), "Replayed missed events");
+        }
+
+        if opened.resume_from.is_none() {
+            let peer = Message::Peer(Peer {
+                id: opened.peer_id,
+                client_id: hello.client_id.clone(),
+            });
+            let in_channel = clients
+                .iter()
+                .filter(|(_, client)| client.member.channel == member.channel);
+            for (other_addr, client) in in_channel {
+                if let Err(e) = client.send(&peer) {
+                    tracing::warn!(addr = %other_addr, error = %e, "Failed to announce new peer");
+                }
+            }
+        }
+
+        clients.insert(
             addr,
-            stream
-                .try_clone()
-                .context("Failed to clone client stream")?,
+            ClientHandle {
+                peer_id: opened.peer_id,
+                member,
+                connected_at: std::time::SystemTime::now(),
+                stats,
+                wire,
+                stream: writer,
+            },
         );
+        Ok(wire)
+    }
+
+    /// Sends `events` from the client at `sender` to the clients its channel
+    /// and the routes lead to, or from the operator if `None` to every
+    /// client, stamping each with its sender and its place in the broadcast
+    /// order.
+    #[tracing::instrument(skip_all, fields(count = events.len(), sender = ?sender), err(Debug))]
+    fn broadcast(&self, mut events: Vec<KeyEvent>, sender: Option<&SocketAddr>) -> Result<()> {
+        if events.is_empty() {
+            return Ok(());
+        }
+        let clients = self.clients.lock().unwrap();
+        let sender = match sender {
+            Some(addr) => match clients.get(addr) {
+                Some(client) => Some(client),
+                // Kicked while this was in flight.
+                None => return Ok(()),
+            },
+            None => None,
+        };
+
+        {
+            let capacity = self.config().replay.capacity;
+            let mut history = self.history.lock().unwrap();
+            for event in &mut events {
+                history.next_seq += 1;
+                event.seq = history.next_seq;
+                event.sender = sender.map_or(OPERATOR_PEER_ID, |client| client.peer_id);
+                if history.events.len() >= capacity {
+                    history.events.pop_front();
+                }
+                if capacity > 0 {
+                    history.events.push_back(Recorded {
+                        at: Instant::now(),
+                        sender: sender.map(|client| Arc::clone(&client.member)),
+                        event: event.clone(),
+                    });
+                }
+            }
+        }
+
+        let config = self.config();
+        let routes = &config.routes;
+        let targets: Vec<(&SocketAddr, &ClientHandle)> = clients
+            .iter()
+            .filter(|(_, client)| {
+                sender
+                    .is_none_or(|sender| routing::delivers(routes, &sender.member, &client.member))
+            })
+            .collect();
+        let recipients: Vec<PeerId> = targets.iter().map(|(_, client)| client.peer_id).collect();
+        let receipts: Vec<Receipt> = events
+            .iter()
+            .filter(|event| event.ack)
+            .map(|event| Receipt {
+                id: event.id,
+                recipients: recipients.clone(),
+            })
+            .collect();
+
+        let started = Instant::now();
+        let count = events.len() as u64;
+        let message = match <[KeyEvent; 1]>::try_from(events) {
+            Ok([event]) => Message::Key(event),
+            Err(events) => Message::Batch(events),
+        };
+        // Encoded once per distinct wire format in use.
+        let mut frames: HashMap<Wire, Vec<u8>> = HashMap::new();
+        tracing::debug!(client_count = targets.len(), "Broadcasting key events");
+        for (addr, client) in targets {
+            let span = tracing::debug_span!("write_to_client", addr = %addr);
+            let _enter = span.enter();
+            tracing::debug!("write");
+
+            let frame = match frames.entry(client.wire) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(entry) => entry.insert(
+                    client
+                        .wire
+                        .encode(&message)
+                        .context("Failed to encode key events")?,
+                ),
+            };
+            // A client that can't be written to is dropped; the others
+            // still get the events.
+            match write_or_drop(&client.stream, frame) {
+                Ok(()) => self
+                    .metrics
+                    .events_broadcast
+                    .add(&client.member.client_id, count),
+                Err(e) => {
+                    self.metrics.broadcast_write_errors.inc();
+                    tracing::warn!(addr = %addr, error = %e, "Error broadcasting to client");
+                }
+            }
+        }
+        self.metrics.broadcast_duration.observe(started.elapsed());
+
+        if let Some(client) = sender {
+            for receipt in receipts {
+                client
+                    .send(&Message::Receipt(receipt))
+                    .context("Failed to send receipt")?;
+            }
+        }
+        Ok(())
     }
 
-    let mut buf = [0; 1024];
-    loop {
-        match stream.read(&mut buf) {
-            Ok(0) => {
+    /// Passes an acknowledgement on to the client that sent the acked event.
+    fn relay_ack(&self, ack: Ack) -> Result<()> {
+        let clients = self.clients.lock().unwrap();
+        for (addr, client) in clients.iter() {
+            if client.peer_id != ack.sender {
+                continue;
+            }
+            client
+                .send(&Message::Ack(ack.clone()))
+                .context(format!("Error relaying ack to {}", addr))?;
+        }
+        Ok(())
+    }
+}
+
+fn handle_client(stream: TcpStream, state: Arc<ServerState>, addr: SocketAddr) -> Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(HANDSHAKE_TIMEOUT_SECS)))?;
+    // Applies to every clone of the socket, and so to every write to it.
+    stream.set_write_timeout(Some(Duration::from_secs(CLIENT_WRITE_TIMEOUT_SECS)))?;
+    let mut first_byte = [0];
+    if stream
+        .peek(&mut first_byte)
+        .context(format!("Failed to read hello from {}", addr))?
+        == 0
+    {
+        tracing::info!("Client disconnected: {}", addr);
+        return Ok(());
+    }
+    let codec = Codec::detect(first_byte[0]);
+    let mut reader = BufReader::new(
+        stream
+            .try_clone()
+            .context("Failed to clone client stream")?,
+    );
+    let hello = match Wire::handshake(codec).read_message(&mut reader) {
+        Ok(Message::Hello(hello)) => hello,
+        Ok(other) => {
+            state.rejected.record(Rejection::Unexpected);
+            return Err(anyhow::anyhow!(
+                "Expected hello from {}, got {:?}",
+                addr,
+                other
+            ));
+        }
+        Err(e) => {
+            if e.kind() == io::ErrorKind::InvalidData {
+                state.rejected.record(Rejection::Malformed);
+            }
+            return Err(anyhow::anyhow!("Failed to read hello from {}: {}", addr, e));
+        }
+    };
+    if let Err(e) = hello.validate() {
+        state.rejected.record(Rejection::Invalid);
+        return Err(anyhow::anyhow!("Rejected hello from {}: {}", addr, e));
+    }
+    if let Err(reason) = state.admit(&hello) {
+        let goodbye = Message::Goodbye(Goodbye {
+            reason: reason.clone(),
+            retry_after_ms: None,
+        });
+        let _ = Wire::handshake(codec).write_message(&mut &stream, &goodbye);
+        return Err(anyhow::anyhow!(
+            "Refused {} ({}): {}",
+            addr,
+            hello.client_id,
+            reason
+        ));
+    }
+    stream.set_read_timeout(None)?;
+
+    let connection = state.next_connection.fetch_add(1, Ordering::Relaxed);
+    let opened = state
+        .open_session(&hello, connection)
+        .map_err(|e| anyhow::anyhow!("Rejected hello from {}: {}", addr, e))?;
+    tracing::info!(
+        addr = %addr,
+        client_id = %hello.client_id,
+        peer_id = opened.peer_id,
+        codec = ?codec,
+        resumed = opened.resume_from.is_some(),
+        "Client joined"
+    );
+    let stats = Arc::new(ClientStats::default());
+    let wire = match state.register(addr, &stream, codec, &hello, &opened, Arc::clone(&stats)) {
+        Ok(wire) => wire,
+        Err(e) => {
+            state.close_session(&opened.token, connection);
+            return Err(e);
+        }
+    };
+
+    let mut rejected = 0;
+    let mut reject = |reason: Rejection, error: &dyn std::fmt::Display| -> Result<()> {
+        rejected += 1;
+        let total = state.rejected.record(reason);
+        tracing::warn!(
+            addr = %addr,
+            client_id = %hello.client_id,
+            reason = ?reason,
+            error = %error,
+            rejected,
+            reason_total = total,
+            "Rejected frame from client"
+        );
+        if rejected >= MAX_REJECTED_FRAMES {
+            return Err(anyhow::anyhow!(
+                "Disconnecting {} after {} rejected frames",
+                addr,
+                rejected
+            ));
+        }
+        Ok(())
+    };
+
+    let limiter = state.limiter(&hello.client_id);
+    let mut chords = ChordTracker::default();
+    // Warn once when a client goes over the limit rather than on every frame.
+    let mut limited = false;
+    let mut relay = |mut events: Vec<KeyEvent>| -> Result<()> {
+        state.apply_key_policy(&hello.client_id, &mut chords, &mut events);
+        let mut limiter = limiter.lock().unwrap();
+        limiter.reconfigure(state.config().rate_limit);
+        let dropped = limiter.admit(&mut events);
+        let was_limited = std::mem::replace(&mut limited, dropped > 0);
+        if dropped > 0 {
+            stats
+                .events_rate_limited
+                .fetch_add(dropped as u64, Ordering::Relaxed);
+            state
+                .metrics
+                .events_rate_limited
+                .add(&hello.client_id, dropped as u64);
+            if was_limited {
+                tracing::debug!(addr = %addr, dropped, "Dropping key events over the rate limit");
+            } else {
+                tracing::warn!(
+                    addr = %addr,
+                    client_id = %hello.client_id,
+                    dropped,
+                    "Client is over the rate limit, dropping key events"
+                );
+            }
+            if !limiter.tolerate(dropped) {
+                return Err(anyhow::anyhow!(
+                    "Disconnecting {} for flooding key events",
+                    addr
+                ));
+            }
+        }
+        drop(limiter);
+        stats
+            .events_sent
+            .fetch_add(events.len() as u64, Ordering::Relaxed);
+        state
+            .metrics
+            .events_received
+            .add(&hello.client_id, events.len() as u64);
+        state.broadcast(events, Some(&addr))
+    };
+
+    let mut left = false;
+    let result = loop {
+        let payload = match wire.read_frame(&mut reader) {
+            Ok(payload) => payload,
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
                 tracing::info!("Client disconnected: {}", addr);
-                // Remove client from the map
-                let mut clients_map = clients.lock().unwrap();
-                clients_map.remove(&addr);
-                break;
+                break Ok(());
             }
-            Ok(size) => {
-                broadcast(&buf[..size], &clients, Some(&addr))?;
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                // The rest of the frame is still unread, so there is no
+                // telling where the next one starts.
+                let _ = reject(Rejection::Oversized, &e);
+                break Err(anyhow::anyhow!("Disconnecting {}: {}", addr, e));
             }
             Err(e) => {
-                // Remove client from the map
-                let mut clients_map = clients.lock().unwrap();
-                clients_map.remove(&addr);
-                return Err(anyhow::anyhow!("Error reading from client {}: {}", addr, e));
+                break Err(anyhow::anyhow!("Error reading from client {}: {}", addr, e));
+            }
+        };
+
+        stats
+            .bytes_sent
+            .fetch_add(payload.len() as u64, Ordering::Relaxed);
+        let outcome = match wire.decode(&payload) {
+            Ok(Message::Key(event)) => match event.validate() {
+                Ok(()) => relay(vec![event]),
+                Err(e) => reject(Rejection::Invalid, &e),
+            },
+            Ok(Message::Batch(events)) => match validate_batch(&events) {
+                Ok(()) => relay(events),
+                Err(e) => reject(Rejection::Invalid, &e),
+            },
+            Ok(Message::Ack(mut ack)) => {
+                ack.receiver = opened.peer_id;
+                if let Err(e) = state.relay_ack(ack) {
+                    tracing::warn!(addr = %addr, error = %e, "Failed to relay ack");
+                }
+                Ok(())
+            }
+            Ok(Message::Ping(ping)) => {
+                // Broadcasts write to this socket from other threads while
+                // holding the clients lock; hold it too so frames don't
+                // interleave.
+                let _clients = state.clients.lock().unwrap();
+                let pong = Pong {
+                    sent_us: ping.sent_us,
+                    server_us: now_micros(),
+                };
+                let sent = wire
+                    .encode(&Message::Pong(pong))
+                    .and_then(|frame| write_or_drop(&stream, &frame));
+                if let Err(e) = sent {
+                    tracing::warn!(addr = %addr, error = %e, "Failed to answer ping");
+                }
+                Ok(())
             }
+            Ok(Message::Goodbye(goodbye)) => {
+                tracing::info!(
+                    addr = %addr,
+                    client_id = %hello.client_id,
+                    reason = ?goodbye.reason,
+                    "Client is leaving"
+                );
+                left = true;
+                break Ok(());
+            }
+            Ok(other) => reject(Rejection::Unexpected, &format!("{:?}", other)),
+            Err(e) => reject(Rejection::Malformed, &e),
+        };
+        if let Err(e) = outcome {
+            break Err(e);
         }
+    };
+
+    // Remove client from the map
+    state.clients.lock().unwrap().remove(&addr);
+    if left {
+        state.end_session(&opened.token, connection);
+    } else {
+        state.close_session(&opened.token, connection);
     }
-    Ok(())
+    result
 }
 
-#[tracing::instrument(skip_all, fields(payload_size = payload.len(), sender = ?_sender), err(Debug))]
-fn broadcast(
-    payload: &[u8],
-    clients: &Arc<Mutex<HashMap<SocketAddr, TcpStream>>>,
-    _sender: Option<&SocketAddr>,
-) -> Result<()> {
-    let clients = clients.lock().unwrap();
-    tracing::debug!(clients = ?clients, client_count = clients.len(), "Broadcasting payload");
-    for (addr, client) in clients.iter() {
-        let span = tracing::debug_span!("write_to_client", addr = %addr);
-        let _enter = span.enter();
-        tracing::debug!("write");
-
-        client
-            .try_clone()
-            .context(format!("Failed to clone client stream for {}", addr))?
-            .write_all(payload)
-            .context(format!("Error broadcasting to {}", addr))?;
+fn validate_batch(events: &[KeyEvent]) -> Result<(), String> {
+    if events.len() > MAX_BATCH_LEN {
+        return Err(format!(
+            "batch of {} events exceeds maximum of {}",
+            events.len(),
+            MAX_BATCH_LEN
+        ));
     }
-    Ok(())
+    events.iter().try_for_each(KeyEvent::validate)
 }
 
-pub fn run(bind_address: &str) -> Result<()> {
-    let server = Server::new();
-    let (_chan, handle) = server.start(bind_address)?;
+/// Runs the server until it is shut down by a signal. With a `reloader`, the
+/// config file it came from is watched for changes.
+pub fn run(config: ServerConfig, reloader: Option<ConfigReloader>) -> Result<()> {
+    let list = |ranges: &[KeyRange]| ranges.iter().map(ToString::to_string).collect::<Vec<_>>();
+    tracing::info!(
+        allow = ?list(&config.key_filter.allow),
+        deny = ?list(&config.key_filter.deny),
+        deny_chords = ?config.deny_chords.iter().map(ToString::to_string).collect::<Vec<_>>(),
+        "Key policy"
+    );
+    let server = Server::new(config.clone());
+    if let Some(metrics_address) = &config.metrics_address {
+        server.serve_metrics(metrics_address)?;
+    }
+    if let Some(admin_address) = &config.admin_address {
+        config
+            .check_admin_access()
+            .map_err(|e| anyhow::anyhow!("admin_token {}", e))?;
+        server.serve_admin(admin_address)?;
+    }
+    if let Some(reloader) = reloader {
+        server.watch_config(reloader)?;
+    }
+    let (shutdown, handle) = server.start(&config.bind_address)?;
+
+    let state = Arc::clone(&server.state);
+    crate::utils::on_shutdown_signal(move |_| {
+        let _ = shutdown.send(state.config().shutdown_retry_after);
+    })?;
+
     match handle.join() {
         Ok(result) => result.context("Server execution failed")?,
         Err(e) => return Err(anyhow::anyhow!("Server thread panicked: {:?}", e)),
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::Resume;
+
+    fn hello(client_id: &str, resume: Option<&Opened>) -> Hello {
+        Hello {
+            client_id: client_id.to_string(),
+            resume: resume.map(|opened| Resume {
+                session_token: opened.token.clone(),
+                last_seq: 0,
+            }),
+            compression: Vec::new(),
+            tags: Vec::new(),
+            channel: None,
+            secret: None,
+        }
+    }
+
+    fn state() -> Arc<ServerState> {
+        Server::new(ServerConfig::for_tests()).state
+    }
+
+    #[test]
+    fn admits_clients_with_a_secret_to_known_channels() {
+        let mut config = ServerConfig::for_tests();
+        config.auth_secrets = vec!["s3cret".to_string()];
+        config.channels = vec!["lobby".to_string()];
+        let state = Server::new(config).state;
+        let hello = |secret: Option<&str>, channel: Option<&str>| Hello {
+            secret: secret.map(String::from),
+            channel: channel.map(String::from),
+            ..hello("a", None)
+        };
+
+        assert_eq!(state.admit(&hello(Some("s3cret"), Some("lobby"))), Ok(()));
+        let refused = Err("authentication failed".to_string());
+        assert_eq!(state.admit(&hello(None, Some("lobby"))), refused);
+        assert_eq!(state.admit(&hello(Some("s3cre"), Some("lobby"))), refused);
+        assert_eq!(
+            state.admit(&hello(Some("s3cret"), None)),
+            Err("unknown channel default".to_string())
+        );
+    }
+
+    #[test]
+    fn admits_anyone_by_default() {
+        assert_eq!(state().admit(&hello("a", None)), Ok(()));
+    }
+
+    #[test]
+    fn peers_are_only_announced_within_a_channel() {
+        let state = state();
+        let in_channel = |client_id: &str, channel: &str| Hello {
+            channel: Some(channel.to_string()),
+            ..hello(client_id, None)
+        };
+        state.open_session(&in_channel("a", "one"), 1).unwrap();
+        state.open_session(&in_channel("b", "two"), 2).unwrap();
+        state.open_session(&hello("c", None), 3).unwrap();
+
+        let names = |channel| {
+            let mut names: Vec<String> = state
+                .peers(channel)
+                .into_iter()
+                .map(|peer| peer.client_id)
+                .collect();
+            names.sort();
+            names
+        };
+        assert_eq!(names("one"), ["a", OPERATOR_CLIENT_ID]);
+        assert_eq!(names("default"), ["c", OPERATOR_CLIENT_ID]);
+    }
+
+    #[test]
+    fn a_session_resumes_only_in_its_channel() {
+        let state = state();
+        let opened = state.open_session(&hello("a", None), 1).unwrap();
+        state.close_session(&opened.token, 1);
+        let elsewhere = Hello {
+            channel: Some("other".to_string()),
+            ..hello("a", Some(&opened))
+        };
+        let reopened = state.open_session(&elsewhere, 2).unwrap();
+        assert_eq!(reopened.resume_from, None);
+        assert_ne!(reopened.peer_id, opened.peer_id);
+    }
+
+    fn flood(limiter: &Mutex<RateLimiter>) -> usize {
+        let mut events = vec![KeyEvent::default(); 1000];
+        limiter.lock().unwrap().admit(&mut events)
+    }
+
+    #[test]
+    fn drops_a_client_that_stops_reading() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server_side, _) = listener.accept().unwrap();
+        server_side
+            .set_write_timeout(Some(Duration::from_millis(50)))
+            .unwrap();
+
+        // More than the socket buffers hold, while the client reads nothing.
+        let frame = vec![0; 64 * 1024 * 1024];
+        assert!(write_or_drop(&server_side, &frame).is_err());
+        assert!(write_or_drop(&server_side, b"more").is_err());
+        drop(client);
+    }
+
+    #[test]
+    fn denies_ctrl_alt_del_from_one_client() {
+        let state = state();
+        let mut events: Vec<KeyEvent> = [
+            evdev::KeyCode::KEY_LEFTCTRL,
+            evdev::KeyCode::KEY_RIGHTALT,
+            evdev::KeyCode::KEY_DELETE,
+            evdev::KeyCode::KEY_A,
+        ]
+        .iter()
+        .map(|key| KeyEvent {
+            key: key.code(),
+            ..KeyEvent::default()
+        })
+        .collect();
+        state.apply_key_policy("a", &mut ChordTracker::default(), &mut events);
+        let keys: Vec<u16> = events.iter().map(|event| event.key).collect();
+        assert_eq!(
+            keys,
+            [
+                evdev::KeyCode::KEY_LEFTCTRL.code(),
+                evdev::KeyCode::KEY_RIGHTALT.code(),
+                evdev::KeyCode::KEY_A.code()
+            ]
+        );
+
+        // Another client's Delete doesn't complete the first one's chord.
+        let mut events = vec![KeyEvent {
+            key: evdev::KeyCode::KEY_DELETE.code(),
+            ..KeyEvent::default()
+        }];
+        state.apply_key_policy("b", &mut ChordTracker::default(), &mut events);
+        assert_eq!(events.len(), 1);
+    }
+
+    #[test]
+    fn rate_limits_survive_a_reconnect() {
+        let state = state();
+        let limiter = state.limiter("a");
+        assert!(flood(&limiter) > 0);
+        drop(limiter);
+
+        // Another client doesn't evict it, and reconnecting gets it back.
+        assert_eq!(flood(&state.limiter("b")), 1000 - 40);
+        let limiter = state.limiter("a");
+        assert_eq!(flood(&limiter), 1000);
+        drop(limiter);
+
+        // Clients gone with full buckets are forgotten, the others kept.
+        drop(state.limiter("c"));
+        drop(state.limiter("d"));
+        let limiters = state.limiters.lock().unwrap();
+        let mut ids: Vec<&str> = limiters.keys().map(String::as_str).collect();
+        ids.sort();
+        assert_eq!(ids, ["a", "b", "d"]);
+    }
+
+    #[test]
+    fn resumes_a_session_with_its_token() {
+        let state = state();
+        let opened = state.open_session(&hello("a", None), 1).unwrap();
+        // Taken while connected, unless it is a resume.
+        assert!(state.open_session(&hello("a", None), 2).is_err());
+        let resumed = state.open_session(&hello("a", Some(&opened)), 2).unwrap();
+        assert_eq!(resumed.peer_id, opened.peer_id);
+        assert_eq!(resumed.resume_from, Some(0));
+    }
+
+    #[test]
+    fn a_replaced_connection_leaves_the_session_alone() {
+        let state = state();
+        let opened = state.open_session(&hello("a", None), 1).unwrap();
+        state.open_session(&hello("a", Some(&opened)), 2).unwrap();
+
+        // The old connection only notices now that it is gone.
+        state.close_session(&opened.token, 1);
+        state.end_session(&opened.token, 1);
+        let sessions = state.sessions.lock().unwrap();
+        let session = &sessions[&opened.token];
+        assert_eq!(session.connection, 2);
+        assert!(session.disconnected_at.is_none());
+    }
+
+    #[test]
+    fn the_owning_connection_closes_and_ends_the_session() {
+        let state = state();
+        let opened = state.open_session(&hello("a", None), 1).unwrap();
+        state.close_session(&opened.token, 1);
+        assert!(
+            state.sessions.lock().unwrap()[&opened.token]
+                .disconnected_at
+                .is_some()
+        );
+        let resumed = state.open_session(&hello("a", Some(&opened)), 2).unwrap();
+        state.end_session(&resumed.token, 2);
+        assert!(state.sessions.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,710 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::net::ToSocketAddrs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::key_filter::{Chord, KeyFilter, KeyRange};
+use crate::rate_limit::{Rate, RateLimitConfig};
+use crate::routing::{Route, Selector};
+use crate::server::ReplayConfig;
+
+/// Everything the server can be configured with.
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    pub bind_address: String,
+    pub admin_address: Option<String>,
+    /// Bearer token the admin API requires. Required unless the admin API
+    /// only listens on a loopback address.
+    pub admin_token: Option<String>,
+    pub metrics_address: Option<String>,
+    /// Refuse new clients once this many are connected.
+    pub max_clients: Option<usize>,
+    /// Clients must present one of these; anyone may connect if empty.
+    pub auth_secrets: Vec<String>,
+    /// The channels clients may join; any if empty.
+    pub channels: Vec<String>,
+    /// Where each client's key events go within its channel; to everyone in
+    /// it if empty.
+    pub routes: Vec<Route>,
+    pub replay: ReplayConfig,
+    pub rate_limit: RateLimitConfig,
+    /// Which keys may be relayed at all.
+    pub key_filter: KeyFilter,
+    /// Chords whose last key isn't relayed while the others are held.
+    pub deny_chords: Vec<Chord>,
+    /// How long after its press a key counts as held for `deny_chords`.
+    pub chord_window: Duration,
+    /// How long clients are asked to wait before reconnecting after a shutdown.
+    pub shutdown_retry_after: Duration,
+}
+
+// The config file, as written. Anything left out keeps its command line value.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawServerConfig {
+    bind_address: Option<String>,
+    admin_address: Option<String>,
+    admin_token: Option<String>,
+    metrics_address: Option<String>,
+    max_clients: Option<usize>,
+    auth: Option<RawAuth>,
+    channels: Option<Vec<String>>,
+    routes: Option<Vec<RawRoute>>,
+    replay: Option<RawReplay>,
+    rate_limit: Option<RawRateLimit>,
+    keys: Option<RawKeys>,
+    shutdown_retry_after_secs: Option<u64>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawAuth {
+    secrets: Option<Vec<String>>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawRoute {
+    from: String,
+    to: Vec<String>,
+}
+
+impl RawRoute {
+    fn parse(&self) -> Result<Route, String> {
+        if self.to.is_empty() {
+            return Err(format!("route from {} goes nowhere", self.from));
+        }
+        Ok(Route {
+            from: Selector::from_str(&self.from)?,
+            to: self
+                .to
+                .iter()
+                .map(|to| Selector::from_str(to))
+                .collect::<Result<_, _>>()?,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawReplay {
+    buffer_size: Option<usize>,
+    max_age_ms: Option<u64>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawRateLimit {
+    per_sec: Option<f64>,
+    burst: Option<u32>,
+    key_per_sec: Option<f64>,
+    key_burst: Option<u32>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawKeys {
+    allow: Option<Vec<String>>,
+    deny: Option<Vec<String>>,
+    deny_chords: Option<Vec<String>>,
+    chord_window_ms: Option<u64>,
+}
+
+impl ServerConfig {
+    /// Refuses to serve the admin API beyond this machine without a token,
+    /// as it can press keys on every client.
+    pub fn check_admin_access(&self) -> Result<(), String> {
+        match &self.admin_address {
+            Some(addr) if self.admin_token.is_none() && !is_loopback(addr) => Err(format!(
+                "must be set to serve the admin API on {}, which is not a loopback address",
+                addr
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Whether `addr` only resolves to loopback addresses.
+fn is_loopback(addr: &str) -> bool {
+    match addr.to_socket_addrs() {
+        Ok(addrs) => {
+            let addrs: Vec<_> = addrs.collect();
+            !addrs.is_empty() && addrs.iter().all(|addr| addr.ip().is_loopback())
+        }
+        Err(_) => false,
+    }
+}
+
+#[cfg(test)]
+impl ServerConfig {
+    /// The command line defaults.
+    pub fn for_tests() -> Self {
+        ServerConfig {
+            bind_address: "127.0.0.1:1234".to_string(),
+            admin_address: None,
+            admin_token: None,
+            metrics_address: None,
+            max_clients: None,
+            auth_secrets: Vec::new(),
+            channels: Vec::new(),
+            routes: Vec::new(),
+            replay: ReplayConfig {
+                capacity: 256,
+                max_age: Duration::from_secs(5),
+            },
+            rate_limit: RateLimitConfig::default(),
+            key_filter: KeyFilter::default(),
+            deny_chords: crate::key_filter::DANGEROUS_CHORDS
+                .iter()
+                .map(|chord| Chord::from_str(chord).unwrap())
+                .collect(),
+            chord_window: Duration::from_millis(500),
+            shutdown_retry_after: Duration::from_secs(5),
+        }
+    }
+}
+
+/// Settings that only take effect when the server starts.
+const RESTART_ONLY: [&str; 3] = ["bind_address", "admin_address", "metrics_address"];
+
+/// Settings whose values are never logged.
+const SECRET: [&str; 2] = ["admin_token", "auth.secrets"];
+
+/// Problems with individual settings, by dotted path (`rate_limit.burst`).
+type FieldErrors = BTreeMap<String, String>;
+
+/// Settings given on the command line, by the dotted path they have in the
+/// file. These win over the file.
+pub type Overridden = BTreeSet<String>;
+
+impl RawServerConfig {
+    fn load(path: &Path) -> Result<Self> {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        // An empty file is an empty config rather than an error.
+        if text.trim().is_empty() {
+            return Ok(Self::default());
+        }
+        serde_norway::from_str(&text).with_context(|| format!("Invalid {}", path.display()))
+    }
+
+    /// This file without the settings in `overridden`.
+    fn without(&self, overridden: &Overridden) -> Self {
+        let Ok(mut value) = serde_json::to_value(self) else {
+            return self.clone();
+        };
+        for path in overridden {
+            if let Some(setting) = value.pointer_mut(&format!("/{}", path.replace('.', "/"))) {
+                *setting = serde_json::Value::Null;
+            }
+        }
+        serde_json::from_value(value).unwrap_or_else(|_| self.clone())
+    }
+
+    /// Applies the settings in this file on top of `base`.
+    fn resolve(&self, base: &ServerConfig) -> Result<ServerConfig, FieldErrors> {
+        let mut errors = FieldErrors::new();
+        let mut config = base.clone();
+
+        let mut address = |path: &str, value: &Option<String>, target: &mut String| {
+            if let Some(value) = value {
+                match value.to_socket_addrs() {
+                    Ok(_) => *target = value.clone(),
+                    Err(e) => {
+                        errors.insert(path.to_string(), format!("invalid address: {}", e));
+                    }
+                }
+            }
+        };
+        address("bind_address", &self.bind_address, &mut config.bind_address);
+        let mut admin = config.admin_address.clone().unwrap_or_default();
+        address("admin_address", &self.admin_address, &mut admin);
+        let mut metrics = config.metrics_address.clone().unwrap_or_default();
+        address("metrics_address", &self.metrics_address, &mut metrics);
+        if self.admin_address.is_some() {
+            config.admin_address = Some(admin);
+        }
+        if self.metrics_address.is_some() {
+            config.metrics_address = Some(metrics);
+        }
+        match &self.admin_token {
+            Some(token) if token.is_empty() => {
+                errors.insert("admin_token".to_string(), "must not be empty".to_string());
+            }
+            Some(token) => config.admin_token = Some(token.clone()),
+            None => {}
+        }
+        if let Err(e) = config.check_admin_access() {
+            errors.entry("admin_token".to_string()).or_insert(e);
+        }
+
+        match self.max_clients {
+            Some(0) => {
+                errors.insert("max_clients".to_string(), "must be at least 1".to_string());
+            }
+            Some(max) => config.max_clients = Some(max),
+            None => {}
+        }
+
+        if let Some(secrets) = self.auth.as_ref().and_then(|auth| auth.secrets.as_ref()) {
+            if secrets.iter().any(String::is_empty) {
+                errors.insert("auth.secrets".to_string(), "must not be empty".to_string());
+            } else {
+                config.auth_secrets = secrets.clone();
+            }
+        }
+        if let Some(channels) = &self.channels {
+            if channels.iter().any(String::is_empty) {
+                errors.insert(
+                    "channels".to_string(),
+                    "channel names must not be empty".to_string(),
+                );
+            } else {
+                config.channels = channels.clone();
+            }
+        }
+        if let Some(routes) = &self.routes {
+            match routes.iter().map(RawRoute::parse).collect() {
+                Ok(routes) => config.routes = routes,
+                Err(e) => {
+                    errors.insert("routes".to_string(), e);
+                }
+            }
+        }
+
+        if let Some(replay) = &self.replay {
+            if let Some(size) = replay.buffer_size {
+                config.replay.capacity = size;
+            }
+            if let Some(ms) = replay.max_age_ms {
+                config.replay.max_age = Duration::from_millis(ms);
+            }
+        }
+
+        if let Some(limits) = &self.rate_limit {
+            let mut rate =
+                |path: &str, per_sec: Option<f64>, burst: Option<u32>, rate: &mut Rate| {
+                    let per_sec = per_sec.unwrap_or(rate.per_sec);
+                    let burst = burst.unwrap_or(rate.burst);
+                    if !per_sec.is_finite() || per_sec < 0.0 {
+                        errors.insert(
+                            format!("rate_limit.{}per_sec", path),
+                            "must be 0 (no limit) or more".to_string(),
+                        );
+                    } else if per_sec > 0.0 && burst == 0 {
+                        errors.insert(
+                            format!("rate_limit.{}burst", path),
+                            "must be at least 1 while the rate is limited".to_string(),
+                        );
+                    } else {
+                        *rate = Rate { per_sec, burst };
+                    }
+                };
+            rate(
+                "",
+                limits.per_sec,
+                limits.burst,
+                &mut config.rate_limit.client,
+            );
+            rate(
+                "key_",
+                limits.key_per_sec,
+                limits.key_burst,
+                &mut config.rate_limit.key,
+            );
+        }
+
+        if let Some(keys) = &self.keys {
+            let mut ranges =
+                |path: &str, keys: &Option<Vec<String>>, target: &mut Vec<KeyRange>| {
+                    let Some(keys) = keys else { return };
+                    let parsed: Result<Vec<KeyRange>, String> =
+                        keys.iter().map(|k| KeyRange::from_str(k)).collect();
+                    match parsed {
+                        Ok(parsed) => *target = parsed,
+                        Err(e) => {
+                            errors.insert(path.to_string(), e);
+                        }
+                    }
+                };
+            ranges("keys.allow", &keys.allow, &mut config.key_filter.allow);
+            ranges("keys.deny", &keys.deny, &mut config.key_filter.deny);
+            if let Some(chords) = &keys.deny_chords {
+                match chords.iter().map(|c| Chord::from_str(c)).collect() {
+                    Ok(chords) => config.deny_chords = chords,
+                    Err(e) => {
+                        errors.insert("keys.deny_chords".to_string(), e);
+                    }
+                }
+            }
+            if let Some(ms) = keys.chord_window_ms {
+                config.chord_window = Duration::from_millis(ms);
+            }
+        }
+
+        if let Some(secs) = self.shutdown_retry_after_secs {
+            config.shutdown_retry_after = Duration::from_secs(secs);
+        }
+
+        if errors.is_empty() {
+            Ok(config)
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// Every setting in the file, by dotted path, rendered for display.
+    fn settings(&self) -> BTreeMap<String, String> {
+        fn flatten(prefix: &str, value: &serde_json::Value, out: &mut BTreeMap<String, String>) {
+            match value {
+                serde_json::Value::Null => {}
+                serde_json::Value::Object(fields) => {
+                    for (name, value) in fields {
+                        let path = if prefix.is_empty() {
+                            name.clone()
+                        } else {
+                            format!("{}.{}", prefix, name)
+                        };
+                        flatten(&path, value, out);
+                    }
+                }
+                other => {
+                    out.insert(prefix.to_string(), other.to_string());
+                }
+            }
+        }
+        let mut out = BTreeMap::new();
+        if let Ok(value) = serde_json::to_value(self) {
+            flatten("", &value, &mut out);
+        }
+        out
+    }
+}
+
+/// Describes how `new` differs from `old`, one setting per line, with the
+/// problem next to each setting that failed.
+fn diff(old: &RawServerConfig, new: &RawServerConfig, errors: &FieldErrors) -> String {
+    let old = old.settings();
+    let new = new.settings();
+    let mut paths: Vec<&String> = old.keys().chain(new.keys()).collect();
+    paths.extend(errors.keys());
+    paths.sort();
+    paths.dedup();
+
+    let mut lines = Vec::new();
+    for path in paths {
+        let before = old.get(path).map_or("(unset)", String::as_str);
+        let after = new.get(path).map_or("(unset)", String::as_str);
+        let error = errors.get(path);
+        if before == after && error.is_none() {
+            continue;
+        }
+        let (before, after) = if SECRET.contains(&path.as_str()) {
+            let hide = |value| {
+                if value == "(unset)" {
+                    value
+                } else {
+                    "(hidden)"
+                }
+            };
+            (hide(before), hide(after))
+        } else {
+            (before, after)
+        };
+        let mut line = format!("  {}: {} -> {}", path, before, after);
+        if let Some(error) = error {
+            line.push_str(&format!("  <- {}", error));
+        }
+        lines.push(line);
+    }
+    lines.join("\n")
+}
+
+/// Re-reads the server config file and decides whether the result can be
+/// applied to the running server.
+pub struct ConfigReloader {
+    path: PathBuf,
+    /// Settings from the command line, which the file is applied on top of.
+    base: ServerConfig,
+    overridden: Overridden,
+    /// The file as last applied.
+    applied: RawServerConfig,
+}
+
+impl ConfigReloader {
+    fn new(
+        path: PathBuf,
+        base: ServerConfig,
+        overridden: Overridden,
+        applied: RawServerConfig,
+    ) -> Self {
+        Self {
+            path,
+            base,
+            overridden,
+            applied,
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns the new config to switch to, or `None` if the file didn't
+    /// change anything or is invalid; either way, the outcome is logged.
+    pub fn reload(&mut self, current: &ServerConfig) -> Option<ServerConfig> {
+        let raw = match RawServerConfig::load(&self.path) {
+            Ok(raw) => raw,
+            Err(e) => {
+                tracing::error!(
+                    "Rejected config reload, keeping the current config: {:#}",
+                    e
+                );
+                return None;
+            }
+        };
+        let mut config = match raw.without(&self.overridden).resolve(&self.base) {
+            Ok(config) => config,
+            Err(errors) => {
+                tracing::error!(
+                    "Rejected reload of {}, keeping the current config:\n{}",
+                    self.path.display(),
+                    diff(&self.applied, &raw, &errors)
+                );
+                return None;
+            }
+        };
+
+        let changes = diff(&self.applied, &raw, &FieldErrors::new());
+        if changes.is_empty() {
+            tracing::debug!(path = %self.path.display(), "Config file unchanged");
+            return None;
+        }
+        tracing::info!("Reloaded {}:\n{}", self.path.display(), changes);
+
+        let old = self.applied.settings();
+        let new = raw.settings();
+        for setting in RESTART_ONLY {
+            if old.get(setting) != new.get(setting) {
+                tracing::warn!(setting, "Setting only takes effect after a restart");
+            }
+        }
+        for setting in &self.overridden {
+            if old.get(setting) != new.get(setting) {
+                tracing::warn!(%setting, "Setting is overridden on the command line");
+            }
+        }
+        config.bind_address = current.bind_address.clone();
+        config.admin_address = current.admin_address.clone();
+        config.metrics_address = current.metrics_address.clone();
+
+        self.applied = raw;
+        Some(config)
+    }
+}
+
+/// Loads the config file at `path` on top of the settings in `base`, except
+/// for those `overridden` on the command line. Returns the config to start
+/// with and a reloader for later.
+pub fn load(
+    path: PathBuf,
+    base: ServerConfig,
+    overridden: Overridden,
+) -> Result<(ServerConfig, ConfigReloader)> {
+    let raw = RawServerConfig::load(&path)?;
+    let settings = raw.settings();
+    for setting in overridden.iter().filter(|s| settings.contains_key(*s)) {
+        tracing::info!(%setting, "Command line overrides the config file");
+    }
+    let config = raw.without(&overridden).resolve(&base).map_err(|errors| {
+        anyhow::anyhow!(
+            "Invalid {}:\n{}",
+            path.display(),
+            diff(&RawServerConfig::default(), &raw, &errors)
+        )
+    })?;
+    tracing::info!(path = %path.display(), "Loaded server config");
+    Ok((config, ConfigReloader::new(path, base, overridden, raw)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn base() -> ServerConfig {
+        ServerConfig::for_tests()
+    }
+
+    fn raw(yaml: &str) -> RawServerConfig {
+        serde_norway::from_str(yaml).unwrap()
+    }
+
+    #[test]
+    fn applies_only_what_the_file_sets() {
+        let config = raw("max_clients: 3\nrate_limit:\n  burst: 10\n")
+            .resolve(&base())
+            .unwrap();
+        assert_eq!(config.max_clients, Some(3));
+        assert_eq!(config.rate_limit.client.burst, 10);
+        assert_eq!(config.rate_limit.client.per_sec, 100.0);
+        assert_eq!(config.rate_limit.key, RateLimitConfig::default().key);
+        assert_eq!(config.replay.capacity, 256);
+        assert_eq!(config.bind_address, "127.0.0.1:1234");
+    }
+
+    #[test]
+    fn reports_each_bad_setting() {
+        let errors = raw(concat!(
+            "max_clients: 0\n",
+            "rate_limit:\n  per_sec: 5\n  burst: 0\n  key_per_sec: -1\n",
+            "keys:\n  deny: [KEY_POWER, KEY_NOPE]\n",
+        ))
+        .resolve(&base())
+        .unwrap_err();
+        let paths: Vec<&str> = errors.keys().map(String::as_str).collect();
+        assert_eq!(
+            paths,
+            [
+                "keys.deny",
+                "max_clients",
+                "rate_limit.burst",
+                "rate_limit.key_per_sec"
+            ]
+        );
+        assert_eq!(errors["keys.deny"], "unknown key: KEY_NOPE");
+    }
+
+    #[test]
+    fn reads_auth_channels_and_routes() {
+        let config = raw(concat!(
+            "auth:\n  secrets: [s3cret]\n",
+            "channels: [lobby, game]\n",
+            "routes:\n  - from: tag:lead\n    to: ['*']\n  - from: alice\n    to: [bob, tag:echo]\n",
+        ))
+        .resolve(&base())
+        .unwrap();
+        assert_eq!(config.auth_secrets, ["s3cret"]);
+        assert_eq!(config.channels, ["lobby", "game"]);
+        assert_eq!(
+            config.routes,
+            [
+                Route {
+                    from: Selector::Tag("lead".to_string()),
+                    to: vec![Selector::Any],
+                },
+                Route {
+                    from: Selector::ClientId("alice".to_string()),
+                    to: vec![
+                        Selector::ClientId("bob".to_string()),
+                        Selector::Tag("echo".to_string())
+                    ],
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_bad_auth_channels_and_routes() {
+        let errors = raw(concat!(
+            "auth:\n  secrets: ['']\n",
+            "channels: ['']\n",
+            "routes:\n  - from: alice\n    to: []\n",
+        ))
+        .resolve(&base())
+        .unwrap_err();
+        let paths: Vec<&str> = errors.keys().map(String::as_str).collect();
+        assert_eq!(paths, ["auth.secrets", "channels", "routes"]);
+        assert_eq!(errors["routes"], "route from alice goes nowhere");
+    }
+
+    #[test]
+    fn reads_chords() {
+        let config = raw("keys:\n  deny_chords: [ctrl+shift+esc]\n  chord_window_ms: 200\n")
+            .resolve(&base())
+            .unwrap();
+        let chords: Vec<String> = config.deny_chords.iter().map(ToString::to_string).collect();
+        assert_eq!(chords, ["KEY_LEFTCTRL+KEY_LEFTSHIFT+KEY_ESC"]);
+        assert_eq!(config.chord_window, Duration::from_millis(200));
+
+        let errors = raw("keys:\n  deny_chords: [delete]\n")
+            .resolve(&base())
+            .unwrap_err();
+        assert_eq!(
+            errors["keys.deny_chords"],
+            "chord delete needs at least two keys"
+        );
+    }
+
+    #[test]
+    fn a_zero_burst_is_fine_without_a_limit() {
+        let config = raw("rate_limit:\n  per_sec: 0\n  burst: 0\n")
+            .resolve(&base())
+            .unwrap();
+        assert_eq!(config.rate_limit.client.per_sec, 0.0);
+    }
+
+    #[test]
+    fn command_line_settings_win() {
+        let overridden: Overridden = ["rate_limit.per_sec", "max_clients"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+        let mut base = base();
+        base.rate_limit.client.per_sec = 5.0;
+        let config = raw("max_clients: 3\nrate_limit:\n  per_sec: 50\n  burst: 7\n")
+            .without(&overridden)
+            .resolve(&base)
+            .unwrap();
+        assert_eq!(config.rate_limit.client.per_sec, 5.0);
+        assert_eq!(config.rate_limit.client.burst, 7);
+        assert_eq!(config.max_clients, None);
+    }
+
+    #[test]
+    fn diff_shows_changes_and_errors() {
+        let old = raw("max_clients: 3\nreplay:\n  buffer_size: 10\n");
+        let new = raw("max_clients: 0\nrate_limit:\n  burst: 5\n");
+        let errors = new.resolve(&base()).unwrap_err();
+        assert_eq!(
+            diff(&old, &new, &errors),
+            [
+                "  max_clients: 3 -> 0  <- must be at least 1",
+                "  rate_limit.burst: (unset) -> 5",
+                "  replay.buffer_size: 10 -> (unset)",
+            ]
+            .join("\n")
+        );
+        assert_eq!(diff(&old, &old, &FieldErrors::new()), "");
+    }
+
+    #[test]
+    fn admin_api_beyond_loopback_needs_a_token() {
+        assert!(
+            raw("admin_address: 127.0.0.1:1235\n")
+                .resolve(&base())
+                .is_ok()
+        );
+        let errors = raw("admin_address: 0.0.0.0:1235\n")
+            .resolve(&base())
+            .unwrap_err();
+        assert!(errors.contains_key("admin_token"));
+        let config = raw("admin_address: 0.0.0.0:1235\nadmin_token: hunter2\n")
+            .resolve(&base())
+            .unwrap();
+        assert_eq!(config.admin_token.as_deref(), Some("hunter2"));
+    }
+
+    #[test]
+    fn diff_hides_secrets() {
+        let old = raw("admin_token: hunter2\n");
+        let new = raw("admin_token: hunter3\n");
+        assert_eq!(
+            diff(&old, &new, &FieldErrors::new()),
+            "  admin_token: (hidden) -> (hidden)"
+        );
+    }
+}
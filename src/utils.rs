@@ -1,11 +1,12 @@
 use std::fs::OpenOptions;
 use std::io::{self, ErrorKind};
+use std::path::Path;
 
 /// Opens a file for reading and writing, creating it if it doesn't exist.
 /// If the file already exists, it opens it without creating a new one.
 /// Returns a tuple containing the file handle and a boolean indicating
 /// whether the file was newly created or already existed.
-pub fn open_or_create(path: &str) -> io::Result<(std::fs::File, bool)> {
+pub fn open_or_create(path: &Path) -> io::Result<(std::fs::File, bool)> {
     // Try to create it exclusively first
     match OpenOptions::new()
         .read(true)
@@ -22,3 +23,63 @@ pub fn open_or_create(path: &str) -> io::Result<(std::fs::File, bool)> {
         Err(e) => Err(e), // Other error
     }
 }
+
+/// Compares a secret in constant time, so it can't be guessed byte by byte
+/// from how long a wrong one takes to refuse.
+pub fn secrets_match(secret: &str, given: &str) -> bool {
+    secret.len() == given.len()
+        && secret
+            .bytes()
+            .zip(given.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+/// Calls `shutdown` from a background thread on the first SIGINT or SIGTERM,
+/// and exits the process right away on a second one.
+pub fn on_shutdown_signal(shutdown: impl FnOnce(i32) + Send + 'static) -> anyhow::Result<()> {
+    use signal_hook::consts::{SIGINT, SIGTERM};
+
+    let mut signals = signal_hook::iterator::Signals::new([SIGINT, SIGTERM])
+        .map_err(|e| anyhow::anyhow!("Failed to install signal handlers: {}", e))?;
+    std::thread::spawn(move || {
+        let mut signals = signals.forever();
+        if let Some(signal) = signals.next() {
+            tracing::info!(signal, "Received signal, shutting down");
+            shutdown(signal);
+        }
+        if signals.next().is_some() {
+            tracing::warn!("Received second signal, exiting immediately");
+            std::process::exit(1);
+        }
+    });
+    Ok(())
+}
+
+/// The candidate closest to `word`, ignoring case, if it is close enough to
+/// be a likely typo.
+pub fn closest<'a>(word: &str, candidates: impl IntoIterator<Item = &'a str>) -> Option<&'a str> {
+    let word = word.to_uppercase();
+    let max_distance = (word.chars().count() / 3).max(1);
+    candidates
+        .into_iter()
+        .map(|candidate| (edit_distance(&word, &candidate.to_uppercase()), candidate))
+        .filter(|(distance, _)| *distance <= max_distance)
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, candidate)| candidate)
+}
+
+/// The Levenshtein distance between `a` and `b`.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, a) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, b) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(a != *b);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
@@ -0,0 +1,52 @@
+use anyhow::{Context, Result};
+use inotify::{Inotify, WatchMask};
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+
+/// Editors often save in several steps; changes this close together are
+/// reported once.
+const SETTLE_TIME: Duration = Duration::from_millis(100);
+
+/// Calls `on_change` from a background thread whenever the file at `path` is
+/// written, created or replaced. The parent directory is watched, since many
+/// editors save by renaming a new file over the old one.
+pub fn watch_file(path: &Path, mut on_change: impl FnMut() + Send + 'static) -> Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let name = path
+        .file_name()
+        .with_context(|| format!("Not a file: {}", path.display()))?
+        .to_os_string();
+
+    let mut inotify = Inotify::init().context("Failed to initialize inotify")?;
+    inotify
+        .watches()
+        .add(
+            dir,
+            WatchMask::CLOSE_WRITE | WatchMask::MOVED_TO | WatchMask::CREATE,
+        )
+        .with_context(|| format!("Failed to watch {}", dir.display()))?;
+
+    thread::spawn(move || {
+        let mut buffer = [0; 4096];
+        loop {
+            let changed = match inotify.read_events_blocking(&mut buffer) {
+                Ok(mut events) => events.any(|event| event.name == Some(name.as_os_str())),
+                Err(e) => {
+                    tracing::error!(error = %e, "Stopped watching for config changes");
+                    return;
+                }
+            };
+            if changed {
+                thread::sleep(SETTLE_TIME);
+                // Whatever happened meanwhile is covered by this one call.
+                while inotify.read_events(&mut buffer).is_ok() {}
+                on_change();
+            }
+        }
+    });
+    Ok(())
+}